@@ -3,6 +3,7 @@ use scene::{Mesh, Intersection, Scene};
 use camera::Ray;
 use config;
 use math::det;
+use matrix::Matrix44;
 
 #[derive(Debug, Clone)]
 pub struct Aabb {
@@ -18,6 +19,14 @@ impl Aabb {
     }
 
     pub fn intersect_ray(&self, ray: &Ray) -> (bool, f64) {
+        let (tmin, tmax) = self.ray_interval(ray);
+        let hit = tmin <= tmax && tmax.is_sign_positive();
+        let distance = if tmin.is_sign_positive() { tmin } else { tmax };
+        (hit, distance)
+    }
+
+    // レイが AABB に入る距離と出る距離。交差しない場合は tmin > tmax になる
+    pub fn ray_interval(&self, ray: &Ray) -> (f64, f64) {
         let dir_inv = Vector3::new(
             ray.direction.x.recip(),
             ray.direction.y.recip(),
@@ -32,10 +41,7 @@ impl Aabb {
         let t6 = (self.max.z - ray.origin.z) * dir_inv.z;
         let tmin = (t1.min(t2).max(t3.min(t4))).max(t5.min(t6));
         let tmax = (t1.max(t2).min(t3.max(t4))).min(t5.max(t6));
-
-        let hit = tmin <= tmax && tmax.is_sign_positive();
-        let distance = if tmin.is_sign_positive() { tmin } else { tmax };
-        (hit, distance)
+        (tmin, tmax)
     }
 
     pub fn merge(&mut self, other: &Aabb) {
@@ -48,6 +54,24 @@ impl Aabb {
         self.max.z = self.max.z.max(other.max.z);
     }
 
+    // 8つの頂点を変換して、それを囲む AABB を求める
+    pub fn transform(&self, matrix: &Matrix44) -> Aabb {
+        let mut aabb = Aabb {
+            min: Vector3::from_one(config::INF),
+            max: Vector3::from_one(-config::INF),
+        };
+        for i in 0..8 {
+            let corner = Vector3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            let p = *matrix * corner;
+            aabb.merge(&Aabb { min: p, max: p });
+        }
+        aabb
+    }
+
     pub fn from_triangle(v0: &Vector3, v1: &Vector3, v2: &Vector3) -> Aabb {
         Aabb {
             min: Vector3::new(
@@ -205,8 +229,9 @@ impl BvhNode {
         BvhNode::build_from_mesh_with_indexes(mesh, &mut face_indexes)
     }
 
+    // 無限に広がる要素は含めない
     pub fn build_from_scene(scene: &Scene) -> BvhNode {
-        let mut indexes: Vec<usize> = (0..scene.elements.len()).collect();
+        let mut indexes: Vec<usize> = (0..scene.elements.len()).filter(|i| scene.elements[*i].bounded()).collect();
         BvhNode::build_from_scene_with_indexes(scene, &mut indexes)
     }
 
@@ -221,6 +246,7 @@ impl BvhNode {
             for face_index in &self.indexes {
                 let face = &mesh.faces[*face_index];
                 if intersect_polygon(&mesh.vertexes[face.v0], &mesh.vertexes[face.v1], &mesh.vertexes[face.v2], ray, intersection) {
+                    mesh.interpolate_vertex_attributes(face, intersection);
                     any_hit = true;
                 }
             }
@@ -236,6 +262,30 @@ impl BvhNode {
         any_hit
     }
 
+    // 最も近い交点だけでなく、レイの前方にあるすべての交点を集める
+    pub fn intersect_all_for_mesh(&self, mesh: &Mesh, ray: &Ray, hits: &mut Vec<Intersection>) {
+        if !self.aabb.intersect_ray(ray).0 {
+            return;
+        }
+
+        if self.children.is_empty() {
+            // leaf node
+            for face_index in &self.indexes {
+                let face = &mesh.faces[*face_index];
+                let mut intersection = Intersection::empty();
+                if intersect_polygon(&mesh.vertexes[face.v0], &mesh.vertexes[face.v1], &mesh.vertexes[face.v2], ray, &mut intersection) {
+                    mesh.interpolate_vertex_attributes(face, &mut intersection);
+                    hits.push(intersection);
+                }
+            }
+        } else {
+            // intermediate node
+            for child in &self.children {
+                child.intersect_all_for_mesh(mesh, ray, hits);
+            }
+        }
+    }
+
     pub fn intersect_for_scene(&self, scene: &Scene, ray: &Ray, intersection: &mut Intersection) -> Option<usize> {
         if !self.aabb.intersect_ray(ray).0 {
             return None;
@@ -284,6 +334,7 @@ pub fn intersect_polygon(v0: &Vector3, v1: &Vector3, v2: &Vector3, ray: &Ray, in
 
     intersection.position = ray.origin + ray.direction * t;
     intersection.normal = edge1.cross(&edge2).normalize();
+    intersection.geometric_normal = intersection.normal;
     intersection.distance = t;
     intersection.uv = Vector2::new(u, v);
     true
@@ -9,6 +9,11 @@ pub struct Camera {
     // カメラの位置
     pub eye: Vector3,
 
+    // 注視点・上方向・垂直画角（Camera::new の引数をシーンファイルへの書き出し用に保持）
+    pub target: Vector3,
+    pub y_up: Vector3,
+    pub v_fov: f64,
+
     // レンズ形状
     pub lens_shape: LensShape,
 
@@ -28,7 +33,7 @@ pub struct Camera {
     pub plane_half_up: Vector3,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub enum LensShape {
     Square,
@@ -52,6 +57,9 @@ impl Camera {
 
         Camera {
             eye: eye,
+            target: target,
+            y_up: y_up,
+            v_fov: v_fov,
             lens_shape: lens_shape,
             lens_radius: lens_radius,
             focus_distance: focus_distance,
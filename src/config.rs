@@ -13,6 +13,16 @@ pub const GAMMA_FACTOR: f64 = 2.2;
 pub const SUPERSAMPLING: u32 = 2;
 pub const PATHTRACING_BOUNCE_LIMIT: u32 = 10;
 
+// Smooth Shading
+// 頂点法線が無いメッシュで、この角度（度）より大きい辺は平滑化しない
+pub const SMOOTHING_ANGLE: f64 = 45.0;
+
+// Sphere Tracing
+// 距離がこれより小さくなったら交差とみなす。法線の中心差分の幅にも使う
+// レイの原点は OFFSET だけ面から離れるので、OFFSET より十分小さくする
+pub const SPHERE_TRACING_EPS: f64 = 1e-5;
+pub const SPHERE_TRACING_MAX_STEPS: u32 = 512;
+
 // Tone Mapping
 pub const TONE_MAPPING_MODE: ToneMappingMode = ToneMappingMode::Reinhard;
 pub const TONE_MAPPING_EXPOSURE: f64 = 1.5;
@@ -0,0 +1,188 @@
+use vector::Vector3;
+use material::Material;
+use camera::Ray;
+use color::Color;
+use bvh::Aabb;
+use scene::{Intersectable, Intersection, Surface, Solid, Boundary, Interval};
+use description::{ElementDescription, ElementType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(&self, in_a: bool, in_b: bool) -> bool {
+        match *self {
+            CsgOperation::Union => in_a || in_b,
+            CsgOperation::Intersection => in_a && in_b,
+            CsgOperation::Difference => in_a && !in_b,
+        }
+    }
+}
+
+// 2つの Solid の集合演算
+// 子要素のマテリアルは使わず、Csg のマテリアルで描画する
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub a: Box<Solid>,
+    pub b: Box<Solid>,
+    pub material: Material,
+}
+
+impl Solid for Csg {
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        // 両方の区間の端点を距離順にたどり、内外が切り替わる点を新しい区間の端点にする
+        let mut events = vec![];
+        let a = self.a.intervals(ray).into_iter().map(|interval| (interval, true));
+        let b = self.b.intervals(ray).into_iter().map(|interval| (interval, false));
+        for (interval, is_a) in a.chain(b) {
+            events.push((interval.enter, is_a, true));
+            events.push((interval.exit, is_a, false));
+        }
+        events.sort_by(|x, y| x.0.distance.partial_cmp(&y.0.distance).unwrap());
+
+        let mut intervals = vec![];
+        let (mut in_a, mut in_b, mut inside) = (false, false, false);
+        let mut enter: Option<Boundary> = None;
+        for (boundary, is_a, entering) in events {
+            if is_a { in_a = entering; } else { in_b = entering; }
+            if self.operation.inside(in_a, in_b) == inside {
+                continue;
+            }
+            inside = !inside;
+
+            // 差集合では b の表面が境界になるので、法線を反転して外側に向ける
+            let boundary = if !is_a && self.operation == CsgOperation::Difference { boundary.flip() } else { boundary };
+            if inside {
+                enter = Some(boundary);
+            } else if let Some(e) = enter.take() {
+                intervals.push(Interval { enter: e, exit: boundary });
+            }
+        }
+        intervals
+    }
+}
+
+impl Intersectable for Csg {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        let nearest = self.intervals(ray).into_iter()
+            .flat_map(|interval| vec![interval.enter, interval.exit])
+            .find(|boundary| boundary.distance > 0.0);
+
+        match nearest {
+            Some(boundary) if boundary.distance < intersection.distance => {
+                intersection.position = ray.origin + ray.direction * boundary.distance;
+                intersection.distance = boundary.distance;
+                intersection.normal = boundary.normal;
+                intersection.geometric_normal = boundary.geometric_normal;
+                intersection.uv = boundary.uv;
+                intersection.tangent = Vector3::zero();
+                intersection.vertex_color = Color::one();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn material(&self) -> &Material { &self.material }
+
+    fn aabb(&self) -> Aabb {
+        let a = self.a.aabb();
+        let b = self.b.aabb();
+        match self.operation {
+            CsgOperation::Union => {
+                let mut aabb = a;
+                aabb.merge(&b);
+                aabb
+            }
+            CsgOperation::Intersection => Aabb {
+                min: Vector3::new(a.min.x.max(b.min.x), a.min.y.max(b.min.y), a.min.z.max(b.min.z)),
+                max: Vector3::new(a.max.x.min(b.max.x), a.max.y.min(b.max.y), a.max.z.min(b.max.z)),
+            },
+            CsgOperation::Difference => a,
+        }
+    }
+
+    fn bounded(&self) -> bool { true }
+
+    fn nee_available(&self) -> bool { false }
+
+    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
+        unimplemented!()
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Csg, &self.material);
+        description.operation = Some(self.operation);
+        description.a = Some(Box::new(self.a.description()?));
+        description.b = Some(Box::new(self.b.description()?));
+        Some(description)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scene::Sphere;
+    use material::SurfaceType;
+    use texture::Texture;
+
+    fn material() -> Material {
+        Material {
+            surface: SurfaceType::Diffuse,
+            albedo: Texture::white(),
+            emission: Texture::black(),
+            roughness: Texture::black(),
+        }
+    }
+
+    fn sphere(x: f64, radius: f64) -> Box<Solid> {
+        Box::new(Sphere { center: Vector3::new(x, 0.0, 0.0), radius, material: material() })
+    }
+
+    // x 軸に沿ったレイで、区間の距離と端点の法線の x 成分を返す
+    fn intervals(operation: CsgOperation, a: Box<Solid>, b: Box<Solid>) -> Vec<(f64, f64, f64, f64)> {
+        let csg = Csg { operation, a, b, material: material() };
+        let ray = Ray { origin: Vector3::new(-5.0, 0.0, 0.0), direction: Vector3::new(1.0, 0.0, 0.0) };
+        csg.intervals(&ray).into_iter()
+            .map(|i| (i.enter.distance, i.enter.normal.x, i.exit.distance, i.exit.normal.x))
+            .collect()
+    }
+
+    fn assert_intervals(actual: Vec<(f64, f64, f64, f64)>, expected: Vec<(f64, f64, f64, f64)>) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a.0 - e.0).abs() < 1e-9 && (a.1 - e.1).abs() < 1e-9 &&
+                    (a.2 - e.2).abs() < 1e-9 && (a.3 - e.3).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn union_merges_overlapping_intervals() {
+        // a: [4, 6], b: [5, 7]
+        assert_intervals(intervals(CsgOperation::Union, sphere(0.0, 1.0), sphere(1.0, 1.0)), vec![(4.0, -1.0, 7.0, 1.0)]);
+        // 離れていればそれぞれの区間が残る
+        assert_intervals(intervals(CsgOperation::Union, sphere(0.0, 1.0), sphere(3.0, 1.0)),
+                         vec![(4.0, -1.0, 6.0, 1.0), (7.0, -1.0, 9.0, 1.0)]);
+    }
+
+    #[test]
+    fn intersection_keeps_the_overlap() {
+        assert_intervals(intervals(CsgOperation::Intersection, sphere(0.0, 1.0), sphere(1.0, 1.0)), vec![(5.0, -1.0, 6.0, 1.0)]);
+        assert_intervals(intervals(CsgOperation::Intersection, sphere(0.0, 1.0), sphere(3.0, 1.0)), vec![]);
+    }
+
+    #[test]
+    fn difference_flips_the_normals_of_b() {
+        // b の入口が差集合の出口になり、法線は外側（+x）を向く
+        assert_intervals(intervals(CsgOperation::Difference, sphere(0.0, 1.0), sphere(1.0, 1.0)), vec![(4.0, -1.0, 5.0, 1.0)]);
+        // a の内側に b を置くと 2 つに分かれ、b の出口が入口になる
+        assert_intervals(intervals(CsgOperation::Difference, sphere(0.0, 3.0), sphere(0.0, 1.0)),
+                         vec![(2.0, -1.0, 4.0, 1.0), (6.0, -1.0, 8.0, 1.0)]);
+        // a を b が覆えば何も残らない
+        assert_intervals(intervals(CsgOperation::Difference, sphere(0.0, 1.0), sphere(0.0, 3.0)), vec![]);
+    }
+}
@@ -0,0 +1,431 @@
+extern crate toml;
+
+use toml::Spanned;
+
+use vector::Vector3;
+use matrix::Matrix44;
+use camera::{Camera, LensShape};
+use material::{Material, SurfaceType, Principled, PrincipledMaps};
+use texture::Texture;
+use scene::Skybox;
+use csg::CsgOperation;
+use subdivision::Subdivision;
+use displacement::Displacement;
+use sdf::{DistanceFunction, SphereDistance, BoxDistance, Mandelbulb, MengerSponge, SmoothUnion};
+
+// シーンファイルの各テーブルに対応する構造体
+// SceneLoader で読み込み、SceneWriter で書き出す
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneDescription {
+    pub camera: CameraDescription,
+    pub skybox: SkyboxDescription,
+    #[serde(default)]
+    pub elements: Vec<Spanned<ElementDescription>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDescription {
+    pub eye: [f64; 3],
+    pub target: [f64; 3],
+    #[serde(default = "default_y_up")]
+    pub y_up: [f64; 3],
+    pub fov: f64,
+    #[serde(default = "default_lens_shape")]
+    pub lens_shape: LensShape,
+    #[serde(default)]
+    pub aperture: f64,
+    pub focus_distance: f64,
+}
+
+impl CameraDescription {
+    pub fn from_camera(camera: &Camera) -> CameraDescription {
+        CameraDescription {
+            eye: from_vector3(&camera.eye),
+            target: from_vector3(&camera.target),
+            y_up: from_vector3(&camera.y_up),
+            fov: camera.v_fov,
+            lens_shape: camera.lens_shape.clone(),
+            aperture: camera.lens_radius * 2.0,
+            focus_distance: camera.focus_distance,
+        }
+    }
+}
+
+// 6面の画像をすべて省略すると、全方向が intensity の一様な色になる
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SkyboxDescription {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub px: Option<Spanned<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nx: Option<Spanned<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub py: Option<Spanned<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ny: Option<Spanned<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pz: Option<Spanned<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nz: Option<Spanned<String>>,
+    #[serde(default = "default_one")]
+    pub intensity: [f64; 3],
+}
+
+impl SkyboxDescription {
+    pub fn from_skybox(skybox: &Skybox) -> SkyboxDescription {
+        let faces = skybox.faces.as_ref();
+        SkyboxDescription {
+            px: faces.map(|faces| unspanned(&faces.px_texture.path)),
+            nx: faces.map(|faces| unspanned(&faces.nx_texture.path)),
+            py: faces.map(|faces| unspanned(&faces.py_texture.path)),
+            ny: faces.map(|faces| unspanned(&faces.ny_texture.path)),
+            pz: faces.map(|faces| unspanned(&faces.pz_texture.path)),
+            nz: faces.map(|faces| unspanned(&faces.nz_texture.path)),
+            intensity: from_vector3(&skybox.intensity),
+        }
+    }
+}
+
+// 要素の種類ごとに使うキーが異なるが、Spanned を使うために内部タグ付き enum にはしない
+// （serde が値をバッファリングするので toml の位置情報が失われる）
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ElementDescription {
+    #[serde(rename = "type")]
+    pub kind: ElementType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub center: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radius: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normal: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub corner: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edge_u: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edge_v: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bottom: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minor_radius: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<Spanned<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merge_groups: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usemtl: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub import_mtl: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smooth: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project_uvs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Vec<TransformDescription>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vertexes: Option<Vec<[f64; 3]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub faces: Option<Vec<[usize; 3]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subdivision: Option<Subdivision>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub displacement: Option<DisplacementDescription>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sdf: Option<SdfDescription>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<CsgOperation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub a: Option<Box<ElementDescription>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b: Option<Box<ElementDescription>>,
+    // glTF ファイルのメッシュはファイル内のマテリアルを使うので省略する
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub material: Option<MaterialDescription>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum ElementType {
+    Sphere,
+    Plane,
+    Rectangle,
+    Disk,
+    Cylinder,
+    Cone,
+    Capsule,
+    Torus,
+    Cuboid,
+    Sdf,
+    Csg,
+    Heightfield,
+    BvhMesh,
+    Instance,
+}
+
+impl ElementType {
+    pub fn keys(&self) -> &'static [&'static str] {
+        match *self {
+            ElementType::Sphere => &["center", "radius", "transform"],
+            ElementType::Plane => &["center", "normal"],
+            ElementType::Rectangle => &["corner", "edge_u", "edge_v"],
+            ElementType::Disk => &["center", "normal", "radius"],
+            ElementType::Cylinder | ElementType::Cone | ElementType::Capsule => &["bottom", "top", "radius"],
+            ElementType::Torus => &["center", "normal", "radius", "minor_radius"],
+            ElementType::Cuboid => &["min", "max", "transform"],
+            ElementType::Sdf => &["min", "max", "sdf"],
+            ElementType::Csg => &["operation", "a", "b"],
+            ElementType::Heightfield => &["path", "min", "max"],
+            ElementType::BvhMesh | ElementType::Instance => &["path", "group", "merge_groups", "usemtl", "import_mtl", "smooth", "project_uvs", "transform", "vertexes", "faces", "subdivision", "displacement"],
+        }
+    }
+}
+
+impl ElementDescription {
+    pub fn new(kind: ElementType, material: &Material) -> ElementDescription {
+        ElementDescription {
+            kind,
+            center: None,
+            radius: None,
+            normal: None,
+            corner: None,
+            edge_u: None,
+            edge_v: None,
+            bottom: None,
+            top: None,
+            minor_radius: None,
+            min: None,
+            max: None,
+            path: None,
+            group: None,
+            merge_groups: None,
+            usemtl: None,
+            import_mtl: None,
+            smooth: None,
+            project_uvs: None,
+            transform: None,
+            vertexes: None,
+            faces: None,
+            subdivision: None,
+            displacement: None,
+            sdf: None,
+            operation: None,
+            a: None,
+            b: None,
+            material: Some(MaterialDescription::from_material(material)),
+        }
+    }
+
+    pub fn present_keys(&self) -> Vec<&'static str> {
+        let mut keys = vec![];
+        if self.center.is_some() { keys.push("center"); }
+        if self.radius.is_some() { keys.push("radius"); }
+        if self.normal.is_some() { keys.push("normal"); }
+        if self.corner.is_some() { keys.push("corner"); }
+        if self.edge_u.is_some() { keys.push("edge_u"); }
+        if self.edge_v.is_some() { keys.push("edge_v"); }
+        if self.bottom.is_some() { keys.push("bottom"); }
+        if self.top.is_some() { keys.push("top"); }
+        if self.minor_radius.is_some() { keys.push("minor_radius"); }
+        if self.min.is_some() { keys.push("min"); }
+        if self.max.is_some() { keys.push("max"); }
+        if self.path.is_some() { keys.push("path"); }
+        if self.group.is_some() { keys.push("group"); }
+        if self.merge_groups.is_some() { keys.push("merge_groups"); }
+        if self.usemtl.is_some() { keys.push("usemtl"); }
+        if self.import_mtl.is_some() { keys.push("import_mtl"); }
+        if self.smooth.is_some() { keys.push("smooth"); }
+        if self.project_uvs.is_some() { keys.push("project_uvs"); }
+        if self.transform.is_some() { keys.push("transform"); }
+        if self.vertexes.is_some() { keys.push("vertexes"); }
+        if self.faces.is_some() { keys.push("faces"); }
+        if self.subdivision.is_some() { keys.push("subdivision"); }
+        if self.displacement.is_some() { keys.push("displacement"); }
+        if self.sdf.is_some() { keys.push("sdf"); }
+        if self.operation.is_some() { keys.push("operation"); }
+        if self.a.is_some() { keys.push("a"); }
+        if self.b.is_some() { keys.push("b"); }
+        keys
+    }
+}
+
+// Matrix44 の生成関数と 1:1 に対応させる
+// 角度はラジアンで指定する
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransformDescription {
+    Matrix([[f64; 4]; 4]),
+    ScaleLinear(f64),
+    Scale([f64; 3]),
+    RotateX(f64),
+    RotateY(f64),
+    RotateZ(f64),
+    Translate([f64; 3]),
+}
+
+impl TransformDescription {
+    pub fn from_matrix(matrix: &Matrix44) -> TransformDescription {
+        TransformDescription::Matrix([matrix[0], matrix[1], matrix[2], matrix[3]])
+    }
+
+    pub fn to_matrix(&self) -> Matrix44 {
+        match *self {
+            TransformDescription::Matrix(elements) => Matrix44::new(elements),
+            TransformDescription::ScaleLinear(s) => Matrix44::scale_linear(s),
+            TransformDescription::Scale(s) => Matrix44::scale(s[0], s[1], s[2]),
+            TransformDescription::RotateX(t) => Matrix44::rotate_x(t),
+            TransformDescription::RotateY(t) => Matrix44::rotate_y(t),
+            TransformDescription::RotateZ(t) => Matrix44::rotate_z(t),
+            TransformDescription::Translate(t) => Matrix44::translate(t[0], t[1], t[2]),
+        }
+    }
+}
+
+// sdf モジュールの距離関数と 1:1 に対応させる
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", deny_unknown_fields)]
+pub enum SdfDescription {
+    Sphere { center: [f64; 3], radius: f64 },
+    Box { center: [f64; 3], half_size: [f64; 3] },
+    Mandelbulb { center: [f64; 3], scale: f64, power: f64, iterations: u32 },
+    MengerSponge { center: [f64; 3], size: f64, iterations: u32 },
+    SmoothUnion { a: Box<SdfDescription>, b: Box<SdfDescription>, k: f64 },
+}
+
+impl SdfDescription {
+    pub fn to_distance_function(&self) -> Box<DistanceFunction> {
+        match *self {
+            SdfDescription::Sphere { ref center, radius } => Box::new(SphereDistance { center: to_vector3(center), radius }),
+            SdfDescription::Box { ref center, ref half_size } => Box::new(BoxDistance { center: to_vector3(center), half_size: to_vector3(half_size) }),
+            SdfDescription::Mandelbulb { ref center, scale, power, iterations } => Box::new(Mandelbulb { center: to_vector3(center), scale, power, iterations }),
+            SdfDescription::MengerSponge { ref center, size, iterations } => Box::new(MengerSponge { center: to_vector3(center), size, iterations }),
+            SdfDescription::SmoothUnion { ref a, ref b, k } => Box::new(SmoothUnion { a: a.to_distance_function(), b: b.to_distance_function(), k }),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialDescription {
+    pub surface: SurfaceType,
+    #[serde(default = "TextureDescription::white")]
+    pub albedo: TextureDescription,
+    #[serde(default = "TextureDescription::black")]
+    pub emission: TextureDescription,
+    #[serde(default = "TextureDescription::black")]
+    pub roughness: TextureDescription,
+
+    // Principled のパラメーターに乗算するテクスチャ。省略すると白になる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metallic: Option<TextureDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub specular: Option<TextureDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sheen: Option<TextureDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clearcoat: Option<TextureDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transmission: Option<TextureDescription>,
+}
+
+impl MaterialDescription {
+    pub fn from_material(material: &Material) -> MaterialDescription {
+        // テクスチャは SurfaceType とは別のキーに書き出す
+        let (surface, maps) = match material.surface {
+            SurfaceType::Principled(ref principled) => (SurfaceType::Principled(Principled { maps: None, ..principled.clone() }), principled.maps.as_ref()),
+            ref surface => (surface.clone(), None),
+        };
+        let map = |texture: fn(&PrincipledMaps) -> &Texture| maps.map(|maps| TextureDescription::from_texture(texture(maps)));
+        MaterialDescription {
+            surface,
+            albedo: TextureDescription::from_texture(&material.albedo),
+            emission: TextureDescription::from_texture(&material.emission),
+            roughness: TextureDescription::from_texture(&material.roughness),
+            metallic: map(|maps| &maps.metallic),
+            specular: map(|maps| &maps.specular),
+            sheen: map(|maps| &maps.sheen),
+            clearcoat: map(|maps| &maps.clearcoat),
+            transmission: map(|maps| &maps.transmission),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DisplacementDescription {
+    pub texture: TextureDescription,
+    pub scale: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edge_length: Option<f64>,
+}
+
+impl DisplacementDescription {
+    pub fn from_displacement(displacement: &Displacement) -> DisplacementDescription {
+        DisplacementDescription {
+            texture: TextureDescription::from_texture(&displacement.texture),
+            scale: displacement.scale,
+            edge_length: displacement.edge_length,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TextureDescription {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<Spanned<String>>,
+    #[serde(default = "default_one")]
+    pub color: [f64; 3],
+}
+
+impl TextureDescription {
+    fn white() -> TextureDescription {
+        TextureDescription { path: None, color: [1.0, 1.0, 1.0] }
+    }
+
+    fn black() -> TextureDescription {
+        TextureDescription { path: None, color: [0.0, 0.0, 0.0] }
+    }
+
+    pub fn from_texture(texture: &Texture) -> TextureDescription {
+        TextureDescription {
+            path: texture.image_texture.as_ref().map(|t| unspanned(&t.path)),
+            color: from_vector3(&texture.color),
+        }
+    }
+}
+
+fn default_y_up() -> [f64; 3] { [0.0, 1.0, 0.0] }
+
+fn default_lens_shape() -> LensShape { LensShape::Circle }
+
+fn default_one() -> [f64; 3] { [1.0, 1.0, 1.0] }
+
+// 書き出し時は位置情報を持たない
+pub fn unspanned(value: &str) -> Spanned<String> {
+    Spanned::new(0..0, value.to_string())
+}
+
+pub fn to_vector3(v: &[f64; 3]) -> Vector3 {
+    Vector3::new(v[0], v[1], v[2])
+}
+
+// 書き出した正規化済みの向きを読み直しても値が変わらないように、すでに単位長のものは正規化し直さない
+pub fn to_direction(v: &[f64; 3]) -> Vector3 {
+    let v = to_vector3(v);
+    if (v.length() - 1.0).abs() < 1e-12 { v } else { v.normalize() }
+}
+
+pub fn from_vector3(v: &Vector3) -> [f64; 3] {
+    [v.x, v.y, v.z]
+}
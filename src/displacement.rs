@@ -0,0 +1,209 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, BinaryHeap};
+
+use vector::{Vector3, Vector2};
+use texture::Texture;
+use scene::{Mesh, Face};
+
+// 分割後の三角形の数の上限。これに達したら、edge_length より長い辺が残っていても分割をやめる
+const MAX_TESSELLATED_TRIANGLES: usize = 1 << 20;
+
+// メッシュの頂点を、頂点法線の方向に texture の値 * scale だけ動かす
+// edge_length を指定した場合は、先にすべての辺がそれ以下の長さになるまで三角形を分割する
+#[derive(Clone, Debug)]
+pub struct Displacement {
+    pub texture: Texture,
+    pub scale: f64,
+    pub edge_length: Option<f64>,
+}
+
+pub fn displace(mut mesh: Mesh, displacement: &Displacement) -> Mesh {
+    // UV が無い面は投影した UV を使う
+    mesh.compute_uvs();
+
+    let mut tessellation = Tessellation::from_mesh(&mesh);
+    if let Some(edge_length) = displacement.edge_length {
+        tessellation.tessellate(edge_length);
+    }
+
+    // UV の継ぎ目にある頂点は、最初に見つかった角の UV で高さを決める（頂点は共有したままなので裂け目はできない）
+    let mut vertex_uvs = vec![None; tessellation.vertexes.len()];
+    for (triangle, uvs) in tessellation.triangles.iter().zip(&tessellation.uvs) {
+        for k in 0..3 {
+            if vertex_uvs[triangle[k]].is_none() {
+                vertex_uvs[triangle[k]] = Some(uvs[k]);
+            }
+        }
+    }
+    for (i, uv) in vertex_uvs.into_iter().enumerate() {
+        if let Some(uv) = uv {
+            let height = displacement.texture.sample(uv);
+            let height = (height.x + height.y + height.z) / 3.0 * displacement.scale;
+            tessellation.vertexes[i] = tessellation.vertexes[i] + tessellation.normals[i] * height;
+        }
+    }
+
+    let mut uvs = vec![];
+    let mut faces = vec![];
+    for (triangle, corner_uvs) in tessellation.triangles.iter().zip(&tessellation.uvs) {
+        let mut face = Face::new(triangle[0], triangle[1], triangle[2]);
+        face.uv_indexes = Some([uvs.len(), uvs.len() + 1, uvs.len() + 2]);
+        uvs.extend(corner_uvs.iter().cloned());
+        faces.push(face);
+    }
+
+    // 頂点法線は BvhMesh::from_mesh で動かした後の形から計算し直す
+    mesh.vertexes = tessellation.vertexes;
+    mesh.colors = tessellation.colors;
+    mesh.normals = vec![];
+    mesh.uvs = uvs;
+    mesh.faces = faces;
+    if let Some(ref mut source) = mesh.source {
+        source.displacement = Some(displacement.clone());
+    }
+    mesh
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+// 頂点を共有した三角形の集まり。UV は角ごとに持つ
+struct Tessellation {
+    vertexes: Vec<Vector3>,
+    colors: Vec<Vector3>,
+
+    // 頂点ごとに1つの法線（面ごとに異なる法線で動かすと裂け目ができるので）
+    normals: Vec<Vector3>,
+
+    triangles: Vec<[usize; 3]>,
+    uvs: Vec<[Vector2; 3]>,
+}
+
+impl Tessellation {
+    fn from_mesh(mesh: &Mesh) -> Tessellation {
+        // 頂点法線がある面はそれを、無い面は面の法線（面積で重み付け）を頂点ごとに足し合わせる
+        let mut normals = vec![Vector3::zero(); mesh.vertexes.len()];
+        for face in &mesh.faces {
+            let vertexes = [face.v0, face.v1, face.v2];
+            match face.normal_indexes {
+                Some(indexes) => {
+                    for k in 0..3 {
+                        normals[vertexes[k]] += mesh.normals[indexes[k]];
+                    }
+                }
+                None => {
+                    let cross = (mesh.vertexes[face.v1] - mesh.vertexes[face.v0]).cross(&(mesh.vertexes[face.v2] - mesh.vertexes[face.v0]));
+                    for k in 0..3 {
+                        normals[vertexes[k]] += cross;
+                    }
+                }
+            }
+        }
+
+        Tessellation {
+            vertexes: mesh.vertexes.clone(),
+            colors: mesh.colors.clone(),
+            normals: normals.iter().map(|n| if *n == Vector3::zero() { *n } else { n.normalize() }).collect(),
+            triangles: mesh.faces.iter().map(|f| [f.v0, f.v1, f.v2]).collect(),
+            uvs: mesh.faces.iter()
+                .map(|f| {
+                    let indexes = f.uv_indexes.unwrap();
+                    [mesh.uvs[indexes[0]], mesh.uvs[indexes[1]], mesh.uvs[indexes[2]]]
+                })
+                .collect(),
+        }
+    }
+
+    // 最も長い辺から順に、その中点で辺を共有するすべての三角形を2つに分割する
+    // 辺を共有する三角形を同時に分割するので、T 字の頂点ができない
+    // 全体で最も長い辺は、それを含むどの三角形でも最も長い辺なので、細長い三角形が増えていかない
+    // edge_length は正の有限の値であること（ローダーで検証する）
+    fn tessellate(&mut self, edge_length: f64) {
+        let mut edge_triangles: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for (t, triangle) in self.triangles.iter().enumerate() {
+            for k in 0..3 {
+                edge_triangles.entry(edge_key(triangle[k], triangle[(k + 1) % 3])).or_default().push(t);
+            }
+        }
+
+        let mut queue: BinaryHeap<Edge> = edge_triangles.keys().map(|&(a, b)| self.edge(a, b)).collect();
+        while let Some(edge) = queue.pop() {
+            if edge.length <= edge_length || self.triangles.len() >= MAX_TESSELLATED_TRIANGLES {
+                break;
+            }
+
+            let (a, b) = edge.key;
+            let m = self.vertexes.len();
+            self.vertexes.push((self.vertexes[a] + self.vertexes[b]) * 0.5);
+            self.normals.push((self.normals[a] + self.normals[b]).normalize());
+            if !self.colors.is_empty() {
+                let color = (self.colors[a] + self.colors[b]) * 0.5;
+                self.colors.push(color);
+            }
+            queue.push(self.edge(a, m));
+            queue.push(self.edge(m, b));
+
+            for s in edge_triangles.remove(&edge.key).unwrap_or_default() {
+                // s の頂点を (a', b', c) の順に回して、a'-b' を分割する辺にする
+                let source = self.triangles[s];
+                let uvs = self.uvs[s];
+                let r = (0..3).find(|&r| edge_key(source[r], source[(r + 1) % 3]) == edge.key).unwrap();
+                let (a2, b2, c) = (source[r], source[(r + 1) % 3], source[(r + 2) % 3]);
+                let (uv_a, uv_b, uv_c) = (uvs[r], uvs[(r + 1) % 3], uvs[(r + 2) % 3]);
+                let uv_m = (uv_a + uv_b) * 0.5;
+
+                let n = self.triangles.len();
+                self.triangles[s] = [a2, m, c];
+                self.uvs[s] = [uv_a, uv_m, uv_c];
+                self.triangles.push([m, b2, c]);
+                self.uvs.push([uv_m, uv_b, uv_c]);
+
+                edge_triangles.entry(edge_key(a2, m)).or_default().push(s);
+                edge_triangles.entry(edge_key(m, c)).or_default().extend(&[s, n]);
+                edge_triangles.entry(edge_key(m, b2)).or_default().push(n);
+                if let Some(triangles) = edge_triangles.get_mut(&edge_key(b2, c)) {
+                    for x in triangles.iter_mut() {
+                        if *x == s {
+                            *x = n;
+                        }
+                    }
+                }
+                queue.push(self.edge(m, c));
+            }
+        }
+    }
+
+    fn edge(&self, a: usize, b: usize) -> Edge {
+        Edge {
+            length: (self.vertexes[b] - self.vertexes[a]).length(),
+            key: edge_key(a, b),
+        }
+    }
+}
+
+// BinaryHeap で長い順に取り出すための辺
+struct Edge {
+    length: f64,
+    key: (usize, usize),
+}
+
+impl PartialEq for Edge {
+    fn eq(&self, other: &Edge) -> bool {
+        self.length == other.length
+    }
+}
+
+impl Eq for Edge {}
+
+impl PartialOrd for Edge {
+    fn partial_cmp(&self, other: &Edge) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Edge {
+    fn cmp(&self, other: &Edge) -> Ordering {
+        self.length.partial_cmp(&other.length).unwrap()
+    }
+}
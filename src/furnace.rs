@@ -0,0 +1,250 @@
+extern crate rand;
+
+use self::rand::{Rng, SeedableRng, StdRng};
+use material::{PointMaterial, SurfaceType, ConductorIor, Metal, Principled};
+use color::Color;
+use vector::Vector3;
+use config;
+
+// ホワイトファーネステスト
+// 白い albedo の材質を一様に白く光る環境に置いたときの明るさ（方向ごとのアルベド）を sample() のモンテカルロ積分で求め、
+// エネルギーを失わない材質（拡散反射、鏡面、屈折、粗さ 0 の GGX）では 1 になることを、
+// 多重散乱を無視して失う材質では 1 を超えない（エネルギーが増えない）ことを確かめる
+// NEE に対応した材質では、余弦で重点サンプリングした bsdf の積分とも一致することを確かめる
+
+const SAMPLES: usize = 50000;
+const ROUGHNESS: [f64; 3] = [0.1, 0.5, 1.0];
+const VIEW_COS: [f64; 4] = [1.0, 0.7, 0.3, 0.1];
+
+// 誤差の許容範囲（標準偏差の倍数と、それに加える絶対値）
+const SIGMA: f64 = 4.0;
+const ENERGY_TOLERANCE: f64 = 0.005;
+const BSDF_TOLERANCE: f64 = 0.01;
+
+#[test]
+fn diffuse() {
+    for_each_case(&SurfaceType::Diffuse, |case, albedo| {
+        assert!((albedo.mean - Color::one()).length() < 1e-9, "{}: {:?}", case, albedo.mean);
+    });
+    assert_matches_bsdf(&SurfaceType::Diffuse);
+}
+
+#[test]
+fn specular() {
+    assert_lossless(&SurfaceType::Specular, 0.0);
+}
+
+#[test]
+fn refraction() {
+    assert_lossless(&SurfaceType::Refraction { refractive_index: 1.5 }, 0.0);
+}
+
+#[test]
+fn ggx() {
+    let surface = SurfaceType::GGX { f0: 1.0 };
+    assert_lossless(&surface, 0.0);
+    assert_energy_conserved(&surface);
+    assert_matches_bsdf(&surface);
+}
+
+#[test]
+fn ggx_refraction() {
+    let surface = SurfaceType::GGXRefraction { refractive_index: 1.5 };
+    assert_lossless(&surface, 0.0);
+    assert_energy_conserved(&surface);
+}
+
+#[test]
+fn conductor() {
+    let surface = SurfaceType::Conductor { ior: ConductorIor::Metal(Metal::Silver) };
+    assert_energy_conserved(&surface);
+    assert_matches_bsdf(&surface);
+}
+
+#[test]
+fn anisotropic_ggx() {
+    let surface = SurfaceType::AnisotropicGGX { f0: 1.0, roughness_x: 0.2, roughness_y: 0.7, tangent: None };
+    assert_energy_conserved(&surface);
+    assert_matches_bsdf(&surface);
+}
+
+#[test]
+fn layered() {
+    let surface = SurfaceType::Layered {
+        coat: Box::new(SurfaceType::GGX { f0: 1.0 }),
+        base: Box::new(SurfaceType::Diffuse),
+        refractive_index: 1.5,
+        coat_roughness: 0.1,
+    };
+    assert_energy_conserved(&surface);
+    assert_matches_bsdf(&surface);
+}
+
+#[test]
+fn principled_transmission() {
+    let surface = SurfaceType::Principled(Principled { transmission: 1.0, ..default_principled() });
+    assert_lossless(&surface, 0.0);
+    assert_energy_conserved(&surface);
+}
+
+#[test]
+fn principled_metallic() {
+    let surface = SurfaceType::Principled(Principled { metallic: 1.0, ..default_principled() });
+    assert_energy_conserved(&surface);
+    assert_matches_bsdf(&surface);
+}
+
+// Burley の拡散反射は粗いと 1 を超えるので、エネルギーは確かめずに bsdf との一致だけを確かめる
+// clearcoat_gloss が 1 だと鋭すぎて余弦の重点サンプリングでは bsdf の積分が求まらないので、最も粗い clearcoat にする
+#[test]
+fn principled_dielectric() {
+    assert_matches_bsdf(&SurfaceType::Principled(default_principled()));
+    assert_matches_bsdf(&SurfaceType::Principled(Principled { sheen: 1.0, clearcoat: 1.0, clearcoat_gloss: 0.0, ..default_principled() }));
+}
+
+fn default_principled() -> Principled {
+    Principled {
+        metallic: 0.0,
+        specular: 0.5,
+        specular_tint: 0.0,
+        sheen: 0.0,
+        sheen_tint: 0.5,
+        clearcoat: 0.0,
+        clearcoat_gloss: 1.0,
+        transmission: 0.0,
+        refractive_index: 1.5,
+        maps: None,
+    }
+}
+
+fn assert_energy_conserved(surface: &SurfaceType) {
+    for_each_case(surface, |case, albedo| {
+        let max = albedo.mean.x.max(albedo.mean.y).max(albedo.mean.z);
+        assert!(max <= 1.0 + SIGMA * albedo.sigma + ENERGY_TOLERANCE, "{}: {:?}", case, albedo.mean);
+    });
+}
+
+// 指定した粗さで、どの視線の角度でもアルベドが 1 になる
+fn assert_lossless(surface: &SurfaceType, roughness: f64) {
+    let normal = Vector3::new(0.0, 1.0, 0.0);
+    let mut rng = rng();
+    for_each_material_with(surface, &[roughness], |case, material, view| {
+        let albedo = estimate_albedo(&mut rng, material, view, &normal);
+        let error = (albedo.mean - Color::one()).length();
+        assert!(error <= SIGMA * albedo.sigma + ENERGY_TOLERANCE, "{}: {:?}", case, albedo.mean);
+    });
+}
+
+fn assert_matches_bsdf(surface: &SurfaceType) {
+    let normal = Vector3::new(0.0, 1.0, 0.0);
+    let mut rng = rng();
+    for_each_material(surface, |case, material, view| {
+        let albedo = estimate_albedo(&mut rng, material, view, &normal);
+        let bsdf = estimate_bsdf(&mut rng, material, view, &normal);
+        let difference = (albedo.mean - bsdf.mean).length();
+        assert!(difference <= SIGMA * (albedo.sigma + bsdf.sigma) + BSDF_TOLERANCE, "{}: sample {:?}, bsdf {:?}", case, albedo.mean, bsdf.mean);
+    });
+}
+
+// 粗さと視線の角度の組み合わせごとに、sample() で求めたアルベドを渡す
+fn for_each_case<F: FnMut(&str, &Estimate)>(surface: &SurfaceType, mut f: F) {
+    let normal = Vector3::new(0.0, 1.0, 0.0);
+    let mut rng = rng();
+    for_each_material(surface, |case, material, view| {
+        f(case, &estimate_albedo(&mut rng, material, view, &normal));
+    });
+}
+
+fn for_each_material<F: FnMut(&str, &PointMaterial, &Vector3)>(surface: &SurfaceType, f: F) {
+    for_each_material_with(surface, &ROUGHNESS, f);
+}
+
+fn for_each_material_with<F: FnMut(&str, &PointMaterial, &Vector3)>(surface: &SurfaceType, roughnesses: &[f64], mut f: F) {
+    for &roughness in roughnesses {
+        for &view_cos in &VIEW_COS {
+            let material = PointMaterial {
+                surface: surface.clone(),
+                albedo: Color::one(),
+                emission: Color::zero(),
+                roughness,
+                tangent: Vector3::zero(),
+            };
+            let view = Vector3::new((1.0 - view_cos * view_cos).sqrt(), view_cos, 0.0);
+            f(&format!("roughness {}, cos {}", roughness, view_cos), &material, &view);
+        }
+    }
+}
+
+fn rng() -> StdRng {
+    SeedableRng::from_seed(&[1, 2, 3, 4][..])
+}
+
+struct Estimate {
+    mean: Color,
+    sigma: f64,
+}
+
+// sample() の reflectance の平均
+fn estimate_albedo(rng: &mut StdRng, material: &PointMaterial, view: &Vector3, normal: &Vector3) -> Estimate {
+    let mut estimator = Estimator::new();
+    for _ in 0..SAMPLES {
+        let reflectance = match material.sample(rng.gen::<(f64, f64, f64)>(), &Vector3::zero(), view, normal, normal) {
+            // 屈折では立体角の変化に伴う放射輝度の補正が掛かっているので、エネルギーに戻す
+            Some(result) => match refractive_index(&material.surface) {
+                Some(refractive_index) if result.ray.direction.dot(normal) < 0.0 => result.reflectance * (refractive_index * refractive_index),
+                _ => result.reflectance,
+            },
+            None => Color::zero(),
+        };
+        estimator.add(reflectance);
+    }
+    estimator.estimate()
+}
+
+fn refractive_index(surface: &SurfaceType) -> Option<f64> {
+    match *surface {
+        SurfaceType::Refraction { refractive_index } => Some(refractive_index),
+        SurfaceType::GGXRefraction { refractive_index } => Some(refractive_index),
+        SurfaceType::Principled(ref principled) => Some(principled.refractive_index),
+        _ => None,
+    }
+}
+
+// 余弦で重点サンプリングした bsdf * cos / pdf の平均
+fn estimate_bsdf(rng: &mut StdRng, material: &PointMaterial, view: &Vector3, normal: &Vector3) -> Estimate {
+    let mut estimator = Estimator::new();
+    for _ in 0..SAMPLES {
+        let (u, v) = rng.gen::<(f64, f64)>();
+        let phi = config::PI2 * u;
+        let r = v.sqrt();
+        let light = Vector3::new(r * phi.cos(), (1.0 - v).sqrt(), r * phi.sin());
+        estimator.add(material.bsdf(view, normal, &light) * config::PI);
+    }
+    estimator.estimate()
+}
+
+struct Estimator {
+    sum: Color,
+    sum2: f64,
+    count: usize,
+}
+
+impl Estimator {
+    fn new() -> Estimator {
+        Estimator { sum: Color::zero(), sum2: 0.0, count: 0 }
+    }
+
+    fn add(&mut self, value: Color) {
+        self.sum += value;
+        self.sum2 += value.y * value.y;
+        self.count += 1;
+    }
+
+    // 平均と、緑チャンネルから求めた平均の標準偏差
+    fn estimate(&self) -> Estimate {
+        let n = self.count as f64;
+        let mean = self.sum / n;
+        let variance = (self.sum2 / n - mean.y * mean.y).max(0.0);
+        Estimate { mean, sigma: (variance / n).sqrt() }
+    }
+}
@@ -0,0 +1,720 @@
+extern crate image;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use image::{DynamicImage, GenericImage, ImageBuffer, Luma};
+
+use vector::{Vector3, Vector2};
+use matrix::Matrix44;
+use scene::{Mesh, Face, MeshSource};
+use material::{Material, SurfaceType};
+use texture::{Texture, ImageTexture};
+use camera::{Camera, LensShape};
+use color::Color;
+use config;
+use loader::MeshLoadError;
+
+// glTF 2.0 の JSON のうち、読み込みに使う部分だけを定義する（それ以外のキーは無視される）
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Gltf {
+    scene: Option<usize>,
+    #[serde(default)]
+    scenes: Vec<GltfScene>,
+    #[serde(default)]
+    nodes: Vec<GltfNode>,
+    #[serde(default)]
+    meshes: Vec<GltfMesh>,
+    #[serde(default)]
+    accessors: Vec<GltfAccessor>,
+    #[serde(default)]
+    buffer_views: Vec<GltfBufferView>,
+    #[serde(default)]
+    buffers: Vec<GltfBuffer>,
+    #[serde(default)]
+    materials: Vec<GltfMaterial>,
+    #[serde(default)]
+    textures: Vec<GltfTexture>,
+    #[serde(default)]
+    images: Vec<GltfImage>,
+    #[serde(default)]
+    cameras: Vec<GltfCamera>,
+}
+
+#[derive(Deserialize)]
+struct GltfScene {
+    #[serde(default)]
+    nodes: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct GltfNode {
+    camera: Option<usize>,
+    mesh: Option<usize>,
+    #[serde(default)]
+    children: Vec<usize>,
+    matrix: Option<[f64; 16]>,
+    translation: Option<[f64; 3]>,
+    rotation: Option<[f64; 4]>,
+    scale: Option<[f64; 3]>,
+}
+
+#[derive(Deserialize)]
+struct GltfMesh {
+    primitives: Vec<GltfPrimitive>,
+}
+
+#[derive(Deserialize)]
+struct GltfPrimitive {
+    attributes: HashMap<String, usize>,
+    indices: Option<usize>,
+    material: Option<usize>,
+    #[serde(default = "default_mode")]
+    mode: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfAccessor {
+    buffer_view: Option<usize>,
+    #[serde(default)]
+    byte_offset: usize,
+    component_type: u32,
+    #[serde(default)]
+    normalized: bool,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: String,
+    sparse: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfBufferView {
+    buffer: usize,
+    #[serde(default)]
+    byte_offset: usize,
+    byte_length: usize,
+    byte_stride: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfBuffer {
+    uri: Option<String>,
+    byte_length: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfMaterial {
+    pbr_metallic_roughness: Option<GltfPbrMetallicRoughness>,
+    emissive_texture: Option<GltfTextureInfo>,
+    #[serde(default)]
+    emissive_factor: [f64; 3],
+    #[serde(default)]
+    extensions: GltfMaterialExtensions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfPbrMetallicRoughness {
+    #[serde(default = "default_base_color_factor")]
+    base_color_factor: [f64; 4],
+    base_color_texture: Option<GltfTextureInfo>,
+    #[serde(default = "default_one")]
+    metallic_factor: f64,
+    #[serde(default = "default_one")]
+    roughness_factor: f64,
+    metallic_roughness_texture: Option<GltfTextureInfo>,
+}
+
+#[derive(Deserialize)]
+struct GltfTextureInfo {
+    index: usize,
+}
+
+#[derive(Deserialize, Default)]
+struct GltfMaterialExtensions {
+    #[serde(rename = "KHR_materials_transmission")]
+    transmission: Option<GltfTransmission>,
+    #[serde(rename = "KHR_materials_ior")]
+    ior: Option<GltfIor>,
+    #[serde(rename = "KHR_materials_emissive_strength")]
+    emissive_strength: Option<GltfEmissiveStrength>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfTransmission {
+    #[serde(default)]
+    transmission_factor: f64,
+}
+
+#[derive(Deserialize)]
+struct GltfIor {
+    #[serde(default = "default_ior")]
+    ior: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfEmissiveStrength {
+    #[serde(default = "default_one")]
+    emissive_strength: f64,
+}
+
+#[derive(Deserialize)]
+struct GltfTexture {
+    source: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfImage {
+    uri: Option<String>,
+    buffer_view: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct GltfCamera {
+    #[serde(rename = "type")]
+    kind: String,
+    perspective: Option<GltfPerspective>,
+}
+
+#[derive(Deserialize)]
+struct GltfPerspective {
+    yfov: f64,
+}
+
+fn default_mode() -> u32 { 4 }
+
+fn default_base_color_factor() -> [f64; 4] { [1.0, 1.0, 1.0, 1.0] }
+
+fn default_one() -> f64 { 1.0 }
+
+fn default_ior() -> f64 { 1.5 }
+
+pub struct GltfLoader {
+    path: String,
+    gltf: Gltf,
+    buffers: Vec<Vec<u8>>,
+
+    // 同じ画像を複数のマテリアルで共有する（key は画像のインデックスと、ラフネス用に G チャンネルを取り出したかどうか）
+    images: HashMap<(usize, bool), Arc<ImageTexture>>,
+}
+
+impl GltfLoader {
+    // シーンのすべてのメッシュを、ノードの変換行列を掛けてから matrix で変換して読み込む
+    // マテリアルは glTF のものを使う
+    pub fn load(path: &str, matrix: Matrix44) -> Result<Vec<Mesh>, MeshLoadError> {
+        let mut loader = GltfLoader::open(path)?;
+        let mut meshes = vec![];
+        for (node, world) in loader.scene_nodes(matrix)? {
+            if let Some(mesh) = loader.gltf.nodes[node].mesh {
+                for primitive in 0..loader.mesh(mesh)?.primitives.len() {
+                    meshes.push(loader.build_mesh(node, mesh, primitive, world, matrix)?);
+                }
+            }
+        }
+        Ok(meshes)
+    }
+
+    // GltfLoader::load で読み込んだメッシュのうち、MeshSource.group が part のものだけを読み込む
+    pub fn load_part(path: &str, part: &str, matrix: Matrix44) -> Result<Mesh, MeshLoadError> {
+        let mut loader = GltfLoader::open(path)?;
+        for (node, world) in loader.scene_nodes(matrix)? {
+            if let Some(mesh) = loader.gltf.nodes[node].mesh {
+                for primitive in 0..loader.mesh(mesh)?.primitives.len() {
+                    if part_name(node, primitive) == part {
+                        return loader.build_mesh(node, mesh, primitive, world, matrix);
+                    }
+                }
+            }
+        }
+        Err(MeshLoadError::NoFaces { path: path.to_string(), group: Some(part.to_string()), usemtl: None })
+    }
+
+    // シーンを巡回して最初に見つかった透視投影のカメラ
+    pub fn load_camera(path: &str) -> Result<Camera, MeshLoadError> {
+        let loader = GltfLoader::open(path)?;
+        for (node, world) in loader.scene_nodes(Matrix44::identity())? {
+            if let Some(index) = loader.gltf.nodes[node].camera {
+                let camera = loader.gltf.cameras.get(index).ok_or_else(|| loader.invalid(format!("camera index out of range: {}", index)))?;
+                if let (true, Some(ref perspective)) = (camera.kind == "perspective", camera.perspective.as_ref()) {
+                    // glTF のカメラは -Z 方向を向き、+Y が上
+                    // Camera の v_fov は垂直画角の半分（度）
+                    let eye = world * Vector3::zero();
+                    let forward = world.transform_direction(&Vector3::new(0.0, 0.0, -1.0)).normalize();
+                    let y_up = world.transform_direction(&Vector3::new(0.0, 1.0, 0.0)).normalize();
+                    return Ok(Camera::new(eye, eye + forward, y_up, (0.5 * perspective.yfov).to_degrees(), LensShape::Circle, 0.0, 1.0));
+                }
+            }
+        }
+        Err(loader.invalid("perspective camera not found".to_string()))
+    }
+
+    fn open(path: &str) -> Result<GltfLoader, MeshLoadError> {
+        let mut bytes = vec![];
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut bytes))
+            .map_err(|error| MeshLoadError::Io { path: path.to_string(), error })?;
+        let invalid = |message: String| MeshLoadError::InvalidData { path: path.to_string(), message };
+
+        // .glb は JSON のチャンクと BIN のチャンクを持つ
+        let (json, binary) = if bytes.starts_with(b"glTF") {
+            let mut chunks = vec![];
+            let mut position = 12;
+            while position + 8 <= bytes.len() {
+                let length = read_u32(&bytes, position) as usize;
+                let kind = read_u32(&bytes, position + 4);
+                let start = position + 8;
+                if start + length > bytes.len() {
+                    return Err(invalid("unexpected end of file".to_string()));
+                }
+                chunks.push((kind, &bytes[start..start + length]));
+                position = start + length;
+            }
+            let json = chunks.iter().find(|c| c.0 == 0x4E4F534A).ok_or_else(|| invalid("JSON chunk not found".to_string()))?.1;
+            let binary = chunks.iter().find(|c| c.0 == 0x004E4942).map(|c| c.1.to_vec());
+            (json, binary)
+        } else {
+            (&bytes[..], None)
+        };
+
+        let gltf: Gltf = serde_json::from_slice(json).map_err(|e| invalid(format!("invalid glTF: {}", e)))?;
+
+        let mut loader = GltfLoader { path: path.to_string(), gltf, buffers: vec![], images: HashMap::new() };
+        let mut binary = binary;
+        for i in 0..loader.gltf.buffers.len() {
+            let data = match loader.gltf.buffers[i].uri {
+                Some(ref uri) => loader.read_uri(uri)?,
+                // uri の無いバッファは .glb の BIN チャンク
+                None => binary.take().ok_or_else(|| loader.invalid(format!("buffers[{}] has no data", i)))?,
+            };
+            if data.len() < loader.gltf.buffers[i].byte_length {
+                return Err(loader.invalid(format!("buffers[{}] is too short", i)));
+            }
+            loader.buffers.push(data);
+        }
+        Ok(loader)
+    }
+
+    // (ノードのインデックス, ワールド行列) をシーンのノードの巡回順に返す
+    fn scene_nodes(&self, matrix: Matrix44) -> Result<Vec<(usize, Matrix44)>, MeshLoadError> {
+        let roots = match self.gltf.scene.or(if self.gltf.scenes.is_empty() { None } else { Some(0) }) {
+            Some(scene) => self.gltf.scenes.get(scene).ok_or_else(|| self.invalid(format!("scene index out of range: {}", scene)))?.nodes.clone(),
+            // シーンが無い場合は、どのノードの子でもないノードをルートとする
+            None => (0..self.gltf.nodes.len()).filter(|i| self.gltf.nodes.iter().all(|n| !n.children.contains(i))).collect(),
+        };
+
+        let mut result = vec![];
+        let mut stack: Vec<(usize, Matrix44)> = roots.iter().rev().map(|i| (*i, matrix)).collect();
+        while let Some((index, parent)) = stack.pop() {
+            if result.len() > self.gltf.nodes.len() {
+                return Err(self.invalid("node hierarchy has a cycle".to_string()));
+            }
+            let node = self.gltf.nodes.get(index).ok_or_else(|| self.invalid(format!("node index out of range: {}", index)))?;
+            let world = parent * node_matrix(node);
+            result.push((index, world));
+            for child in node.children.iter().rev() {
+                stack.push((*child, world));
+            }
+        }
+        Ok(result)
+    }
+
+    fn mesh(&self, index: usize) -> Result<&GltfMesh, MeshLoadError> {
+        self.gltf.meshes.get(index).ok_or_else(|| self.invalid(format!("mesh index out of range: {}", index)))
+    }
+
+    fn build_mesh(&mut self, node: usize, mesh: usize, primitive: usize, world: Matrix44, matrix: Matrix44) -> Result<Mesh, MeshLoadError> {
+        let (attributes, indices, material, mode) = {
+            let p = &self.mesh(mesh)?.primitives[primitive];
+            (p.attributes.clone(), p.indices, p.material, p.mode)
+        };
+        let normal_matrix = world.inverse().transpose();
+
+        let position = *attributes.get("POSITION").ok_or_else(|| self.invalid(format!("meshes[{}].primitives[{}] has no POSITION", mesh, primitive)))?;
+        let vertexes: Vec<Vector3> = self.read_accessor(position)?.iter()
+            .map(|v| world * Vector3::new(v[0], v[1], v[2]))
+            .collect();
+        let normals: Vec<Vector3> = match attributes.get("NORMAL") {
+            Some(&index) => self.read_accessor(index)?.iter()
+                .map(|n| normal_matrix.transform_direction(&Vector3::new(n[0], n[1], n[2])).normalize())
+                .collect(),
+            None => vec![],
+        };
+        // glTF の UV は左上が原点
+        let uvs: Vec<Vector2> = match attributes.get("TEXCOORD_0") {
+            Some(&index) => self.read_accessor(index)?.iter().map(|t| Vector2::new(t[0], 1.0 - t[1])).collect(),
+            None => vec![],
+        };
+        // 頂点カラーはリニア
+        let colors: Vec<Color> = match attributes.get("COLOR_0") {
+            Some(&index) => self.read_accessor(index)?.iter().map(|c| Color::new(c[0], c[1], c[2])).collect(),
+            None => vec![],
+        };
+
+        let indices: Vec<usize> = match indices {
+            Some(index) => self.read_accessor(index)?.iter().map(|i| i[0] as usize).collect(),
+            None => (0..vertexes.len()).collect(),
+        };
+        if indices.iter().any(|i| *i >= vertexes.len()) {
+            return Err(self.invalid(format!("meshes[{}].primitives[{}] has an index out of range", mesh, primitive)));
+        }
+
+        // 4: TRIANGLES, 5: TRIANGLE_STRIP, 6: TRIANGLE_FAN（点と線は読み込まない）
+        let mut triangles = vec![];
+        match mode {
+            4 => {
+                for t in indices.chunks(3).filter(|t| t.len() == 3) {
+                    triangles.push([t[0], t[1], t[2]]);
+                }
+            }
+            5 => {
+                for i in 2..indices.len() {
+                    triangles.push(if i % 2 == 0 {
+                        [indices[i - 2], indices[i - 1], indices[i]]
+                    } else {
+                        [indices[i - 1], indices[i - 2], indices[i]]
+                    });
+                }
+            }
+            6 => {
+                for i in 2..indices.len() {
+                    triangles.push([indices[0], indices[i - 1], indices[i]]);
+                }
+            }
+            _ => {}
+        }
+
+        // 行列式が負の場合は裏返るので、頂点の順番を入れ替える
+        let flip = world[0][0] * (world[1][1] * world[2][2] - world[1][2] * world[2][1])
+            - world[0][1] * (world[1][0] * world[2][2] - world[1][2] * world[2][0])
+            + world[0][2] * (world[1][0] * world[2][1] - world[1][1] * world[2][0]) < 0.0;
+
+        let faces = triangles.iter()
+            .map(|t| {
+                let t = if flip { [t[0], t[2], t[1]] } else { *t };
+                Face {
+                    v0: t[0],
+                    v1: t[1],
+                    v2: t[2],
+                    normal_indexes: if normals.is_empty() { None } else { Some(t) },
+                    uv_indexes: if uvs.is_empty() { None } else { Some(t) },
+                    fan: false,
+                }
+            })
+            .collect();
+
+        Ok(Mesh {
+            vertexes,
+            normals,
+            uvs,
+            colors,
+            faces,
+            material: self.build_material(material)?,
+            source: Some(MeshSource {
+                path: self.path.clone(),
+                matrix,
+                group: Some(part_name(node, primitive)),
+                merge_groups: false,
+                usemtl: None,
+                import_mtl: false,
+                subdivision: None,
+                displacement: None,
+            }),
+            smooth: false,
+            project_uvs: false,
+        })
+    }
+
+    // metallic-roughness の PBR マテリアルを SurfaceType に対応付ける
+    // - KHR_materials_transmission の transmissionFactor が 0.5 以上 : 透過（KHR_materials_ior を屈折率とする）
+    // - metallicFactor が 0.5 以上 : GGX（metallicFactor を f0 とする）
+    // - それ以外 : 完全拡散反射
+    // マテリアルが無い場合は glTF の仕様のデフォルト値を使う
+    fn build_material(&mut self, index: Option<usize>) -> Result<Material, MeshLoadError> {
+        let (base_color_factor, base_color_texture, metallic, roughness, roughness_texture, emissive_factor, emissive_texture, transmission, ior) = match index {
+            Some(index) => {
+                let m = self.gltf.materials.get(index).ok_or_else(|| self.invalid(format!("material index out of range: {}", index)))?;
+                let strength = m.extensions.emissive_strength.as_ref().map_or(1.0, |e| e.emissive_strength);
+                let e = m.emissive_factor;
+                let (base_color_factor, base_color_texture, metallic, roughness, roughness_texture) = match m.pbr_metallic_roughness {
+                    Some(ref pbr) => (
+                        pbr.base_color_factor,
+                        pbr.base_color_texture.as_ref().map(|t| t.index),
+                        pbr.metallic_factor,
+                        pbr.roughness_factor,
+                        pbr.metallic_roughness_texture.as_ref().map(|t| t.index),
+                    ),
+                    None => (default_base_color_factor(), None, 1.0, 1.0, None),
+                };
+                (
+                    base_color_factor, base_color_texture, metallic, roughness, roughness_texture,
+                    Color::new(e[0], e[1], e[2]) * strength,
+                    m.emissive_texture.as_ref().map(|t| t.index),
+                    m.extensions.transmission.as_ref().map_or(0.0, |t| t.transmission_factor),
+                    m.extensions.ior.as_ref().map_or(default_ior(), |i| i.ior),
+                )
+            }
+            None => (default_base_color_factor(), None, 1.0, 1.0, None, Color::zero(), None, 0.0, default_ior()),
+        };
+
+        let surface = if transmission >= 0.5 {
+            if roughness == 0.0 {
+                SurfaceType::Refraction { refractive_index: ior }
+            } else {
+                SurfaceType::GGXRefraction { refractive_index: ior }
+            }
+        } else if metallic >= 0.5 {
+            SurfaceType::GGX { f0: metallic }
+        } else {
+            SurfaceType::Diffuse
+        };
+
+        let base_color = Color::new(base_color_factor[0], base_color_factor[1], base_color_factor[2]);
+        Ok(Material {
+            surface,
+            albedo: self.build_texture(base_color_texture, false, base_color)?,
+            emission: self.build_texture(emissive_texture, false, emissive_factor)?,
+            // ラフネスは metallicRoughnessTexture の G チャンネル
+            roughness: self.build_texture(roughness_texture, true, Color::from_one(roughness))?,
+        })
+    }
+
+    fn build_texture(&mut self, texture: Option<usize>, green: bool, color: Color) -> Result<Texture, MeshLoadError> {
+        let source = match texture {
+            Some(index) => self.gltf.textures.get(index).ok_or_else(|| self.invalid(format!("texture index out of range: {}", index)))?.source,
+            None => None,
+        };
+        let image_texture = match source {
+            Some(image) => Some(self.image_texture(image, green)?),
+            None => None,
+        };
+        Ok(Texture { image_texture, color })
+    }
+
+    fn image_texture(&mut self, index: usize, green: bool) -> Result<Arc<ImageTexture>, MeshLoadError> {
+        if let Some(texture) = self.images.get(&(index, green)) {
+            return Ok(texture.clone());
+        }
+
+        let (uri, buffer_view) = {
+            let image = self.gltf.images.get(index).ok_or_else(|| self.invalid(format!("image index out of range: {}", index)))?;
+            (image.uri.clone(), image.buffer_view)
+        };
+        let name = match uri {
+            Some(ref uri) if !uri.starts_with("data:") => resolve_path(&self.path, &decode_percent(uri)),
+            _ => format!("{}#images[{}]", self.path, index),
+        };
+        let bytes = match (uri, buffer_view) {
+            (Some(uri), _) => self.read_uri(&uri)?,
+            (None, Some(view)) => self.read_buffer_view(view)?.to_vec(),
+            (None, None) => return Err(self.invalid(format!("images[{}] has no data", index))),
+        };
+        let mut image = image::load_from_memory(&bytes).map_err(|e| self.invalid(format!("images[{}]: {}", index, e)))?;
+
+        if green {
+            // ImageTexture はサンプリング時にガンマ補正を外すので、G チャンネルの値がそのまま得られるように逆変換しておく
+            let (width, height) = image.dimensions();
+            let mut buffer = ImageBuffer::new(width, height);
+            for (x, y, pixel) in buffer.enumerate_pixels_mut() {
+                let g = image.get_pixel(x, y).data[1] as f64 / 255.0;
+                *pixel = Luma([(g.powf(config::GAMMA_FACTOR.recip()) * 255.0).round() as u8]);
+            }
+            image = DynamicImage::ImageLuma8(buffer);
+        }
+
+        let texture = Arc::new(ImageTexture::from_image(image, &name));
+        self.images.insert((index, green), texture.clone());
+        Ok(texture)
+    }
+
+    // 要素ごとの値を f64 で返す（normalized な整数は 0-1 または -1-1 にする）
+    fn read_accessor(&self, index: usize) -> Result<Vec<Vec<f64>>, MeshLoadError> {
+        let accessor = self.gltf.accessors.get(index).ok_or_else(|| self.invalid(format!("accessor index out of range: {}", index)))?;
+        if accessor.sparse.is_some() {
+            return Err(self.invalid(format!("accessors[{}]: sparse accessors are not supported", index)));
+        }
+
+        let components = match accessor.kind.as_str() {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            "MAT2" => 4,
+            "MAT3" => 9,
+            "MAT4" => 16,
+            kind => return Err(self.invalid(format!("accessors[{}]: unknown type \"{}\"", index, kind))),
+        };
+        let size = match accessor.component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            t => return Err(self.invalid(format!("accessors[{}]: unknown componentType {}", index, t))),
+        };
+
+        let view = match accessor.buffer_view {
+            Some(view) => view,
+            // bufferView が無い場合はすべて 0
+            None => return Ok(vec![vec![0.0; components]; accessor.count]),
+        };
+        let data = self.read_buffer_view(view)?;
+        let stride = self.gltf.buffer_views[view].byte_stride.unwrap_or(components * size);
+        if accessor.count > 0 && accessor.byte_offset + stride * (accessor.count - 1) + components * size > data.len() {
+            return Err(self.invalid(format!("accessors[{}] is out of bufferViews[{}]", index, view)));
+        }
+
+        let normalized = accessor.normalized;
+        let read = |offset: usize| -> f64 {
+            let b = &data[offset..offset + size];
+            match accessor.component_type {
+                5120 => { let v = b[0] as i8 as f64; if normalized { (v / 127.0).max(-1.0) } else { v } }
+                5121 => { let v = b[0] as f64; if normalized { v / 255.0 } else { v } }
+                5122 => { let v = i16::from_le_bytes([b[0], b[1]]) as f64; if normalized { (v / 32767.0).max(-1.0) } else { v } }
+                5123 => { let v = u16::from_le_bytes([b[0], b[1]]) as f64; if normalized { v / 65535.0 } else { v } }
+                5125 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                _ => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            }
+        };
+
+        Ok((0..accessor.count)
+            .map(|i| {
+                let offset = accessor.byte_offset + stride * i;
+                (0..components).map(|c| read(offset + c * size)).collect()
+            })
+            .collect())
+    }
+
+    fn read_buffer_view(&self, index: usize) -> Result<&[u8], MeshLoadError> {
+        let view = self.gltf.buffer_views.get(index).ok_or_else(|| self.invalid(format!("bufferView index out of range: {}", index)))?;
+        let buffer = self.buffers.get(view.buffer).ok_or_else(|| self.invalid(format!("buffer index out of range: {}", view.buffer)))?;
+        if view.byte_offset + view.byte_length > buffer.len() {
+            return Err(self.invalid(format!("bufferViews[{}] is out of buffers[{}]", index, view.buffer)));
+        }
+        Ok(&buffer[view.byte_offset..view.byte_offset + view.byte_length])
+    }
+
+    // data URI（base64）または glTF ファイルからの相対パス
+    fn read_uri(&self, uri: &str) -> Result<Vec<u8>, MeshLoadError> {
+        if uri.starts_with("data:") {
+            let data = uri.splitn(2, ";base64,").nth(1).ok_or_else(|| self.invalid("only base64 data URIs are supported".to_string()))?;
+            decode_base64(data).ok_or_else(|| self.invalid("invalid base64 data URI".to_string()))
+        } else {
+            let path = resolve_path(&self.path, &decode_percent(uri));
+            let mut bytes = vec![];
+            File::open(&path)
+                .and_then(|mut f| f.read_to_end(&mut bytes))
+                .map_err(|error| MeshLoadError::Io { path, error })?;
+            Ok(bytes)
+        }
+    }
+
+    fn invalid(&self, message: String) -> MeshLoadError {
+        MeshLoadError::InvalidData { path: self.path.clone(), message }
+    }
+}
+
+pub fn is_gltf_path(path: &str) -> bool {
+    match Path::new(path).extension() {
+        Some(extension) => {
+            let extension = extension.to_string_lossy().to_lowercase();
+            extension == "gltf" || extension == "glb"
+        }
+        None => false,
+    }
+}
+
+// シーンファイルの group として使うプリミティブの名前
+fn part_name(node: usize, primitive: usize) -> String {
+    format!("node{}/primitive{}", node, primitive)
+}
+
+// matrix が無い場合は T * R * S
+fn node_matrix(node: &GltfNode) -> Matrix44 {
+    if let Some(m) = node.matrix {
+        // glTF の行列は列優先
+        let mut matrix = Matrix44::identity();
+        for row in 0..4 {
+            for column in 0..4 {
+                matrix[row][column] = m[column * 4 + row];
+            }
+        }
+        return matrix;
+    }
+
+    let t = node.translation.unwrap_or([0.0, 0.0, 0.0]);
+    let s = node.scale.unwrap_or([1.0, 1.0, 1.0]);
+    let [x, y, z, w] = node.rotation.unwrap_or([0.0, 0.0, 0.0, 1.0]);
+    let rotation = Matrix44::new([
+        [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - z * w), 2.0 * (x * z + y * w), 0.0],
+        [2.0 * (x * y + z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - x * w), 0.0],
+        [2.0 * (x * z - y * w), 2.0 * (y * z + x * w), 1.0 - 2.0 * (x * x + y * y), 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ]);
+    Matrix44::translate(t[0], t[1], t[2]) * rotation * Matrix44::scale(s[0], s[1], s[2])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn resolve_path(base: &str, file: &str) -> String {
+    match Path::new(base).parent() {
+        Some(dir) => dir.join(file).to_string_lossy().into_owned(),
+        None => file.to_string(),
+    }
+}
+
+// URI の %20 などを元に戻す
+fn decode_percent(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Some(b) = ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                result.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    let mut result = vec![];
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' | b'\n' | b'\r' => continue,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(result)
+}
@@ -0,0 +1,279 @@
+extern crate image;
+
+use image::GenericImage;
+
+use config;
+use vector::{Vector3, Vector2};
+use material::Material;
+use camera::Ray;
+use color::Color;
+use texture::ImageTexture;
+use bvh::{Aabb, intersect_polygon};
+use scene::{Intersectable, Intersection, Surface};
+use description::{ElementDescription, ElementType, from_vector3, unspanned};
+
+// グレースケール画像を高さとした地形
+// 画像の左下が extent.min の XZ、右上が extent.max の XZ になり、画素値 0 から 1 を extent.min.y から extent.max.y にする
+// 画素を格子の頂点として、1つのセルを2つの三角形で表す
+pub struct Heightfield {
+    pub texture: ImageTexture,
+    pub extent: Aabb,
+    pub material: Material,
+
+    // 格子の頂点数
+    width: usize,
+    depth: usize,
+
+    // 頂点の座標と法線（X 方向が先）
+    vertexes: Vec<Vector3>,
+    normals: Vec<Vector3>,
+
+    // min-max quadtree
+    // levels[0] はセルごとの高さの最小値と最大値で、levels[k] は levels[k - 1] の 2x2 のセルをまとめたもの
+    levels: Vec<MinMaxLevel>,
+}
+
+struct MinMaxLevel {
+    width: usize,
+    depth: usize,
+    heights: Vec<(f64, f64)>,
+}
+
+impl Heightfield {
+    pub fn new(texture: ImageTexture, extent: Aabb, material: Material) -> Heightfield {
+        let luma = texture.image.to_luma();
+
+        // 幅か高さが 1 ピクセルの画像は、同じ画素を2つ並べて 2 ピクセルとして扱う
+        let (image_width, image_depth) = (texture.image.width(), texture.image.height());
+        let width = image_width.max(2) as usize;
+        let depth = image_depth.max(2) as usize;
+        let size = extent.max - extent.min;
+        let step_x = size.x / (width - 1) as f64;
+        let step_z = size.z / (depth - 1) as f64;
+
+        // 画像の下の行が Z の小さい側になる（テクスチャの V 座標と同じ向き）
+        let mut vertexes = Vec::with_capacity(width * depth);
+        for j in 0..depth {
+            for i in 0..width {
+                let value = luma.get_pixel((i as u32).min(image_width - 1), ((depth - 1 - j) as u32).min(image_depth - 1)).data[0] as f64 / 255.0;
+                vertexes.push(Vector3::new(
+                    extent.min.x + step_x * i as f64,
+                    extent.min.y + size.y * value,
+                    extent.min.z + step_z * j as f64,
+                ));
+            }
+        }
+
+        // 中心差分で勾配を求める（端では片側の差分にする）
+        let mut normals = Vec::with_capacity(width * depth);
+        for j in 0..depth {
+            for i in 0..width {
+                let (i0, i1) = (i.saturating_sub(1), (i + 1).min(width - 1));
+                let (j0, j1) = (j.saturating_sub(1), (j + 1).min(depth - 1));
+                let dx = (vertexes[j * width + i1].y - vertexes[j * width + i0].y) / (step_x * (i1 - i0) as f64);
+                let dz = (vertexes[j1 * width + i].y - vertexes[j0 * width + i].y) / (step_z * (j1 - j0) as f64);
+                normals.push(Vector3::new(-dx, 1.0, -dz).normalize());
+            }
+        }
+
+        let mut heightfield = Heightfield {
+            texture,
+            extent,
+            material,
+            width,
+            depth,
+            vertexes,
+            normals,
+            levels: vec![],
+        };
+        heightfield.build_levels();
+        heightfield
+    }
+
+    fn build_levels(&mut self) {
+        let mut cells = MinMaxLevel { width: self.width - 1, depth: self.depth - 1, heights: vec![] };
+        for j in 0..cells.depth {
+            for i in 0..cells.width {
+                let ys = [
+                    self.vertex(i, j).y,
+                    self.vertex(i + 1, j).y,
+                    self.vertex(i, j + 1).y,
+                    self.vertex(i + 1, j + 1).y,
+                ];
+                let min = ys.iter().cloned().fold(config::INF, f64::min);
+                let max = ys.iter().cloned().fold(-config::INF, f64::max);
+                cells.heights.push((min, max));
+            }
+        }
+        self.levels.push(cells);
+
+        while self.levels.last().map_or(false, |l| l.width > 1 || l.depth > 1) {
+            let next = {
+                let prev = self.levels.last().unwrap();
+                let mut next = MinMaxLevel { width: (prev.width + 1) / 2, depth: (prev.depth + 1) / 2, heights: vec![] };
+                for j in 0..next.depth {
+                    for i in 0..next.width {
+                        let mut height = (config::INF, -config::INF);
+                        for &(ci, cj) in &[(2 * i, 2 * j), (2 * i + 1, 2 * j), (2 * i, 2 * j + 1), (2 * i + 1, 2 * j + 1)] {
+                            if ci < prev.width && cj < prev.depth {
+                                let (min, max) = prev.heights[cj * prev.width + ci];
+                                height = (height.0.min(min), height.1.max(max));
+                            }
+                        }
+                        next.heights.push(height);
+                    }
+                }
+                next
+            };
+            self.levels.push(next);
+        }
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> Vector3 {
+        self.vertexes[j * self.width + i]
+    }
+
+    // level のノード (i, j) が覆う範囲
+    fn node_aabb(&self, level: usize, i: usize, j: usize) -> Aabb {
+        let (min_y, max_y) = self.levels[level].heights[j * self.levels[level].width + i];
+        let i_end = ((i + 1) << level).min(self.width - 1);
+        let j_end = ((j + 1) << level).min(self.depth - 1);
+        let min = self.vertex(i << level, j << level);
+        let max = self.vertex(i_end, j_end);
+        Aabb {
+            min: Vector3::new(min.x, min_y, min.z),
+            max: Vector3::new(max.x, max_y, max.z),
+        }
+    }
+
+    fn intersect_node(&self, level: usize, i: usize, j: usize, ray: &Ray, intersection: &mut Intersection) -> bool {
+        if level == 0 {
+            return self.intersect_cell(i, j, ray, intersection);
+        }
+
+        // 手前の子ノードから調べて、より近い交点が見つかった子ノードより奥は調べない
+        let child_level = &self.levels[level - 1];
+        let mut children = vec![];
+        for &(ci, cj) in &[(2 * i, 2 * j), (2 * i + 1, 2 * j), (2 * i, 2 * j + 1), (2 * i + 1, 2 * j + 1)] {
+            if ci < child_level.width && cj < child_level.depth {
+                let (t_min, t_max) = self.node_aabb(level - 1, ci, cj).ray_interval(ray);
+                if t_min <= t_max && t_max >= 0.0 {
+                    children.push((t_min, ci, cj));
+                }
+            }
+        }
+        children.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let mut any_hit = false;
+        for (t_min, ci, cj) in children {
+            if t_min > intersection.distance {
+                break;
+            }
+            if self.intersect_node(level - 1, ci, cj, ray, intersection) {
+                any_hit = true;
+            }
+        }
+        any_hit
+    }
+
+    fn intersect_cell(&self, i: usize, j: usize, ray: &Ray, intersection: &mut Intersection) -> bool {
+        // 法線が上を向くように頂点を並べる
+        let corners = [(i, j), (i + 1, j + 1), (i + 1, j), (i, j), (i, j + 1), (i + 1, j + 1)];
+        let mut any_hit = false;
+        for triangle in corners.chunks(3) {
+            let v0 = self.vertex(triangle[0].0, triangle[0].1);
+            let v1 = self.vertex(triangle[1].0, triangle[1].1);
+            let v2 = self.vertex(triangle[2].0, triangle[2].1);
+            if intersect_polygon(&v0, &v1, &v2, ray, intersection) {
+                let (u, v) = (intersection.uv.x, intersection.uv.y);
+                let normal = |(ni, nj): (usize, usize)| self.normals[nj * self.width + ni];
+                intersection.normal = (normal(triangle[0]) * (1.0 - u - v) + normal(triangle[1]) * u + normal(triangle[2]) * v).normalize();
+                intersection.uv = Vector2::new(
+                    (intersection.position.x - self.extent.min.x) / (self.extent.max.x - self.extent.min.x),
+                    (intersection.position.z - self.extent.min.z) / (self.extent.max.z - self.extent.min.z),
+                );
+                intersection.tangent = Vector3::new(1.0, 0.0, 0.0);
+                intersection.vertex_color = Color::one();
+                any_hit = true;
+            }
+        }
+        any_hit
+    }
+}
+
+impl Intersectable for Heightfield {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        let top = self.levels.len() - 1;
+        let (t_min, t_max) = self.node_aabb(top, 0, 0).ray_interval(ray);
+        if t_min > t_max || t_max < 0.0 || t_min > intersection.distance {
+            return false;
+        }
+        self.intersect_node(top, 0, 0, ray, intersection)
+    }
+
+    fn material(&self) -> &Material { &self.material }
+
+    // 実際の高さの範囲に縮める
+    fn aabb(&self) -> Aabb {
+        self.node_aabb(self.levels.len() - 1, 0, 0)
+    }
+
+    fn bounded(&self) -> bool { true }
+
+    fn nee_available(&self) -> bool { false }
+
+    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
+        unimplemented!()
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Heightfield, &self.material);
+        description.path = Some(unspanned(&self.texture.path));
+        description.min = Some(from_vector3(&self.extent.min));
+        description.max = Some(from_vector3(&self.extent.max));
+        Some(description)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, GrayImage, Luma};
+    use material::SurfaceType;
+    use texture::Texture;
+
+    fn heightfield(width: u32, depth: u32, value: u8) -> Heightfield {
+        let image = DynamicImage::ImageLuma8(GrayImage::from_pixel(width, depth, Luma([value])));
+        let extent = Aabb { min: Vector3::new(-1.0, 0.0, -1.0), max: Vector3::new(1.0, 1.0, 1.0) };
+        let material = Material {
+            surface: SurfaceType::Diffuse,
+            albedo: Texture::white(),
+            emission: Texture::black(),
+            roughness: Texture::black(),
+        };
+        Heightfield::new(ImageTexture::from_image(image, "test"), extent, material)
+    }
+
+    fn hit_distance(heightfield: &Heightfield, x: f64, z: f64) -> Option<f64> {
+        let ray = Ray { origin: Vector3::new(x, 2.0, z), direction: Vector3::new(0.0, -1.0, 0.0) };
+        let mut intersection = Intersection::empty();
+        if heightfield.intersect(&ray, &mut intersection) { Some(intersection.distance) } else { None }
+    }
+
+    #[test]
+    fn flat_image_is_hit_at_its_height() {
+        let heightfield = heightfield(4, 4, 255);
+        let distance = hit_distance(&heightfield, 0.3, -0.2).unwrap();
+        assert!((distance - 1.0).abs() < 1e-6, "{}", distance);
+        assert_eq!(hit_distance(&heightfield, 1.5, 0.0), None);
+    }
+
+    #[test]
+    fn one_pixel_wide_or_deep_images_are_extruded() {
+        for &(width, depth) in &[(1, 1), (1, 5), (5, 1)] {
+            let heightfield = heightfield(width, depth, 0);
+            let distance = hit_distance(&heightfield, 0.3, -0.2).unwrap();
+            assert!((distance - 2.0).abs() < 1e-6, "{}x{}: {}", width, depth, distance);
+        }
+    }
+}
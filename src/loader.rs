@@ -1,60 +1,1096 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, BufRead};
+use std::io::{self, BufReader, BufRead, Read};
+use std::path::Path;
+use std::str::FromStr;
 
-use vector::Vector3;
+use vector::{Vector3, Vector2};
 use matrix::Matrix44;
-use scene::{Mesh, Face};
-use material::Material;
+use scene::{Mesh, Face, MeshSource};
+use material::{Material, SurfaceType};
+use texture::Texture;
+use color::{Color, gamma_to_linear};
+
+#[derive(Debug)]
+pub enum MeshLoadError {
+    Io { path: String, error: io::Error },
+    Parse { path: String, line: usize, message: String },
+    InvalidData { path: String, message: String },
+    NoFaces { path: String, group: Option<String>, usemtl: Option<String> },
+    MaterialNotFound { path: String, name: String },
+}
+
+impl fmt::Display for MeshLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MeshLoadError::Io { ref path, ref error } => write!(f, "{}: {}", path, error),
+            MeshLoadError::Parse { ref path, line, ref message } => write!(f, "{}:{}: {}", path, line, message),
+            MeshLoadError::InvalidData { ref path, ref message } => write!(f, "{}: {}", path, message),
+            MeshLoadError::NoFaces { ref path, ref group, ref usemtl } => {
+                write!(f, "{}: no faces found", path)?;
+                if let Some(ref group) = *group {
+                    write!(f, " in group \"{}\"", group)?;
+                }
+                if let Some(ref usemtl) = *usemtl {
+                    write!(f, " with usemtl \"{}\"", usemtl)?;
+                }
+                Ok(())
+            }
+            MeshLoadError::MaterialNotFound { ref path, ref name } => write!(f, "{}: material not found in mtllib: \"{}\"", path, name),
+        }
+    }
+}
+
+// o, g と usemtl で区切られた面の集まり（OBJ 以外は1つだけ）
+// 面のインデックスはファイル全体で共通
+struct ObjGroup {
+    name: String,
+    material: Option<String>,
+    faces: Vec<Face>,
+}
+
+// メッシュのファイルをパースした結果
+struct MeshData {
+    vertexes: Vec<Vector3>,
+    normals: Vec<Vector3>,
+    uvs: Vec<Vector2>,
+
+    // 頂点カラー（vertexes と同じ順番。無い場合は空）
+    colors: Vec<Color>,
+
+    groups: Vec<ObjGroup>,
+
+    // mtllib で指定されたファイル（OBJ ファイルからの相対パスを解決済み）と行番号
+    material_libraries: Vec<(String, usize)>,
+}
+
+const DEFAULT_GROUP: &str = "default";
 
 pub struct ObjLoader;
 
 impl ObjLoader {
-    pub fn load(path: &str, matrix: Matrix44, material: Material) -> Mesh {
+    // ファイル全体を1つのメッシュとして読み込む
+    // mtllib, usemtl は無視して、すべての面に material を使う
+    pub fn load(path: &str, matrix: Matrix44, material: Material) -> Result<Mesh, MeshLoadError> {
+        let data = ObjLoader::parse(path)?;
+        let faces: Vec<&Face> = data.groups.iter().flat_map(|g| g.faces.iter()).collect();
+        let mut mesh = data.build_mesh(&faces, path, None, None, matrix, material);
+        mesh.source.as_mut().unwrap().merge_groups = true;
+        Ok(mesh)
+    }
+
+    // o, g のグループごとに別のメッシュとして読み込む
+    // import_mtl が true の場合は usemtl ごとにも分割して、MTL ファイルのマテリアルを使う（usemtl より前の面は material を使う）
+    pub fn load_groups(path: &str, matrix: Matrix44, material: Material, import_mtl: bool) -> Result<Vec<Mesh>, MeshLoadError> {
+        let data = ObjLoader::parse(path)?;
+
+        if import_mtl {
+            let materials = data.load_material_libraries(path)?;
+            let mut meshes = vec![];
+            for group in data.groups.iter().filter(|g| !g.faces.is_empty()) {
+                let faces: Vec<&Face> = group.faces.iter().collect();
+                let mut mesh = data.build_mesh(&faces, path, Some(group.name.clone()), group.material.clone(), matrix,
+                                               find_material(&materials, path, group.material.as_ref(), &material)?);
+                mesh.source.as_mut().unwrap().import_mtl = true;
+                meshes.push(mesh);
+            }
+            Ok(meshes)
+        } else {
+            let mut names: Vec<&String> = vec![];
+            for group in data.groups.iter().filter(|g| !g.faces.is_empty()) {
+                if !names.contains(&&group.name) {
+                    names.push(&group.name);
+                }
+            }
+            Ok(names.iter()
+                .map(|name| {
+                    let faces: Vec<&Face> = data.groups.iter()
+                        .filter(|g| g.name == **name)
+                        .flat_map(|g| g.faces.iter())
+                        .collect();
+                    data.build_mesh(&faces, path, Some(name.to_string()), None, matrix, material.clone())
+                })
+                .collect())
+        }
+    }
+
+    // グループ名と usemtl のマテリアル名を指定して、一部の面だけを読み込む
+    // import_mtl が true の場合は、usemtl が None なら usemtl より前の面を選び、MTL ファイルのマテリアルを使う
+    pub fn load_part(path: &str, group: Option<&str>, usemtl: Option<&str>, import_mtl: bool, matrix: Matrix44, material: Material) -> Result<Mesh, MeshLoadError> {
+        let data = ObjLoader::parse(path)?;
+        let faces: Vec<&Face> = data.groups.iter()
+            .filter(|g| group.map_or(true, |name| g.name == name))
+            .filter(|g| if import_mtl {
+                g.material.as_ref().map(|m| m.as_str()) == usemtl
+            } else {
+                usemtl.map_or(true, |name| g.material.as_ref().map_or(false, |m| m == name))
+            })
+            .flat_map(|g| g.faces.iter())
+            .collect();
+        if faces.is_empty() {
+            return Err(MeshLoadError::NoFaces {
+                path: path.to_string(),
+                group: group.map(|s| s.to_string()),
+                usemtl: usemtl.map(|s| s.to_string()),
+            });
+        }
+
+        let usemtl = usemtl.map(|s| s.to_string());
+        let material = if import_mtl {
+            find_material(&data.load_material_libraries(path)?, path, usemtl.as_ref(), &material)?
+        } else {
+            material
+        };
+        let mut mesh = data.build_mesh(&faces, path, group.map(|s| s.to_string()), usemtl, matrix, material);
+        mesh.source.as_mut().unwrap().import_mtl = import_mtl;
+        Ok(mesh)
+    }
+
+    fn parse(path: &str) -> Result<MeshData, MeshLoadError> {
+        let f = File::open(path).map_err(|error| MeshLoadError::Io { path: path.to_string(), error })?;
+        let file = BufReader::new(&f);
+
+        let mut data = MeshData {
+            vertexes: vec![],
+            normals: vec![],
+            uvs: vec![],
+            colors: vec![],
+            // o, g より前の面は default グループに入れる
+            groups: vec![ObjGroup { name: DEFAULT_GROUP.to_string(), material: None, faces: vec![] }],
+            material_libraries: vec![],
+        };
+        let mut current_group = 0;
+
+        for (i, line) in file.lines().enumerate() {
+            let line_number = i + 1;
+            let error = |message: String| MeshLoadError::Parse { path: path.to_string(), line: line_number, message };
+
+            let l = line.map_err(|error| MeshLoadError::Io { path: path.to_string(), error })?;
+            // コメントを取り除く
+            let l = match l.find('#') {
+                Some(pos) => &l[..pos],
+                None => &l[..],
+            };
+            let split_line: Vec<&str> = l.split_whitespace().collect();
+            if split_line.is_empty() {
+                continue;
+            }
+
+            match split_line[0] {
+                "v" => {
+                    // 4番目以降の要素（w や頂点カラー）は無視する
+                    let v = parse_floats(&split_line[1..], 3, 3).map_err(&error)?;
+                    data.vertexes.push(Vector3::new(v[0], v[1], v[2]));
+                }
+                "vn" => {
+                    let v = parse_floats(&split_line[1..], 3, 3).map_err(&error)?;
+                    data.normals.push(Vector3::new(v[0], v[1], v[2]));
+                }
+                "vt" => {
+                    // v は省略可能、w は無視する
+                    let v = parse_floats(&split_line[1..], 1, 2).map_err(&error)?;
+                    data.uvs.push(Vector2::new(v[0], *v.get(1).unwrap_or(&0.0)));
+                }
+                "f" => {
+                    if split_line.len() < 4 {
+                        return Err(error(format!("face needs at least 3 vertexes: \"{}\"", l.trim())));
+                    }
+
+                    let mut corners = vec![];
+                    for token in &split_line[1..] {
+                        corners.push(data.parse_corner(token).map_err(&error)?);
+                    }
+
+                    // 多角形は扇状に三角形分割する
+                    for k in 1..(corners.len() - 1) {
+                        let (a, b, c) = (&corners[0], &corners[k], &corners[k + 1]);
+                        data.groups[current_group].faces.push(Face {
+                            v0: a.0,
+                            v1: b.0,
+                            v2: c.0,
+                            uv_indexes: match (a.1, b.1, c.1) {
+                                (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                                _ => None,
+                            },
+                            normal_indexes: match (a.2, b.2, c.2) {
+                                (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                                _ => None,
+                            },
+                            fan: k > 1,
+                        });
+                    }
+                }
+                "o" | "g" => {
+                    let name = if split_line.len() > 1 { split_line[1..].join(" ") } else { DEFAULT_GROUP.to_string() };
+                    let material = data.groups[current_group].material.clone();
+                    current_group = data.find_or_add_group(name, material);
+                }
+                "usemtl" => {
+                    if split_line.len() < 2 {
+                        return Err(error("usemtl needs a material name".to_string()));
+                    }
+                    let name = data.groups[current_group].name.clone();
+                    current_group = data.find_or_add_group(name, Some(split_line[1..].join(" ")));
+                }
+                "mtllib" => {
+                    // 1行に複数のファイルを指定できる
+                    for file in &split_line[1..] {
+                        data.material_libraries.push((resolve_path(path, file), line_number));
+                    }
+                }
+                // s（スムージンググループ）, l などは読み飛ばす
+                _ => {}
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+impl MeshData {
+    fn find_or_add_group(&mut self, name: String, material: Option<String>) -> usize {
+        match self.groups.iter().position(|g| g.name == name && g.material == material) {
+            Some(index) => index,
+            None => {
+                self.groups.push(ObjGroup { name, material, faces: vec![] });
+                self.groups.len() - 1
+            }
+        }
+    }
+
+    // 同じ名前のマテリアルが複数のファイルにある場合は、後に読み込んだ方を使う
+    fn load_material_libraries(&self, path: &str) -> Result<HashMap<String, Material>, MeshLoadError> {
+        let mut materials = HashMap::new();
+        for &(ref library, line) in &self.material_libraries {
+            if !Path::new(library).is_file() {
+                return Err(MeshLoadError::Parse {
+                    path: path.to_string(),
+                    line,
+                    message: format!("mtllib not found: \"{}\"", library),
+                });
+            }
+            materials.extend(MtlLoader::load(library)?);
+        }
+        Ok(materials)
+    }
+
+    // "v", "v/vt", "v//vn", "v/vt/vn" の形式をパースして 0 origin のインデックスを返す
+    fn parse_corner(&self, token: &str) -> Result<(usize, Option<usize>, Option<usize>), String> {
+        let indexes: Vec<&str> = token.split('/').collect();
+        if indexes.len() > 3 {
+            return Err(format!("invalid face vertex: \"{}\"", token));
+        }
+
+        let v = resolve_index(indexes[0], self.vertexes.len(), token)?;
+        let vt = match indexes.get(1) {
+            Some(s) if !s.is_empty() => Some(resolve_index(s, self.uvs.len(), token)?),
+            _ => None,
+        };
+        let vn = match indexes.get(2) {
+            Some(s) if !s.is_empty() => Some(resolve_index(s, self.normals.len(), token)?),
+            _ => None,
+        };
+        Ok((v, vt, vn))
+    }
+
+    // 使われている頂点だけを詰めてメッシュを作る
+    fn build_mesh(&self, faces: &[&Face], path: &str, group: Option<String>, usemtl: Option<String>, matrix: Matrix44, material: Material) -> Mesh {
+        let normal_matrix = matrix.inverse().transpose();
+
         let mut mesh = Mesh {
             vertexes: vec![],
+            normals: vec![],
+            uvs: vec![],
+            colors: vec![],
             faces: vec![],
             material: material,
+            source: Some(MeshSource {
+                path: path.to_string(),
+                matrix: matrix,
+                group: group,
+                merge_groups: false,
+                usemtl: usemtl,
+                import_mtl: false,
+                subdivision: None,
+                displacement: None,
+            }),
+            smooth: false,
+            project_uvs: false,
+        };
+
+        let mut vertex_map = HashMap::new();
+        let mut normal_map = HashMap::new();
+        let mut uv_map = HashMap::new();
+
+        for face in faces {
+            let mut remap_vertex = |i: usize| *vertex_map.entry(i).or_insert_with(|| {
+                mesh.vertexes.push(matrix * self.vertexes[i]);
+                if !self.colors.is_empty() {
+                    mesh.colors.push(self.colors[i]);
+                }
+                mesh.vertexes.len() - 1
+            });
+            let (v0, v1, v2) = (remap_vertex(face.v0), remap_vertex(face.v1), remap_vertex(face.v2));
+
+            let normal_indexes = face.normal_indexes.map(|indexes| {
+                let mut remap_normal = |i: usize| *normal_map.entry(i).or_insert_with(|| {
+                    mesh.normals.push(normal_matrix.transform_direction(&self.normals[i]).normalize());
+                    mesh.normals.len() - 1
+                });
+                [remap_normal(indexes[0]), remap_normal(indexes[1]), remap_normal(indexes[2])]
+            });
+
+            let uv_indexes = face.uv_indexes.map(|indexes| {
+                let mut remap_uv = |i: usize| *uv_map.entry(i).or_insert_with(|| {
+                    mesh.uvs.push(self.uvs[i]);
+                    mesh.uvs.len() - 1
+                });
+                [remap_uv(indexes[0]), remap_uv(indexes[1]), remap_uv(indexes[2])]
+            });
+
+            mesh.faces.push(Face { v0, v1, v2, normal_indexes, uv_indexes, fan: face.fan });
+        }
+
+        mesh
+    }
+}
+
+// usemtl が無い面には default_material を使う
+fn find_material(materials: &HashMap<String, Material>, path: &str, usemtl: Option<&String>, default_material: &Material) -> Result<Material, MeshLoadError> {
+    match usemtl {
+        Some(name) => materials.get(name).cloned().ok_or_else(|| MeshLoadError::MaterialNotFound {
+            path: path.to_string(),
+            name: name.clone(),
+        }),
+        None => Ok(default_material.clone()),
+    }
+}
+
+// MTL ファイルの1つのマテリアル（newmtl）の記述
+struct MtlMaterial {
+    kd: Color,
+    ks: Color,
+    ke: Color,
+    ns: f64,
+    ni: Option<f64>,
+    d: f64,
+    illum: u32,
+    map_kd: Option<String>,
+    map_ke: Option<String>,
+    map_ns: Option<String>,
+}
+
+impl MtlMaterial {
+    fn new() -> MtlMaterial {
+        MtlMaterial {
+            kd: Color::from_one(0.8),
+            ks: Color::zero(),
+            ke: Color::zero(),
+            ns: 0.0,
+            ni: None,
+            d: 1.0,
+            illum: 2,
+            map_kd: None,
+            map_ke: None,
+            map_ns: None,
+        }
+    }
+
+    // MTL の Phong ベースのパラメーターを SurfaceType に対応付ける
+    // - illum 4, 6, 7, 9 または d < 1 : 透過（Ni を屈折率とする）
+    // - illum 3, 5 : 鏡面反射
+    // - illum 2 で Ks が 0 でない : GGX（Ks の平均を f0 とする）
+    // - それ以外 : 完全拡散反射
+    fn to_material(&self) -> Material {
+        // Blinn-Phong の指数 Ns から Beckmann 分布の alpha に変換したものを roughness とする
+        let roughness = (2.0 / (self.ns.max(0.0) + 2.0)).sqrt();
+        let f0 = (self.ks.x + self.ks.y + self.ks.z) / 3.0;
+        let refractive_index = self.ni.unwrap_or(1.5);
+
+        let surface = match self.illum {
+            4 | 6 | 7 | 9 => SurfaceType::GGXRefraction { refractive_index },
+            _ if self.d < 1.0 => SurfaceType::GGXRefraction { refractive_index },
+            2 | 3 | 5 if f0 > 0.0 => SurfaceType::GGX { f0 },
+            _ => SurfaceType::Diffuse,
         };
 
-        let f = File::open(path).unwrap();
+        Material {
+            surface,
+            albedo: match self.map_kd {
+                Some(ref path) => Texture::new(path, self.kd),
+                None => Texture::from_color(self.kd),
+            },
+            emission: match self.map_ke {
+                Some(ref path) => Texture::new(path, self.ke),
+                None => Texture::from_color(self.ke),
+            },
+            // Blender の exporter に合わせて map_Ns は roughness のテクスチャとして扱う
+            roughness: match self.map_ns {
+                Some(ref path) => Texture::from_path(path),
+                None => Texture::from_color(Color::from_one(roughness)),
+            },
+        }
+    }
+}
+
+pub struct MtlLoader;
+
+impl MtlLoader {
+    // Ka, Tf などの未対応の記述は読み飛ばす
+    pub fn load(path: &str) -> Result<HashMap<String, Material>, MeshLoadError> {
+        let f = File::open(path).map_err(|error| MeshLoadError::Io { path: path.to_string(), error })?;
         let file = BufReader::new(&f);
-        for (_, line) in file.lines().enumerate() {
-            let l = line.unwrap();
-            let split_line: Vec<&str> = l.split(" ").collect();
+
+        let mut materials = vec![];
+        for (i, line) in file.lines().enumerate() {
+            let line_number = i + 1;
+            let error = |message: String| MeshLoadError::Parse { path: path.to_string(), line: line_number, message };
+
+            let l = line.map_err(|error| MeshLoadError::Io { path: path.to_string(), error })?;
+            let l = match l.find('#') {
+                Some(pos) => &l[..pos],
+                None => &l[..],
+            };
+            let split_line: Vec<&str> = l.split_whitespace().collect();
+            if split_line.is_empty() {
+                continue;
+            }
+
+            if split_line[0] == "newmtl" {
+                if split_line.len() < 2 {
+                    return Err(error("newmtl needs a material name".to_string()));
+                }
+                materials.push((split_line[1..].join(" "), MtlMaterial::new()));
+                continue;
+            }
+
+            let material = match materials.last_mut() {
+                Some(&mut (_, ref mut material)) => material,
+                None => return Err(error(format!("\"{}\" before newmtl", split_line[0]))),
+            };
             match split_line[0] {
-                "v" => {
-                    let local_vertex = Vector3::new(
-                        split_line[1].parse::<f64>().unwrap(),
-                        split_line[2].parse::<f64>().unwrap(),
-                        split_line[3].parse::<f64>().unwrap(),
-                    );
-                    let world_vertex = matrix * local_vertex;
-                    mesh.vertexes.push(world_vertex);
+                "Kd" => material.kd = parse_color(&split_line[1..]).map_err(&error)?,
+                "Ks" => material.ks = parse_color(&split_line[1..]).map_err(&error)?,
+                "Ke" => material.ke = parse_color(&split_line[1..]).map_err(&error)?,
+                "Ns" => material.ns = parse_floats(&split_line[1..], 1, 1).map_err(&error)?[0],
+                "Ni" => material.ni = Some(parse_floats(&split_line[1..], 1, 1).map_err(&error)?[0]),
+                "d" => material.d = parse_floats(&split_line[1..], 1, 1).map_err(&error)?[0],
+                "Tr" => material.d = 1.0 - parse_floats(&split_line[1..], 1, 1).map_err(&error)?[0],
+                "illum" => {
+                    material.illum = u32::from_str(split_line.get(1).unwrap_or(&""))
+                        .map_err(|_| error(format!("invalid illum: \"{}\"", l.trim())))?;
                 }
-                "f" => {
-                    let v1: Vec<&str> = split_line[1].split("/").collect();
-                    let v2: Vec<&str> = split_line[2].split("/").collect();
-                    let v3: Vec<&str> = split_line[3].split("/").collect();
-                    mesh.faces.push(Face {
-                        v0: v1[0].parse::<usize>().unwrap() - 1,
-                        v1: v2[0].parse::<usize>().unwrap() - 1,
-                        v2: v3[0].parse::<usize>().unwrap() - 1,
+                "map_Kd" => material.map_kd = Some(parse_map(path, &split_line[1..]).map_err(&error)?),
+                "map_Ke" => material.map_ke = Some(parse_map(path, &split_line[1..]).map_err(&error)?),
+                "map_Ns" => material.map_ns = Some(parse_map(path, &split_line[1..]).map_err(&error)?),
+                _ => {}
+            }
+        }
+
+        Ok(materials.into_iter().map(|(name, material)| (name, material.to_material())).collect())
+    }
+}
+
+// PLY の数値の型
+#[derive(Clone, Copy)]
+enum PlyType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl PlyType {
+    fn from_str(s: &str) -> Option<PlyType> {
+        match s {
+            "char" | "int8" => Some(PlyType::Int8),
+            "uchar" | "uint8" => Some(PlyType::UInt8),
+            "short" | "int16" => Some(PlyType::Int16),
+            "ushort" | "uint16" => Some(PlyType::UInt16),
+            "int" | "int32" => Some(PlyType::Int32),
+            "uint" | "uint32" => Some(PlyType::UInt32),
+            "float" | "float32" => Some(PlyType::Float32),
+            "double" | "float64" => Some(PlyType::Float64),
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            PlyType::Int8 | PlyType::UInt8 => 1,
+            PlyType::Int16 | PlyType::UInt16 => 2,
+            PlyType::Int32 | PlyType::UInt32 | PlyType::Float32 => 4,
+            PlyType::Float64 => 8,
+        }
+    }
+
+    fn read(&self, bytes: &[u8], big_endian: bool) -> f64 {
+        let mut buf = [0u8; 8];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        if big_endian {
+            buf[..bytes.len()].reverse();
+        }
+        match *self {
+            PlyType::Int8 => buf[0] as i8 as f64,
+            PlyType::UInt8 => buf[0] as f64,
+            PlyType::Int16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+            PlyType::UInt16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+            PlyType::Int32 => i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            PlyType::UInt32 => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            PlyType::Float32 => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+            PlyType::Float64 => f64::from_le_bytes(buf),
+        }
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    value_type: PlyType,
+
+    // list の場合は要素数の型
+    count_type: Option<PlyType>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+impl PlyElement {
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        self.properties.iter().position(|p| names.contains(&p.name.as_str()))
+    }
+}
+
+#[derive(PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+// ヘッダーより後のデータを要素ごとに読み出す
+struct PlyReader<'a> {
+    path: &'a str,
+    format: PlyFormat,
+    data: &'a [u8],
+    position: usize,
+
+    // ASCII の場合の行
+    lines: Vec<(usize, &'a str)>,
+    line_index: usize,
+}
+
+impl<'a> PlyReader<'a> {
+    // 1つの要素のプロパティの値を読む（list でないプロパティは値が1つ）
+    fn read_element(&mut self, element: &PlyElement) -> Result<Vec<Vec<f64>>, MeshLoadError> {
+        if self.format == PlyFormat::Ascii {
+            let (line_number, line) = match self.lines.get(self.line_index) {
+                Some(line) => *line,
+                None => return Err(self.invalid(format!("unexpected end of file in element \"{}\"", element.name))),
+            };
+            self.line_index += 1;
+
+            let error = |message: String| MeshLoadError::Parse { path: self.path.to_string(), line: line_number, message };
+            let mut tokens = line.split_whitespace();
+            let mut next = || -> Result<f64, MeshLoadError> {
+                let token = tokens.next().ok_or_else(|| error("too few values".to_string()))?;
+                f64::from_str(token).map_err(|_| error(format!("invalid number: \"{}\"", token)))
+            };
+
+            let mut values = vec![];
+            for property in &element.properties {
+                let count = if property.count_type.is_some() { next()? as usize } else { 1 };
+                let mut list = vec![];
+                for _ in 0..count {
+                    list.push(next()?);
+                }
+                values.push(list);
+            }
+            Ok(values)
+        } else {
+            let mut values = vec![];
+            for property in &element.properties {
+                let count = match property.count_type {
+                    Some(count_type) => self.read_binary(count_type)? as usize,
+                    None => 1,
+                };
+                let mut list = vec![];
+                for _ in 0..count {
+                    list.push(self.read_binary(property.value_type)?);
+                }
+                values.push(list);
+            }
+            Ok(values)
+        }
+    }
+
+    fn read_binary(&mut self, value_type: PlyType) -> Result<f64, MeshLoadError> {
+        let size = value_type.size();
+        if self.position + size > self.data.len() {
+            return Err(self.invalid("unexpected end of file".to_string()));
+        }
+        let value = value_type.read(&self.data[self.position..self.position + size], self.format == PlyFormat::BinaryBigEndian);
+        self.position += size;
+        Ok(value)
+    }
+
+    fn invalid(&self, message: String) -> MeshLoadError {
+        MeshLoadError::InvalidData { path: self.path.to_string(), message }
+    }
+}
+
+pub struct PlyLoader;
+
+impl PlyLoader {
+    // ASCII とバイナリ（リトルエンディアン、ビッグエンディアン）の PLY を読み込む
+    // vertex の x, y, z, nx, ny, nz, red, green, blue, u, v（s, t）と face の vertex_indices を使い、それ以外の要素は読み飛ばす
+    pub fn load(path: &str, matrix: Matrix44, material: Material) -> Result<Mesh, MeshLoadError> {
+        let bytes = read_file(path)?;
+        let data = PlyLoader::parse(path, &bytes)?;
+        let faces: Vec<&Face> = data.groups.iter().flat_map(|g| g.faces.iter()).collect();
+        Ok(data.build_mesh(&faces, path, None, None, matrix, material))
+    }
+
+    fn parse(path: &str, bytes: &[u8]) -> Result<MeshData, MeshLoadError> {
+        // ヘッダーは ASCII で、end_header の行で終わる
+        let mut format = None;
+        let mut elements: Vec<PlyElement> = vec![];
+        let mut position = 0;
+        let mut line_number = 0;
+        loop {
+            let end = match bytes[position..].iter().position(|b| *b == b'\n') {
+                Some(end) => position + end,
+                None => return Err(MeshLoadError::InvalidData { path: path.to_string(), message: "end_header not found".to_string() }),
+            };
+            let line = String::from_utf8_lossy(&bytes[position..end]).into_owned();
+            position = end + 1;
+            line_number += 1;
+
+            let error = |message: String| MeshLoadError::Parse { path: path.to_string(), line: line_number, message };
+            let split_line: Vec<&str> = line.split_whitespace().collect();
+            if line_number == 1 {
+                if split_line != ["ply"] {
+                    return Err(error("not a PLY file".to_string()));
+                }
+                continue;
+            }
+            if split_line.is_empty() {
+                continue;
+            }
+
+            match split_line[0] {
+                "format" => {
+                    format = Some(match split_line.get(1) {
+                        Some(&"ascii") => PlyFormat::Ascii,
+                        Some(&"binary_little_endian") => PlyFormat::BinaryLittleEndian,
+                        Some(&"binary_big_endian") => PlyFormat::BinaryBigEndian,
+                        _ => return Err(error(format!("unsupported format: \"{}\"", line.trim()))),
                     });
+                }
+                "element" => {
+                    if split_line.len() != 3 {
+                        return Err(error(format!("invalid element: \"{}\"", line.trim())));
+                    }
+                    let count = usize::from_str(split_line[2]).map_err(|_| error(format!("invalid element count: \"{}\"", split_line[2])))?;
+                    elements.push(PlyElement { name: split_line[1].to_string(), count, properties: vec![] });
+                }
+                "property" => {
+                    let element = elements.last_mut().ok_or_else(|| error("property before element".to_string()))?;
+                    let parse_type = |s: &str| PlyType::from_str(s).ok_or_else(|| error(format!("unknown type: \"{}\"", s)));
+                    let property = match split_line.len() {
+                        3 => PlyProperty { name: split_line[2].to_string(), value_type: parse_type(split_line[1])?, count_type: None },
+                        5 if split_line[1] == "list" => PlyProperty {
+                            name: split_line[4].to_string(),
+                            value_type: parse_type(split_line[3])?,
+                            count_type: Some(parse_type(split_line[2])?),
+                        },
+                        _ => return Err(error(format!("invalid property: \"{}\"", line.trim()))),
+                    };
+                    element.properties.push(property);
+                }
+                "end_header" => break,
+                // comment, obj_info
+                _ => {}
+            }
+        }
 
-                    // 四角形ポリゴンの場合の処理
-                    if split_line.len() == 5 {
-                        let v4: Vec<&str> = split_line[4].split("/").collect();
-                        mesh.faces.push(Face {
-                            v0: v1[0].parse::<usize>().unwrap() - 1,
-                            v1: v3[0].parse::<usize>().unwrap() - 1,
-                            v2: v4[0].parse::<usize>().unwrap() - 1,
-                        });
+        let format = format.ok_or_else(|| MeshLoadError::InvalidData { path: path.to_string(), message: "format not found".to_string() })?;
+        let body = &bytes[position..];
+        let lines = if format == PlyFormat::Ascii {
+            let text = ::std::str::from_utf8(body).map_err(|_| MeshLoadError::InvalidData { path: path.to_string(), message: "invalid UTF-8".to_string() })?;
+            text.lines().enumerate()
+                .map(|(i, line)| (line_number + i + 1, line))
+                .filter(|&(_, line)| !line.trim().is_empty())
+                .collect()
+        } else {
+            vec![]
+        };
+        let mut reader = PlyReader { path, format, data: body, position: 0, lines, line_index: 0 };
+
+        let mut data = MeshData {
+            vertexes: vec![],
+            normals: vec![],
+            uvs: vec![],
+            colors: vec![],
+            groups: vec![ObjGroup { name: DEFAULT_GROUP.to_string(), material: None, faces: vec![] }],
+            material_libraries: vec![],
+        };
+        let mut has_normals = false;
+        let mut has_uvs = false;
+
+        for element in &elements {
+            match element.name.as_str() {
+                "vertex" => {
+                    let position = match (element.find(&["x"]), element.find(&["y"]), element.find(&["z"])) {
+                        (Some(x), Some(y), Some(z)) => [x, y, z],
+                        _ => return Err(reader.invalid("vertex needs x, y and z".to_string())),
+                    };
+                    let normal = match (element.find(&["nx"]), element.find(&["ny"]), element.find(&["nz"])) {
+                        (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+                        _ => None,
+                    };
+                    let color = match (element.find(&["red", "diffuse_red"]), element.find(&["green", "diffuse_green"]), element.find(&["blue", "diffuse_blue"])) {
+                        (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+                        _ => None,
+                    };
+                    let uv = match (element.find(&["u", "s", "texture_u", "texture_s"]), element.find(&["v", "t", "texture_v", "texture_t"])) {
+                        (Some(u), Some(v)) => Some([u, v]),
+                        _ => None,
+                    };
+                    has_normals = normal.is_some();
+                    has_uvs = uv.is_some();
+
+                    for _ in 0..element.count {
+                        let values = reader.read_element(element)?;
+                        let value = |i: usize| values[i].get(0).cloned().unwrap_or(0.0);
+                        data.vertexes.push(Vector3::new(value(position[0]), value(position[1]), value(position[2])));
+                        if let Some(n) = normal {
+                            data.normals.push(Vector3::new(value(n[0]), value(n[1]), value(n[2])));
+                        }
+                        if let Some(uv) = uv {
+                            data.uvs.push(Vector2::new(value(uv[0]), value(uv[1])));
+                        }
+                        if let Some(c) = color {
+                            // 整数の場合は 0-255 の範囲とする
+                            let scale = |i: usize| match element.properties[i].value_type {
+                                PlyType::Float32 | PlyType::Float64 => value(i),
+                                _ => value(i) / 255.0,
+                            };
+                            data.colors.push(gamma_to_linear(Color::new(scale(c[0]), scale(c[1]), scale(c[2]))));
+                        }
+                    }
+                }
+                "face" => {
+                    let indices = element.find(&["vertex_indices", "vertex_index"])
+                        .ok_or_else(|| reader.invalid("face needs vertex_indices".to_string()))?;
+                    for i in 0..element.count {
+                        let values = reader.read_element(element)?;
+                        let corners = &values[indices];
+                        if corners.len() < 3 || corners.iter().any(|c| *c < 0.0 || *c as usize >= data.vertexes.len()) {
+                            return Err(reader.invalid(format!("invalid vertex indices in face {}: {:?}", i, corners)));
+                        }
+
+                        // 多角形は扇状に三角形分割する
+                        for k in 1..(corners.len() - 1) {
+                            let (v0, v1, v2) = (corners[0] as usize, corners[k] as usize, corners[k + 1] as usize);
+                            let mut face = Face::new(v0, v1, v2);
+                            if has_normals {
+                                face.normal_indexes = Some([v0, v1, v2]);
+                            }
+                            if has_uvs {
+                                face.uv_indexes = Some([v0, v1, v2]);
+                            }
+                            face.fan = k > 1;
+                            data.groups[0].faces.push(face);
+                        }
+                    }
+                }
+                _ => {
+                    for _ in 0..element.count {
+                        reader.read_element(element)?;
+                    }
+                }
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+pub struct StlLoader;
+
+impl StlLoader {
+    // ASCII とバイナリの STL を読み込む
+    // 頂点を共有していないので、同じ座標の頂点をまとめてからスムーズシェーディングできるようにする
+    pub fn load(path: &str, matrix: Matrix44, material: Material) -> Result<Mesh, MeshLoadError> {
+        let bytes = read_file(path)?;
+
+        // "solid" で始まるバイナリの STL もあるので、ファイルサイズが面の数と一致するかで判定する
+        let is_binary = bytes.len() >= 84 && {
+            let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+            bytes.len() == 84 + count * 50
+        };
+        let triangles = if is_binary {
+            StlLoader::parse_binary(&bytes)
+        } else if bytes.starts_with(b"solid") {
+            StlLoader::parse_ascii(path, &bytes)?
+        } else {
+            return Err(MeshLoadError::InvalidData { path: path.to_string(), message: "not a STL file".to_string() });
+        };
+
+        let mut data = MeshData {
+            vertexes: vec![],
+            normals: vec![],
+            uvs: vec![],
+            colors: vec![],
+            groups: vec![ObjGroup { name: DEFAULT_GROUP.to_string(), material: None, faces: vec![] }],
+            material_libraries: vec![],
+        };
+        let mut vertex_map = HashMap::new();
+        for triangle in &triangles {
+            let mut indexes = [0; 3];
+            for k in 0..3 {
+                let v = triangle[k];
+                indexes[k] = *vertex_map.entry([v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]).or_insert_with(|| {
+                    data.vertexes.push(v);
+                    data.vertexes.len() - 1
+                });
+            }
+            data.groups[0].faces.push(Face::new(indexes[0], indexes[1], indexes[2]));
+        }
+
+        let faces: Vec<&Face> = data.groups[0].faces.iter().collect();
+        Ok(data.build_mesh(&faces, path, None, None, matrix, material))
+    }
+
+    // 80 byte のヘッダー、面の数、面ごとに法線と3頂点（float）と 2 byte の属性
+    fn parse_binary(bytes: &[u8]) -> Vec<[Vector3; 3]> {
+        let count = (bytes.len() - 84) / 50;
+        let read = |offset: usize| -> Vector3 {
+            let f = |i: usize| f32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]) as f64;
+            Vector3::new(f(offset), f(offset + 4), f(offset + 8))
+        };
+        (0..count)
+            .map(|i| {
+                let offset = 84 + i * 50 + 12;// 法線は読み飛ばす
+                [read(offset), read(offset + 12), read(offset + 24)]
+            })
+            .collect()
+    }
+
+    fn parse_ascii(path: &str, bytes: &[u8]) -> Result<Vec<[Vector3; 3]>, MeshLoadError> {
+        let text = String::from_utf8_lossy(bytes);
+        let mut triangles = vec![];
+        let mut vertexes = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| MeshLoadError::Parse { path: path.to_string(), line: i + 1, message };
+            let split_line: Vec<&str> = line.split_whitespace().collect();
+            match split_line.get(0) {
+                Some(&"vertex") => {
+                    let v = parse_floats(&split_line[1..], 3, 3).map_err(&error)?;
+                    vertexes.push(Vector3::new(v[0], v[1], v[2]));
+                }
+                Some(&"endloop") => {
+                    if vertexes.len() != 3 {
+                        return Err(error(format!("facet needs 3 vertexes, found {}", vertexes.len())));
                     }
+                    triangles.push([vertexes[0], vertexes[1], vertexes[2]]);
+                    vertexes.clear();
                 }
+                // solid, facet normal, outer loop, endfacet, endsolid
                 _ => {}
             }
         }
+        Ok(triangles)
+    }
+}
 
-        mesh
+fn read_file(path: &str) -> Result<Vec<u8>, MeshLoadError> {
+    let mut bytes = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut bytes))
+        .map_err(|error| MeshLoadError::Io { path: path.to_string(), error })?;
+    Ok(bytes)
+}
+
+// 相対パスは参照元のファイルがあるディレクトリを基準にする
+fn resolve_path(base: &str, file: &str) -> String {
+    match Path::new(base).parent() {
+        Some(dir) => dir.join(file).to_string_lossy().into_owned(),
+        None => file.to_string(),
+    }
+}
+
+// "Kd r g b"（g, b は省略可能）
+fn parse_color(values: &[&str]) -> Result<Color, String> {
+    let v = parse_floats(values, 1, 3)?;
+    Ok(if v.len() == 3 { Color::new(v[0], v[1], v[2]) } else { Color::from_one(v[0]) })
+}
+
+// -bm などのオプションは読み飛ばして、最後の要素をファイル名とする
+fn parse_map(base: &str, values: &[&str]) -> Result<String, String> {
+    let file = values.last().ok_or_else(|| "texture map needs a file name".to_string())?;
+    let path = resolve_path(base, file);
+    if Path::new(&path).is_file() {
+        Ok(path)
+    } else {
+        Err(format!("file not found: \"{}\"", path))
+    }
+}
+
+// 負のインデックスは末尾からの相対指定
+fn resolve_index(s: &str, len: usize, token: &str) -> Result<usize, String> {
+    let index = i64::from_str(s).map_err(|_| format!("invalid index: \"{}\"", token))?;
+    let resolved = if index > 0 {
+        index - 1
+    } else if index < 0 {
+        len as i64 + index
+    } else {
+        return Err(format!("index must not be 0: \"{}\"", token));
+    };
+
+    if resolved < 0 || resolved >= len as i64 {
+        Err(format!("index out of range: \"{}\"", token))
+    } else {
+        Ok(resolved as usize)
+    }
+}
+
+fn parse_floats(values: &[&str], min: usize, max: usize) -> Result<Vec<f64>, String> {
+    if values.len() < min {
+        return Err(format!("expected at least {} values, found {}", min, values.len()));
+    }
+    values.iter().take(max)
+        .map(|s| f64::from_str(s).map_err(|_| format!("invalid number: \"{}\"", s)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // テストごとに別の一時ファイルに書き出してパースする
+    fn parse_obj(name: &str, source: &str) -> Result<MeshData, MeshLoadError> {
+        let path = env::temp_dir().join(format!("hanamaru-loader-{}.obj", name));
+        fs::write(&path, source).unwrap();
+        let result = ObjLoader::parse(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn triangles(group: &ObjGroup) -> Vec<[usize; 3]> {
+        group.faces.iter().map(|f| [f.v0, f.v1, f.v2]).collect()
+    }
+
+    fn parse_error_line(result: Result<MeshData, MeshLoadError>) -> usize {
+        match result {
+            Err(MeshLoadError::Parse { line, .. }) => line,
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("expected a parse error"),
+        }
+    }
+
+    const QUAD: &str = "
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vn 0 0 1
+";
+
+    #[test]
+    fn negative_indices_are_relative_to_the_end() {
+        let data = parse_obj("negative", &format!("{}f -4/-3/-1 -3/-2/-1 -2/-1/-1\n", QUAD)).unwrap();
+        let face = &data.groups[0].faces[0];
+        assert_eq!([face.v0, face.v1, face.v2], [0, 1, 2]);
+        assert_eq!(face.uv_indexes, Some([0, 1, 2]));
+        assert_eq!(face.normal_indexes, Some([0, 0, 0]));
+    }
+
+    #[test]
+    fn negative_indices_resolve_against_vertexes_read_so_far() {
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 1 1 0\nf -3 -2 -1\n";
+        let data = parse_obj("negative-interleaved", source).unwrap();
+        assert_eq!(triangles(&data.groups[0]), vec![[0, 1, 2], [1, 2, 3]]);
+    }
+
+    #[test]
+    fn polygons_are_split_into_a_fan() {
+        let source = "v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\nf 1 2 3 4 5\n";
+        let data = parse_obj("ngon", source).unwrap();
+        let group = &data.groups[0];
+        assert_eq!(triangles(group), vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+        assert_eq!(group.faces.iter().map(|f| f.fan).collect::<Vec<_>>(), vec![false, true, true]);
+    }
+
+    #[test]
+    fn corners_without_uv_or_normal() {
+        let data = parse_obj("corners", &format!("{}f 1//1 2//1 3//1\nf 1/1 3/3 4/2\nf 1 3 4\n", QUAD)).unwrap();
+        let faces = &data.groups[0].faces;
+        assert_eq!((faces[0].uv_indexes, faces[0].normal_indexes), (None, Some([0, 0, 0])));
+        assert_eq!((faces[1].uv_indexes, faces[1].normal_indexes), (Some([0, 2, 1]), None));
+        assert_eq!((faces[2].uv_indexes, faces[2].normal_indexes), (None, None));
+    }
+
+    #[test]
+    fn groups_and_materials_split_faces() {
+        let source = format!("{}f 1 2 3\no a\nusemtl red\nf 1 3 4\ng b\nf 1 2 4\ng a\nf 2 3 4\n", QUAD);
+        let data = parse_obj("groups", &source).unwrap();
+        let groups: Vec<_> = data.groups.iter()
+            .map(|g| (g.name.as_str(), g.material.as_ref().map(|m| m.as_str()), g.faces.len()))
+            .collect();
+        assert_eq!(groups, vec![
+            ("default", None, 1),
+            ("a", None, 0),
+            ("a", Some("red"), 2),
+            ("b", Some("red"), 1),
+        ]);
+    }
+
+    #[test]
+    fn invalid_faces_report_the_line() {
+        assert_eq!(parse_error_line(parse_obj("zero", &format!("{}f 0 1 2\n", QUAD))), 10);
+        assert_eq!(parse_error_line(parse_obj("out-of-range", &format!("{}f 1 2 5\n", QUAD))), 10);
+        assert_eq!(parse_error_line(parse_obj("negative-out-of-range", &format!("{}f -5 1 2\n", QUAD))), 10);
+        assert_eq!(parse_error_line(parse_obj("two-vertexes", &format!("{}\nf 1 2\n", QUAD))), 11);
+        assert_eq!(parse_error_line(parse_obj("bad-corner", &format!("{}f 1/1/1/1 2 3\n", QUAD))), 10);
+    }
+
+    #[test]
+    fn specular_illum_models_take_f0_from_ks() {
+        let path = env::temp_dir().join("hanamaru-loader-illum.mtl");
+        fs::write(&path, "
+newmtl phong
+illum 2
+Ks 0.2 0.4 0.6
+newmtl mirror
+illum 3
+Ks 0.5 0.5 0.5
+newmtl fresnel
+illum 5
+Ks 1 1 1
+newmtl matte
+illum 3
+Ks 0 0 0
+").unwrap();
+        let materials = MtlLoader::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(materials["phong"].surface, SurfaceType::GGX { f0: (0.2 + 0.4 + 0.6) / 3.0 });
+        assert_eq!(materials["mirror"].surface, SurfaceType::GGX { f0: 0.5 });
+        assert_eq!(materials["fresnel"].surface, SurfaceType::GGX { f0: 1.0 });
+        assert_eq!(materials["matte"].surface, SurfaceType::Diffuse);
     }
 }
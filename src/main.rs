@@ -4,6 +4,10 @@ extern crate time;
 extern crate rand;
 extern crate rayon;
 extern crate getopts;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 
 use image::GenericImage;
 use std::fs::File;
@@ -14,6 +18,7 @@ use num::Float;
 use self::rand::{Rng, SeedableRng, StdRng};
 use getopts::Options;
 use std::env;
+use std::process;
 
 mod config;
 mod vector;
@@ -26,9 +31,20 @@ mod color;
 mod texture;
 mod math;
 mod loader;
+mod gltf_loader;
 mod bvh;
+mod sdf;
+mod csg;
+mod heightfield;
+mod subdivision;
+mod displacement;
 mod tonemap;
 mod filter;
+mod description;
+mod scene_loader;
+mod scene_writer;
+#[cfg(test)]
+mod furnace;
 
 use vector::Vector3;
 use matrix::Matrix44;
@@ -43,6 +59,8 @@ use renderer::{Renderer, DebugRenderer, DebugRenderMode, PathTracingRenderer};
 
 use color::{Color, hsv_to_rgb};
 use loader::ObjLoader;
+use scene_loader::SceneLoader;
+use scene_writer::SceneWriter;
 
 fn tee(f: &mut BufWriter<File>, message: &String) {
     println!("{}", message);
@@ -50,7 +68,6 @@ fn tee(f: &mut BufWriter<File>, message: &String) {
     let _ = f.write(b"\n");
 }
 
-#[allow(dead_code)]
 fn init_scene_simple() -> (Camera, Scene) {
     let camera = Camera::new(
         Vector3::new(0.0, 2.0, 9.0), // eye
@@ -135,7 +152,6 @@ fn init_scene_simple() -> (Camera, Scene) {
     (camera, scene)
 }
 
-#[allow(dead_code)]
 fn init_scene_material_examples() -> (Camera, Scene) {
     let camera = Camera::new(
         Vector3::new(0.0, 2.0, 9.0), // eye
@@ -248,7 +264,6 @@ fn init_scene_material_examples() -> (Camera, Scene) {
     (camera, scene)
 }
 
-#[allow(dead_code)]
 fn init_scene_rtcamp5() -> (Camera, Scene) {
     let seed: &[_] = &[870, 2000, 304, 2];
     let mut rng: StdRng = SeedableRng::from_seed(seed);
@@ -276,7 +291,7 @@ fn init_scene_rtcamp5() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::from_color(Color::from_one(0.1)),
                 },
-            ))),
+            ).unwrap())),
             // うさぎ左
             Box::new(BvhMesh::from_mesh(ObjLoader::load(
                 "models/bunny/bunny_face1000_flip.obj",
@@ -287,7 +302,7 @@ fn init_scene_rtcamp5() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::from_color(Color::from_one(0.1)),
                 },
-            ))),
+            ).unwrap())),
             // 背後にある地図ガラス
             /*Box::new(Cuboid {
                 aabb: Aabb {
@@ -312,7 +327,7 @@ fn init_scene_rtcamp5() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::black(),
                 },
-            ))),
+            ).unwrap())),
             // 地球のテクスチャを光源にした球体
             Box::new(Sphere {
                 center: Vector3::new(0.0, 0.5, -0.5),
@@ -466,7 +481,7 @@ fn init_scene_rtcamp5() -> (Camera, Scene) {
                 emission: Texture::black(),
                 roughness: Texture::black(),
             },
-        )))) {
+        ).unwrap()))) {
             count += 1;
         }
     }
@@ -490,7 +505,7 @@ fn init_scene_rtcamp5() -> (Camera, Scene) {
                 emission: Texture::black(),
                 roughness: Texture::black(),
             },
-        )))) {
+        ).unwrap()))) {
             count += 1;
         }
     }
@@ -498,7 +513,6 @@ fn init_scene_rtcamp5() -> (Camera, Scene) {
     (camera, scene)
 }
 
-#[allow(dead_code)]
 fn init_scene_tbf3() -> (Camera, Scene) {
     let seed: &[_] = &[870, 2000, 304, 1];
     let mut rng: StdRng = SeedableRng::from_seed(seed);
@@ -533,7 +547,7 @@ fn init_scene_tbf3() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::from_color(Color::from_one(0.05)),
                 },
-            ))),
+            ).unwrap())),
             // 背後にある地図ガラス
             /*Box::new(Cuboid {
                 aabb: Aabb {
@@ -557,7 +571,7 @@ fn init_scene_tbf3() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::black(),
                 },
-            ))),
+            ).unwrap())),
 
             // 固定のダイヤモンド（中央）
             Box::new(BvhMesh::from_mesh(ObjLoader::load(
@@ -569,7 +583,7 @@ fn init_scene_tbf3() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::black(),
                 },
-            ))),
+            ).unwrap())),
 
             // 光源の球体（手前）
             Box::new(Sphere {
@@ -689,7 +703,7 @@ fn init_scene_tbf3() -> (Camera, Scene) {
                 emission: Texture::black(),
                 roughness: Texture::black(),
             },
-        )))) {
+        ).unwrap()))) {
             count += 1;
         }
     }
@@ -713,7 +727,7 @@ fn init_scene_tbf3() -> (Camera, Scene) {
                 emission: Texture::black(),
                 roughness: Texture::black(),
             },
-        )))) {
+        ).unwrap()))) {
             count += 1;
         }
     }
@@ -721,7 +735,6 @@ fn init_scene_tbf3() -> (Camera, Scene) {
     (camera, scene)
 }
 
-#[allow(dead_code)]
 fn init_scene_rtcamp6_v1() -> (Camera, Scene) {
     let camera = Camera::new(
         Vector3::new(0.0, 2.0, 10.0), // eye
@@ -765,7 +778,7 @@ fn init_scene_rtcamp6_v1() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::from_color(Color::from_one(0.05)),
                 },*/
-            ))),
+            ).unwrap())),
 
             // 床
             Box::new(Cuboid {
@@ -800,7 +813,6 @@ fn init_scene_rtcamp6_v1() -> (Camera, Scene) {
     (camera, scene)
 }
 
-#[allow(dead_code)]
 fn init_scene_rtcamp6_v2() -> (Camera, Scene) {
     let seed: &[_] = &[870, 2000, 304, 2];
     let mut rng: StdRng = SeedableRng::from_seed(seed);
@@ -919,12 +931,11 @@ fn init_scene_rtcamp6_v2() -> (Camera, Scene) {
             emission: Texture::black(),
             roughness: Texture::from_color(Color::from_one(0.05)),
         },*/
-    ))));
+    ).unwrap())));
 
     (camera, scene)
 }
 
-#[allow(dead_code)]
 fn init_scene_rtcamp6_v3() -> (Camera, Scene) {
     let camera = Camera::new(
         Vector3::new(0.0, 2.0, 6.0), // eye
@@ -980,7 +991,7 @@ fn init_scene_rtcamp6_v3() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::from_color(Color::from_one(0.05)),
                 },
-            ))),
+            ).unwrap())),
 
             // 床
             Box::new(Cuboid {
@@ -1016,7 +1027,6 @@ fn init_scene_rtcamp6_v3() -> (Camera, Scene) {
     (camera, scene)
 }
 
-#[allow(dead_code)]
 fn init_scene_rtcamp6_v3_1() -> (Camera, Scene) {
     let scene_scale = 1.0;
     let theta = config::PI2 * 0.03;
@@ -1063,7 +1073,7 @@ fn init_scene_rtcamp6_v3_1() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::from_color(Color::from_one(0.05)),
                 },
-            ))),
+            ).unwrap())),
 
             // 鏡
             Box::new(BvhMesh::from_mesh(ObjLoader::load(
@@ -1075,7 +1085,7 @@ fn init_scene_rtcamp6_v3_1() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::black(),
                 },
-            ))),
+            ).unwrap())),
 
             // 額縁
             Box::new(BvhMesh::from_mesh(ObjLoader::load(
@@ -1087,7 +1097,7 @@ fn init_scene_rtcamp6_v3_1() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::from_color(Color::from_one(0.3)),
                 },
-            ))),
+            ).unwrap())),
 
             // 床
             Box::new(Cuboid {
@@ -1144,7 +1154,7 @@ fn init_scene_rtcamp6_v3_1() -> (Camera, Scene) {
                     roughness: Texture::from_color(Color::from_one(0.05 * i as f64)),
                 }
             },
-        ))));
+        ).unwrap())));
 
         i += 1;
     }
@@ -1152,7 +1162,6 @@ fn init_scene_rtcamp6_v3_1() -> (Camera, Scene) {
     (camera, scene)
 }
 
-#[allow(dead_code)]
 fn init_scene_rtcamp6_v4() -> (Camera, Scene) {
     let camera = Camera::new(
         Vector3::new(0.0, 1.0, 6.0), // eye
@@ -1183,7 +1192,7 @@ fn init_scene_rtcamp6_v4() -> (Camera, Scene) {
                     emission: Texture::black(),
                     roughness: Texture::from_color(Color::from_one(0.05)),
                 },
-            ))),
+            ).unwrap())),
 
             // camera light
             Box::new(Sphere {
@@ -1211,6 +1220,37 @@ fn init_scene_rtcamp6_v4() -> (Camera, Scene) {
     (camera, scene)
 }
 
+struct Preset {
+    name: &'static str,
+    description: &'static str,
+    init: fn() -> (Camera, Scene),
+}
+
+static PRESETS: &[Preset] = &[
+    Preset { name: "simple", description: "a diffuse sphere and two colored lights on a checkered floor", init: init_scene_simple },
+    Preset { name: "material_examples", description: "spheres showing each SurfaceType side by side", init: init_scene_material_examples },
+    Preset { name: "rtcamp5", description: "bunnies and scattered diamonds (レイトレ合宿5)", init: init_scene_rtcamp5 },
+    Preset { name: "tbf3", description: "KLab logo and scattered diamonds (技術書典3)", init: init_scene_tbf3 },
+    Preset { name: "rtcamp6_v1", description: "houdini boss mesh on a floor", init: init_scene_rtcamp6_v1 },
+    Preset { name: "rtcamp6_v2", description: "fractal dodecahedron surrounded by random spheres", init: init_scene_rtcamp6_v2 },
+    Preset { name: "rtcamp6_v3", description: "wired bunny lit by a camera light", init: init_scene_rtcamp6_v3 },
+    Preset { name: "rtcamp6_v3_1", description: "wired bunny, mirror and armadillos (レイトレ合宿6 submission)", init: init_scene_rtcamp6_v3_1 },
+    Preset { name: "rtcamp6_v4", description: "fractal icosahedron lit by a camera light", init: init_scene_rtcamp6_v4 },
+];
+
+const DEFAULT_PRESET: &str = "rtcamp6_v3_1";
+
+fn find_preset(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|p| p.name == name)
+}
+
+fn print_presets() {
+    for preset in PRESETS {
+        let default_mark = if preset.name == DEFAULT_PRESET { " (default)" } else { "" };
+        println!("{:<20} {}{}", preset.name, preset.description, default_mark);
+    }
+}
+
 fn render<R: Renderer>(renderer: &mut R, width: u32, height: u32, camera: &Camera, scene: Scene) -> u32 {
     let mut imgbuf = image::ImageBuffer::new(width, height);
     let sampled = renderer.render(&BvhScene::from_scene(scene), camera, &mut imgbuf);
@@ -1235,6 +1275,10 @@ fn main() {
     opts.optopt("s", "sampling", "sampling limit", "SAMPLING");
     opts.optopt("t", "time", "time limit sec", "TIME");
     opts.optopt("i", "interval", "report interval sec", "INTERVAL");
+    opts.optopt("", "scene", "load scene from a scene file", "PATH");
+    opts.optopt("", "preset", "use a built-in scene (see --list-presets)", "NAME");
+    opts.optflag("", "list-presets", "print the built-in scenes");
+    opts.optopt("", "export", "write the scene to a scene file and exit without rendering", "PATH");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -1244,6 +1288,23 @@ fn main() {
         print_usage(&program, opts);
         return;
     }
+    if matches.opt_present("list-presets") {
+        print_presets();
+        return;
+    }
+    if matches.opt_present("scene") && matches.opt_present("preset") {
+        eprintln!("--scene and --preset cannot be used together");
+        process::exit(1);
+    }
+    let preset_name = matches.opt_str("preset").unwrap_or(DEFAULT_PRESET.to_string());
+    let preset = match find_preset(&preset_name) {
+        Some(preset) => preset,
+        None => {
+            eprintln!("unknown preset: {}", preset_name);
+            print_presets();
+            process::exit(1);
+        }
+    };
     let debug_mode = matches.opt_present("debug");
 
     let width = matches.opt_get_default("w", 1920).unwrap();
@@ -1266,16 +1327,33 @@ fn main() {
 
         let init_scene_begin = time::now();
 
-        //let (camera, scene) = init_scene_rtcamp5();
-        //let (camera, scene) = init_scene_material_examples();
-        //let (camera, scene) = init_scene_tbf3();
-        //let (camera, scene) = init_scene_simple();
-        let (camera, scene) = init_scene_rtcamp6_v3_1();
+        let (camera, scene) = if let Some(path) = matches.opt_str("scene") {
+            tee(&mut f, &format!("scene: {}.", path));
+            match SceneLoader::load(&path) {
+                Ok(camera_scene) => camera_scene,
+                Err(e) => {
+                    eprintln!("failed to load scene: {}", e);
+                    process::exit(1);
+                }
+            }
+        } else {
+            tee(&mut f, &format!("preset: {}.", preset.name));
+            (preset.init)()
+        };
 
         let init_scene_end = time::now();
         let init_scene_sec = (init_scene_end - init_scene_begin).num_milliseconds() as f64 * 0.001;
         tee(&mut f, &format!("init scene: {:.2} sec.", init_scene_sec));
 
+        if let Some(path) = matches.opt_str("export") {
+            if let Err(e) = SceneWriter::write(&path, &camera, &scene) {
+                eprintln!("failed to export scene: {}", e);
+                process::exit(1);
+            }
+            tee(&mut f, &format!("export: {}.", path));
+            return;
+        }
+
         let sampled = if debug_mode {
             let mut debug_renderer = DebugRenderer { mode: DebugRenderMode::FocalPlane };
             render(&mut debug_renderer, width, height, &camera, scene)
@@ -1,20 +1,60 @@
 use texture::Texture;
-use color::Color;
-use vector::Vector3;
+use color::{Color, color_to_luminance};
+use vector::{Vector3, Vector2};
 use camera::Ray;
 use config;
-use math::saturate;
+use math::{saturate, mix};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum SurfaceType {
     Diffuse,
     Specular,
     Refraction { refractive_index: f64 },
     GGX { f0: f64 },
     GGXRefraction { refractive_index: f64 },
+
+    // 透明なコート層（coat）の下に base がある材質（ニスを塗った木材、車の塗装など）
+    // refractive_index から求めたフレネル反射率で coat と base を混ぜる
+    // coat は albedo を掛けずに coat_roughness の粗さで、base は albedo と roughness で評価する
+    Layered { coat: Box<SurfaceType>, base: Box<SurfaceType>, refractive_index: f64, coat_roughness: f64 },
+
+    // Disney の Principled BSDF
+    Principled(Principled),
+
+    // 複素屈折率 eta + ik を RGB ごとに指定した金属。GGX の鏡面反射で、フレネル項を厳密に計算する
+    // 色は複素屈折率で決まるので、albedo は白にする
+    Conductor { ior: ConductorIor },
+
+    // 接線方向と従法線方向で粗さが異なる GGX（ヘアライン加工した金属など）。roughness の代わりに roughness_x, roughness_y を使う
+    // 接線は tangent（ワールド座標）で指定するか、省略するとメッシュの UV の u が増える方向にする
+    AnisotropicGGX {
+        f0: f64,
+        roughness_x: f64,
+        roughness_y: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tangent: Option<[f64; 3]>,
+    },
+}
+
+impl SurfaceType {
+    pub fn nee_available(&self) -> bool {
+        match *self {
+            SurfaceType::Diffuse => true,
+            SurfaceType::GGX { f0: _ } => true,
+            SurfaceType::Layered { ref coat, ref base, .. } => coat.nee_available() && base.nee_available(),
+            SurfaceType::Principled(_) => true,// 透過のローブは bsdf に含めない
+            SurfaceType::Conductor { ior: _ } => true,
+            SurfaceType::AnisotropicGGX { .. } => true,
+
+            SurfaceType::Specular => false,
+            SurfaceType::Refraction { refractive_index: _ } => false,
+            SurfaceType::GGXRefraction { refractive_index: _ } => false,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Material {
     pub surface: SurfaceType,
     pub albedo: Texture,
@@ -22,12 +62,43 @@ pub struct Material {
     pub roughness: Texture,
 }
 
+impl Material {
+    // Principled のテクスチャを uv の位置でサンプリングした SurfaceType
+    pub fn surface_at(&self, uv: Vector2) -> SurfaceType {
+        if let SurfaceType::Principled(ref principled) = self.surface {
+            if let Some(ref maps) = principled.maps {
+                return SurfaceType::Principled(Principled {
+                    metallic: principled.metallic * maps.metallic.sample(uv).x,
+                    specular: principled.specular * maps.specular.sample(uv).x,
+                    sheen: principled.sheen * maps.sheen.sample(uv).x,
+                    clearcoat: principled.clearcoat * maps.clearcoat.sample(uv).x,
+                    transmission: principled.transmission * maps.transmission.sample(uv).x,
+                    maps: None,
+                    ..principled.clone()
+                });
+            }
+        }
+        self.surface.clone()
+    }
+}
+
+// Principled のパラメーターに乗算するテクスチャ（R チャンネルを使う）
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrincipledMaps {
+    pub metallic: Texture,
+    pub specular: Texture,
+    pub sheen: Texture,
+    pub clearcoat: Texture,
+    pub transmission: Texture,
+}
+
 #[derive(Clone, Debug)]
 pub struct PointMaterial {
     pub surface: SurfaceType,
     pub albedo: Color,
     pub emission: Color,
     pub roughness: f64,
+    pub tangent: Vector3,
 }
 
 pub struct SampleResult {
@@ -35,27 +106,52 @@ pub struct SampleResult {
 
     // reflectance = bsdf * cos(normal, light) / pdf
     // 重点的サンプリングを行うと、bsdf * cos を pdf が打ち消すケースが多いので、このような定義とした
-    pub reflectance: f64,
+    // Layered では層ごとに albedo を掛けるかどうかが異なるので、albedo を含めた値にする
+    pub reflectance: Color,
 }
 
 impl PointMaterial {
     pub fn nee_available(&self) -> bool {
-        match self.surface {
-            SurfaceType::Diffuse => true,
-            SurfaceType::GGX { f0: _ } => true,
+        self.surface.nee_available()
+    }
 
-            SurfaceType::Specular => false,
-            SurfaceType::Refraction { refractive_index: _ } => false,
-            SurfaceType::GGXRefraction { refractive_index: _ } => false,
+    // albedo を含めた bsdf
+    pub fn bsdf(&self, view: &Vector3, normal: &Vector3, light: &Vector3) -> Color {
+        self.lobe().bsdf(view, normal, light)
+    }
+
+    // random.0 と random.1 は方向のサンプリングに、random.2 は反射か屈折かの選択に使う
+    pub fn sample(&self, random: (f64, f64, f64), position: &Vector3, view: &Vector3, normal: &Vector3, geometric_normal: &Vector3) -> Option<SampleResult> {
+        self.lobe().sample(random, position, view, normal, geometric_normal)
+    }
+
+    fn lobe<'a>(&'a self) -> Lobe<'a> {
+        Lobe {
+            surface: &self.surface,
+            albedo: self.albedo,
+            roughness: self.roughness,
+            tangent: self.tangent,
         }
     }
+}
 
-    pub fn bsdf(&self, view: &Vector3, normal: &Vector3, light: &Vector3) -> f64 {
-        match self.surface {
-            SurfaceType::Diffuse => config::PI.recip(),
+// 1つの SurfaceType を評価するためのパラメーター
+// Layered では coat と base で albedo と roughness が異なるので、PointMaterial と分けている
+struct Lobe<'a> {
+    surface: &'a SurfaceType,
+    albedo: Color,
+    roughness: f64,
+    tangent: Vector3,
+}
+
+impl<'a> Lobe<'a> {
+    fn bsdf(&self, view: &Vector3, normal: &Vector3, light: &Vector3) -> Color {
+        match *self.surface {
+            SurfaceType::Diffuse => self.albedo * config::PI.recip(),
             SurfaceType::Specular => unimplemented!(),
             SurfaceType::Refraction { refractive_index: _ } => unimplemented!(),
-            SurfaceType::GGX { f0 } => {
+            SurfaceType::Principled(ref principled) => principled.bsdf(self, view, normal, light),
+            SurfaceType::GGX { f0: _ } | SurfaceType::Conductor { ior: _ } => {
                 // https://schuttejoe.github.io/post/ggximportancesamplingpart1/
                 // i: view, g: light, m: half
 
@@ -65,7 +161,7 @@ impl PointMaterial {
 
                 let l_dot_n = light.dot(normal);
                 if l_dot_n.is_sign_negative() {
-                    return 0.0;
+                    return Color::zero();
                 }
 
                 let v_dot_n = view.dot(normal);
@@ -73,85 +169,432 @@ impl PointMaterial {
                 let h_dot_n = half.dot(normal);
 
                 // D: Microfacet Distribution Functions GGX(Trowbridge-Reitz model)
-                let tmp = 1.0 - (1.0 - alpha2) * h_dot_n * h_dot_n;
-                let d = alpha2 / (config::PI * tmp * tmp);
+                let d = d_ggx(h_dot_n, alpha2);
 
                 // G: Masking-Shadowing Fucntion
                 let g = g_smith_joint(l_dot_n, v_dot_n, alpha2);
 
                 // F: Fresnel term
-                let f = f_schlick_f64(v_dot_h, f0);
+                let f = self.fresnel(v_dot_h);
+
+                f * (d * g / (4.0 * l_dot_n * v_dot_n))
+            }
+            SurfaceType::GGXRefraction { refractive_index: _ } => unimplemented!(),
+            SurfaceType::Layered { ref coat, ref base, refractive_index, coat_roughness } => {
+                let (coat, base, fresnel) = self.layers(coat, base, refractive_index, coat_roughness, view, normal);
+                coat.bsdf(view, normal, light) * fresnel + base.bsdf(view, normal, light) * (1.0 - fresnel)
+            }
+            SurfaceType::AnisotropicGGX { f0, roughness_x, roughness_y, ref tangent } => {
+                // 接空間（tangent, binormal, normal）で評価する
+                let frame = self.tangent_frame(tangent, normal);
+                let v = frame.to_local(view);
+                let l = frame.to_local(light);
+                if l.z <= 0.0 || v.z <= 0.0 {
+                    return Color::zero();
+                }
 
-                d * g * f / (4.0 * l_dot_n * v_dot_n)
+                let (alpha_x, alpha_y) = (anisotropic_alpha(roughness_x), anisotropic_alpha(roughness_y));
+                let h = (v + l).normalize();
+                let d = d_ggx_anisotropic(&h, alpha_x, alpha_y);
+                let g = (1.0 + lambda_ggx_anisotropic(&v, alpha_x, alpha_y) + lambda_ggx_anisotropic(&l, alpha_x, alpha_y)).recip();
+                let f = f_schlick_f64(v.dot(&h), f0);
+                self.albedo * (d * g * f / (4.0 * l.z * v.z))
             }
-            SurfaceType::GGXRefraction { refractive_index: _ } => unimplemented!()
         }
     }
 
-    pub fn sample(&self, random: (f64, f64), position: &Vector3, view: &Vector3, normal: &Vector3) -> Option<SampleResult> {
+    // レイの原点は geometric_normal の方向にずらす（補間した法線でずらすとシャドウターミネーター問題が起きる）
+    fn sample(&self, random: (f64, f64, f64), position: &Vector3, view: &Vector3, normal: &Vector3, geometric_normal: &Vector3) -> Option<SampleResult> {
         let ray = -*view;
 
-        match self.surface {
+        match *self.surface {
             SurfaceType::Diffuse => {
+                let direction = importance_sample_diffuse((random.0, random.1), normal);
                 Some(SampleResult {
                     ray: Ray {
-                        origin: *position + *normal * config::OFFSET,
-                        direction: importance_sample_diffuse(random, normal),
+                        origin: offset_origin(position, geometric_normal, &direction),
+                        direction,
                     },
-                    reflectance: 1.0,// bsdf * cos と pdf が打ち消し合う
+                    reflectance: self.albedo,// bsdf * cos と pdf が打ち消し合う
                 })
             }
             SurfaceType::Specular => {
+                let direction = ray.reflect(normal);
                 Some(SampleResult {
                     ray: Ray {
-                        origin: *position + *normal * config::OFFSET,
-                        direction: ray.reflect(normal),
+                        origin: offset_origin(position, geometric_normal, &direction),
+                        direction,
                     },
-                    reflectance: 1.0,// bsdf * cos と pdf が打ち消し合う
+                    reflectance: self.albedo,// bsdf * cos と pdf が打ち消し合う
                 })
             }
             SurfaceType::Refraction { refractive_index } => {
-                sample_refraction(random, position, &ray, normal, refractive_index)
+                sample_refraction(random.2, position, &ray, normal, geometric_normal, refractive_index, &self.albedo)
             }
-            SurfaceType::GGX { f0 } => {
+            SurfaceType::GGX { f0: _ } | SurfaceType::Conductor { ior: _ } => {
+                // 視線から見える法線の分布（VNDF）でハーフベクトルを選ぶと、bsdf * cos / pdf が F * G2 / G1(v) になる
+                // D(h) * cos で選ぶ場合と違って、視線から見えないハーフベクトルが選ばれない
+                let v_dot_n = view.dot(normal);
+                if v_dot_n <= 0.0 {
+                    return None;
+                }
+
                 let alpha2 = roughness_to_alpha2(self.roughness);
-                let half = importance_sample_ggx_half(random, normal, alpha2);
+                let frame = TangentFrame::from_normal(normal);
+                let half = frame.to_world(&sample_ggx_vndf((random.0, random.1), &frame.to_local(view), alpha2.sqrt(), alpha2.sqrt()));
                 let next_direction = ray.reflect(&half);
 
-                // 半球外が選ばれた場合はBRDFを0にする
+                // 半球外の方向は G2 が 0 なので寄与が無い
                 let l_dot_n = next_direction.dot(normal);
-                if l_dot_n.is_sign_negative() {
-                    None
-                } else {
-                    let v_dot_n = view.dot(normal);
-                    let v_dot_h = view.dot(&half);
-                    let h_dot_n = half.dot(normal);
-
-                    // G: Masking-Shadowing Fucntion
-                    let g = g_smith_joint(l_dot_n, v_dot_n, alpha2);
-
-                    // F: Fresnel term
-                    let f = f_schlick_f64(v_dot_h, f0);
-
-                    Some(SampleResult {
-                        ray: Ray {
-                            origin: *position + *normal * config::OFFSET,
-                            direction: next_direction,
-                        },
-                        reflectance: f * saturate(g * v_dot_h / (h_dot_n * v_dot_n)),
-                    })
+                if l_dot_n <= 0.0 {
+                    return None;
                 }
+
+                let v_dot_h = view.dot(&half);
+
+                // G: Masking-Shadowing Fucntion
+                let g = g_smith_joint(l_dot_n, v_dot_n, alpha2);
+                let g1_v = (1.0 + g_smith_joint_lambda(v_dot_n, alpha2)).recip();
+
+                // F: Fresnel term
+                let f = self.fresnel(v_dot_h);
+
+                Some(SampleResult {
+                    ray: Ray {
+                        origin: offset_origin(position, geometric_normal, &next_direction),
+                        direction: next_direction,
+                    },
+                    reflectance: f * (g / g1_v),
+                })
             }
             SurfaceType::GGXRefraction { refractive_index } => {
+                // 視線の側を向いた法線を基準にして、VNDF でハーフベクトルを選ぶ
+                let is_outside = view.dot(normal) > 0.0;
+                let oriented_normal = if is_outside { *normal } else { -*normal };
+                let v_dot_n = view.dot(&oriented_normal);
                 let alpha2 = roughness_to_alpha2(self.roughness);
-                let half = importance_sample_ggx_half(random, normal, alpha2);
-                sample_refraction(random, position, &ray, &half, refractive_index)
+                let frame = TangentFrame::from_normal(&oriented_normal);
+                let half = frame.to_world(&sample_ggx_vndf((random.0, random.1), &frame.to_local(view), alpha2.sqrt(), alpha2.sqrt()));
+
+                // 物体の内外は sample_refraction が法線の向きで判定するので、ハーフベクトルは外向きに戻して渡す
+                let outward_half = if is_outside { half } else { -half };
+                let mut result = sample_refraction(random.2, position, &ray, &outward_half, geometric_normal, refractive_index, &self.albedo)?;
+
+                // 反射なら視線と同じ側、屈折なら反対側に進まなければ G2 が 0 なので寄与が無い
+                let l_dot_n = result.ray.direction.dot(&oriented_normal);
+                let is_reflection = result.ray.direction.dot(&half).is_sign_positive();
+                if is_reflection != l_dot_n.is_sign_positive() {
+                    return None;
+                }
+
+                let g = g_smith_joint(l_dot_n.abs(), v_dot_n, alpha2);
+                let g1_v = (1.0 + g_smith_joint_lambda(v_dot_n, alpha2)).recip();
+                result.reflectance *= g / g1_v;
+                Some(result)
+            }
+            SurfaceType::Layered { ref coat, ref base, refractive_index, coat_roughness } => {
+                // フレネル反射率を確率として coat か base の一方をサンプリングする。選ぶ確率と重みが打ち消し合う
+                // 選択に使った乱数の区間を 0 から 1 に引き伸ばして、選んだ層のサンプリングに使い回す
+                let (coat, base, fresnel) = self.layers(coat, base, refractive_index, coat_roughness, view, normal);
+                if random.0 < fresnel {
+                    coat.sample((random.0 / fresnel, random.1, random.2), position, view, normal, geometric_normal)
+                } else {
+                    base.sample(((random.0 - fresnel) / (1.0 - fresnel), random.1, random.2), position, view, normal, geometric_normal)
+                }
             }
+            SurfaceType::Principled(ref principled) => {
+                principled.sample(self, random, position, view, normal, geometric_normal)
+            }
+            SurfaceType::AnisotropicGGX { f0, roughness_x, roughness_y, ref tangent } => {
+                let frame = self.tangent_frame(tangent, normal);
+                let v = frame.to_local(view);
+                if v.z <= 0.0 {
+                    return None;
+                }
+
+                // 視線から見える法線の分布でハーフベクトルを選ぶと、bsdf * cos / pdf が F * G2 / G1(v) になる
+                let (alpha_x, alpha_y) = (anisotropic_alpha(roughness_x), anisotropic_alpha(roughness_y));
+                let h = sample_ggx_vndf((random.0, random.1), &v, alpha_x, alpha_y);
+                let l = (-v).reflect(&h);
+                if l.z <= 0.0 {
+                    return None;
+                }
+
+                let lambda_v = lambda_ggx_anisotropic(&v, alpha_x, alpha_y);
+                let lambda_l = lambda_ggx_anisotropic(&l, alpha_x, alpha_y);
+                let f = f_schlick_f64(v.dot(&h), f0);
+                let direction = frame.to_world(&l);
+                Some(SampleResult {
+                    ray: Ray {
+                        origin: offset_origin(position, geometric_normal, &direction),
+                        direction,
+                    },
+                    reflectance: self.albedo * (f * (1.0 + lambda_v) / (1.0 + lambda_v + lambda_l)),
+                })
+            }
+        }
+    }
+
+    // 明示した tangent かメッシュの UV から求めた接線を、法線と直交させた基底にする
+    // どちらも無い場合は法線から適当に決める
+    fn tangent_frame(&self, tangent: &Option<[f64; 3]>, normal: &Vector3) -> TangentFrame {
+        let tangent = match *tangent {
+            Some(ref t) => Vector3::new(t[0], t[1], t[2]),
+            None => self.tangent,
+        };
+        let tangent = tangent - *normal * tangent.dot(normal);
+        if tangent.length() > config::EPS {
+            let tangent = tangent.normalize();
+            TangentFrame { tangent, binormal: normal.cross(&tangent), normal: *normal }
+        } else {
+            TangentFrame::from_normal(normal)
+        }
+    }
+
+    // GGX と Conductor のフレネル項（albedo を含む）
+    fn fresnel(&self, v_dot_h: f64) -> Color {
+        match *self.surface {
+            SurfaceType::GGX { f0 } => self.albedo * f_schlick_f64(v_dot_h, f0),
+            SurfaceType::Conductor { ref ior } => {
+                let (eta, k) = ior.eta_k();
+                self.albedo * f_conductor(v_dot_h, &eta, &k)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Layered の coat と base と、coat を選ぶ重み（フレネル反射率）
+    fn layers(&self, coat: &'a SurfaceType, base: &'a SurfaceType, refractive_index: f64, coat_roughness: f64, view: &Vector3, normal: &Vector3) -> (Lobe<'a>, Lobe<'a>, f64) {
+        let f0 = ((refractive_index - 1.0) / (refractive_index + 1.0)).powi(2);
+        let fresnel = f_schlick_f64(view.dot(normal).abs(), f0);
+        let coat = Lobe { surface: coat, albedo: Color::one(), roughness: coat_roughness, tangent: self.tangent };
+        let base = Lobe { surface: base, albedo: self.albedo, roughness: self.roughness, tangent: self.tangent };
+        (coat, base, fresnel)
+    }
+}
+
+struct TangentFrame {
+    tangent: Vector3,
+    binormal: Vector3,
+    normal: Vector3,
+}
+
+impl TangentFrame {
+    fn from_normal(normal: &Vector3) -> TangentFrame {
+        let (tangent, binormal) = get_tangent_space_basis_gram_schmidtd(normal);
+        TangentFrame { tangent, binormal, normal: *normal }
+    }
+
+    fn to_local(&self, v: &Vector3) -> Vector3 {
+        Vector3::new(v.dot(&self.tangent), v.dot(&self.binormal), v.dot(&self.normal))
+    }
+
+    fn to_world(&self, v: &Vector3) -> Vector3 {
+        self.tangent * v.x + self.binormal * v.y + self.normal * v.z
+    }
+}
+
+// 金属の複素屈折率。名前で組み込みの値を使うか、eta と k を RGB ごとに指定する
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ConductorIor {
+    Metal(Metal),
+    Custom { eta: [f64; 3], k: [f64; 3] },
+}
+
+impl ConductorIor {
+    fn eta_k(&self) -> (Color, Color) {
+        match *self {
+            ConductorIor::Metal(metal) => metal.eta_k(),
+            ConductorIor::Custom { ref eta, ref k } => (Color::new(eta[0], eta[1], eta[2]), Color::new(k[0], k[1], k[2])),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Metal {
+    Gold,
+    Silver,
+    Copper,
+    Aluminium,
+    Chrome,
+}
+
+impl Metal {
+    // R, G, B をおよそ 650nm, 550nm, 450nm として測定値から求めた複素屈折率
+    // https://refractiveindex.info/
+    fn eta_k(&self) -> (Color, Color) {
+        match *self {
+            Metal::Gold => (Color::new(0.1431, 0.3750, 1.4425), Color::new(3.9832, 2.3857, 1.6032)),
+            Metal::Silver => (Color::new(0.1553, 0.1167, 0.1384), Color::new(4.8283, 3.1222, 2.1470)),
+            Metal::Copper => (Color::new(0.2004, 0.9240, 1.1022), Color::new(3.9129, 2.4528, 2.1422)),
+            Metal::Aluminium => (Color::new(1.6575, 0.8804, 0.5212), Color::new(9.2239, 6.2695, 4.8370)),
+            Metal::Chrome => (Color::new(3.1071, 3.1812, 2.3230), Color::new(3.3314, 3.3291, 3.1350)),
+        }
+    }
+}
+
+// Disney の Principled BSDF のパラメーター（0 から 1）
+// 拡散反射と sheen、GGX の鏡面反射、GTR1 の clearcoat、GGX の透過の4つのローブからなる
+// 色は albedo、粗さは roughness を使う
+// https://disney-animation.s3.amazonaws.com/library/s2012_pbs_disney_brdf_notes_v2.pdf
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Principled {
+    #[serde(default)]
+    pub metallic: f64,
+    #[serde(default = "default_specular")]
+    pub specular: f64,
+    #[serde(default)]
+    pub specular_tint: f64,
+    #[serde(default)]
+    pub sheen: f64,
+    #[serde(default = "default_sheen_tint")]
+    pub sheen_tint: f64,
+    #[serde(default)]
+    pub clearcoat: f64,
+    #[serde(default = "default_clearcoat_gloss")]
+    pub clearcoat_gloss: f64,
+    #[serde(default)]
+    pub transmission: f64,
+    #[serde(default = "default_refractive_index")]
+    pub refractive_index: f64,
+
+    // シーンファイルでは material の metallic などで指定するので、ここでは読み書きしない
+    #[serde(skip)]
+    pub maps: Option<PrincipledMaps>,
+}
+
+fn default_specular() -> f64 { 0.5 }
+
+fn default_sheen_tint() -> f64 { 0.5 }
+
+fn default_clearcoat_gloss() -> f64 { 1.0 }
+
+fn default_refractive_index() -> f64 { 1.5 }
+
+impl Principled {
+    // 拡散反射、鏡面反射、clearcoat、透過のローブの重み
+    // 透過は鏡面反射を含めた屈折のローブなので、その分だけ鏡面反射を弱める
+    fn weights(&self) -> (f64, f64, f64, f64) {
+        let transmission = (1.0 - self.metallic) * self.transmission;
+        let diffuse = (1.0 - self.metallic) * (1.0 - self.transmission);
+        let specular = 1.0 - transmission;
+        let clearcoat = 0.25 * self.clearcoat;
+        (diffuse, specular, clearcoat, transmission)
+    }
+
+    // 反射のローブの bsdf の和（透過のローブは含まない）
+    // 色は lobe.albedo、粗さは lobe.roughness を使う
+    fn bsdf(&self, lobe: &Lobe, view: &Vector3, normal: &Vector3, light: &Vector3) -> Color {
+        let (albedo, roughness) = (&lobe.albedo, lobe.roughness);
+        let l_dot_n = light.dot(normal);
+        let v_dot_n = view.dot(normal);
+        if l_dot_n <= 0.0 || v_dot_n <= 0.0 {
+            return Color::zero();
+        }
+
+        let half = (*light + *view).normalize();
+        let h_dot_n = half.dot(normal);
+        let l_dot_h = light.dot(&half);
+        let (diffuse_weight, specular_weight, clearcoat_weight, _) = self.weights();
+
+        // 明るさを除いた albedo の色味
+        let luminance = color_to_luminance(albedo);
+        let tint = if luminance > 0.0 { *albedo / luminance } else { Color::one() };
+
+        // Burley の拡散反射（粗いほど視線と光源の角度が大きいときに明るくなる）と、布の縁を明るくする sheen
+        let fd90 = 0.5 + 2.0 * roughness * l_dot_h * l_dot_h;
+        let fd = (1.0 + (fd90 - 1.0) * schlick_weight(l_dot_n)) * (1.0 + (fd90 - 1.0) * schlick_weight(v_dot_n));
+        let sheen = mix(&Color::one(), &tint, self.sheen_tint) * (self.sheen * schlick_weight(l_dot_h));
+        let diffuse = (*albedo * (fd / config::PI) + sheen) * diffuse_weight;
+
+        // 鏡面反射の F0 は、specular = 0.5 で屈折率 1.5 相当の 0.04 になり、metallic が大きいほど albedo に近づく
+        let alpha2 = principled_alpha2(roughness);
+        let f0 = mix(&(mix(&Color::one(), &tint, self.specular_tint) * (0.08 * self.specular)), albedo, self.metallic);
+        let f = f0 + (Color::one() - f0) * schlick_weight(l_dot_h);
+        let specular = f * (d_ggx(h_dot_n, alpha2) * g_smith_joint(l_dot_n, v_dot_n, alpha2) / (4.0 * l_dot_n * v_dot_n) * specular_weight);
+
+        // clearcoat は F0 = 0.04 で、G の alpha は 0.25 に固定する
+        let clearcoat = d_gtr1(h_dot_n, clearcoat_alpha2(self.clearcoat_gloss))
+            * f_schlick_f64(l_dot_h, 0.04)
+            * g_smith_joint(l_dot_n, v_dot_n, 0.25 * 0.25)
+            / (4.0 * l_dot_n * v_dot_n)
+            * clearcoat_weight;
+
+        diffuse + specular + Color::from_one(clearcoat)
+    }
+
+    fn sample(&self, lobe: &Lobe, random: (f64, f64, f64), position: &Vector3, view: &Vector3, normal: &Vector3, geometric_normal: &Vector3) -> Option<SampleResult> {
+        let ray = -*view;
+        let alpha2 = principled_alpha2(lobe.roughness);
+        let (diffuse_weight, specular_weight, clearcoat_weight, transmission_weight) = self.weights();
+
+        // 透過のローブは重みを確率として選び、同じ albedo と roughness の GGXRefraction としてサンプリングする
+        // 選択に使った乱数の区間を 0 から 1 に引き伸ばして使い回す
+        if random.0 < transmission_weight {
+            let refraction = SurfaceType::GGXRefraction { refractive_index: self.refractive_index };
+            let lobe = Lobe { surface: &refraction, ..*lobe };
+            return lobe.sample((random.0 / transmission_weight, random.1, random.2), position, view, normal, geometric_normal);
+        }
+
+        let v_dot_n = view.dot(normal);
+        if v_dot_n <= 0.0 {
+            return None;
         }
+
+        // 反射のローブは重みに比例した確率で1つ選んでサンプリングする
+        // 選ばなかったローブも同じ方向を生成しうるので、pdf は各ローブの pdf を確率で重み付けした和にする
+        // 鏡面反射は GGX と同じく VNDF でハーフベクトルを選ぶ
+        let random = ((random.0 - transmission_weight) / (1.0 - transmission_weight), random.1);
+        let total = diffuse_weight + specular_weight + clearcoat_weight;
+        let (p_diffuse, p_specular, p_clearcoat) = (diffuse_weight / total, specular_weight / total, clearcoat_weight / total);
+        let clearcoat_alpha2 = clearcoat_alpha2(self.clearcoat_gloss);
+        let direction = if random.0 < p_diffuse {
+            importance_sample_diffuse((random.0 / p_diffuse, random.1), normal)
+        } else if random.0 < p_diffuse + p_specular {
+            let frame = TangentFrame::from_normal(normal);
+            let random = ((random.0 - p_diffuse) / p_specular, random.1);
+            ray.reflect(&frame.to_world(&sample_ggx_vndf(random, &frame.to_local(view), alpha2.sqrt(), alpha2.sqrt())))
+        } else {
+            ray.reflect(&importance_sample_gtr1_half(((random.0 - p_diffuse - p_specular) / p_clearcoat, random.1), normal, clearcoat_alpha2))
+        };
+
+        // 半球外が選ばれた場合はBRDFを0にする
+        let l_dot_n = direction.dot(normal);
+        if l_dot_n <= 0.0 {
+            return None;
+        }
+
+        let half = (direction + *view).normalize();
+        let h_dot_n = half.dot(normal);
+        let v_dot_h = view.dot(&half);
+        // VNDF の pdf は G1(v) * D / (4 * v・n) になる
+        let g1_v = (1.0 + g_smith_joint_lambda(v_dot_n, alpha2)).recip();
+        let pdf = p_diffuse * l_dot_n / config::PI
+            + p_specular * d_ggx(h_dot_n, alpha2) * g1_v / (4.0 * v_dot_n)
+            + p_clearcoat * d_gtr1(h_dot_n, clearcoat_alpha2) * h_dot_n / (4.0 * v_dot_h);
+
+        Some(SampleResult {
+            ray: Ray {
+                origin: offset_origin(position, geometric_normal, &direction),
+                direction,
+            },
+            reflectance: self.bsdf(lobe, view, normal, &direction) * (l_dot_n / ((1.0 - transmission_weight) * pdf)),
+        })
     }
 }
 
-fn sample_refraction(random: (f64, f64), position: &Vector3, view: &Vector3, normal: &Vector3, refractive_index: f64) -> Option<SampleResult> {
+// roughness が 0 だと D と pdf が発散するので、alpha2 に下限を設ける
+fn principled_alpha2(roughness: f64) -> f64 {
+    roughness_to_alpha2(roughness).max(config::EPS)
+}
+
+fn clearcoat_alpha2(clearcoat_gloss: f64) -> f64 {
+    let alpha = 0.1 + (0.001 - 0.1) * clearcoat_gloss;
+    alpha * alpha
+}
+
+fn sample_refraction(select: f64, position: &Vector3, view: &Vector3, normal: &Vector3, geometric_normal: &Vector3, refractive_index: f64, albedo: &Color) -> Option<SampleResult> {
     let is_incoming = view.dot(&normal).is_sign_negative();
     let oriented_normal = if is_incoming { *normal } else { -*normal };
     let nnt = if is_incoming { refractive_index.recip() } else { refractive_index };
@@ -161,10 +604,10 @@ fn sample_refraction(random: (f64, f64), position: &Vector3, view: &Vector3, nor
         // 全反射のケース
         Some(SampleResult {
             ray: Ray {
-                origin: *position + config::OFFSET * oriented_normal,
+                origin: offset_origin(position, geometric_normal, &reflect_direction),
                 direction: reflect_direction,
             },
-            reflectance: 1.0,// bsdf * cos と pdf が打ち消し合う
+            reflectance: *albedo,// bsdf * cos と pdf が打ち消し合う
         })
     } else {
         // フレネル反射率rの計算
@@ -176,28 +619,37 @@ fn sample_refraction(random: (f64, f64), position: &Vector3, view: &Vector3, nor
         let r_p = (nnt * cos_t - cos_i) * (nnt * cos_t - cos_i) / ((nnt * cos_t + cos_i) * (nnt * cos_t + cos_i));
         let fr = 0.5 * (r_s + r_p);
 
-        if random.0 <= fr {
+        if select <= fr {
             // 反射
             Some(SampleResult {
                 ray: Ray {
-                    origin: *position + config::OFFSET * oriented_normal,
+                    origin: offset_origin(position, geometric_normal, &reflect_direction),
                     direction: reflect_direction,
                 },
-                reflectance: 1.0,// bsdf * cos と pdf が打ち消し合う
+                reflectance: *albedo,// bsdf * cos と pdf が打ち消し合う
             })
         } else {
             // 屈折
             Some(SampleResult {
                 ray: Ray {
-                    origin: *position - config::OFFSET * oriented_normal,// 物体内部にレイの原点を移動する
+                    origin: offset_origin(position, geometric_normal, &refract_direction),// 物体内部にレイの原点を移動する
                     direction: refract_direction,
                 },
-                reflectance: nnt * nnt,// 立体角の変化に伴う放射輝度の補正
+                reflectance: *albedo * (nnt * nnt),// 立体角の変化に伴う放射輝度の補正
             })
         }
     }
 }
 
+// 次のレイが進む側の面にレイの原点をずらす
+fn offset_origin(position: &Vector3, geometric_normal: &Vector3, direction: &Vector3) -> Vector3 {
+    if direction.dot(geometric_normal).is_sign_negative() {
+        *position - *geometric_normal * config::OFFSET
+    } else {
+        *position + *geometric_normal * config::OFFSET
+    }
+}
+
 // 法線を基準とした空間の基底ベクトルを計算
 fn get_tangent_space_basis_gram_schmidtd(normal: &Vector3) -> (Vector3, Vector3) {
     let up = if normal.x.abs() > config::EPS {
@@ -254,20 +706,64 @@ fn roughness_to_alpha2(roughness: f64) -> f64 {
     alpha * alpha
 }
 
-// Unreal Engine 4 で利用されている ImportanceSampleGGX を移植
-// cos項による重点サンプリングのためのハーフベクトルを計算
-// http://project-asura.com/blog/?p=3124
-fn importance_sample_ggx_half(random: (f64, f64), normal: &Vector3, alpha2: f64) -> Vector3 {
+// Berry の分布（GTR1）による重点サンプリング。Principled の clearcoat で使う
+fn importance_sample_gtr1_half(random: (f64, f64), normal: &Vector3, alpha2: f64) -> Vector3 {
     let (tangent, binormal) = get_tangent_space_basis_gram_schmidtd(normal);
 
     let phi = config::PI2 * random.0;
-    let cos_theta = ((1.0 - random.1) / (1.0 + (alpha2 - 1.0) * random.1)).sqrt();
+    let cos_theta = ((1.0 - alpha2.powf(1.0 - random.1)) / (1.0 - alpha2)).sqrt();
     let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
     let h = Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
     tangent * h.x + binormal * h.y + *normal * h.z
 }
 
+fn d_ggx(h_dot_n: f64, alpha2: f64) -> f64 {
+    let tmp = 1.0 - (1.0 - alpha2) * h_dot_n * h_dot_n;
+    alpha2 / (config::PI * tmp * tmp)
+}
+
+fn d_gtr1(h_dot_n: f64, alpha2: f64) -> f64 {
+    (alpha2 - 1.0) / (config::PI * alpha2.ln() * (1.0 + (alpha2 - 1.0) * h_dot_n * h_dot_n))
+}
+
+// 粗さが 0 だと D が発散するので、alpha に下限を設ける
+fn anisotropic_alpha(roughness: f64) -> f64 {
+    roughness.max(config::EPS)
+}
+
+// 以下の異方性 GGX の関数は接空間のベクトル（z が法線方向）を受け取る
+fn d_ggx_anisotropic(h: &Vector3, alpha_x: f64, alpha_y: f64) -> f64 {
+    let tmp = (h.x / alpha_x).powi(2) + (h.y / alpha_y).powi(2) + h.z * h.z;
+    (config::PI * alpha_x * alpha_y * tmp * tmp).recip()
+}
+
+fn lambda_ggx_anisotropic(w: &Vector3, alpha_x: f64, alpha_y: f64) -> f64 {
+    let a = ((alpha_x * w.x).powi(2) + (alpha_y * w.y).powi(2)) / (w.z * w.z);
+    0.5 * (1.0 + a).sqrt() - 0.5
+}
+
+// 視線から見える法線の分布（VNDF）による重点サンプリング
+// http://jcgt.org/published/0007/04/01/
+fn sample_ggx_vndf(random: (f64, f64), view: &Vector3, alpha_x: f64, alpha_y: f64) -> Vector3 {
+    // 粗さ 1 の半球に引き伸ばした視線
+    let v = Vector3::new(alpha_x * view.x, alpha_y * view.y, view.z).normalize();
+
+    let len2 = v.x * v.x + v.y * v.y;
+    let t1 = if len2 > 0.0 { Vector3::new(-v.y, v.x, 0.0) / len2.sqrt() } else { Vector3::new(1.0, 0.0, 0.0) };
+    let t2 = v.cross(&t1);
+
+    // 視線に垂直な円盤上の点を、視線から見える半球の射影に合わせて歪める
+    let r = random.0.sqrt();
+    let phi = config::PI2 * random.1;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + v.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+
+    let n = t1 * p1 + t2 * p2 + v * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+    Vector3::new(alpha_x * n.x, alpha_y * n.y, n.z.max(0.0)).normalize()
+}
+
 fn g_smith_joint_lambda(x_dot_n: f64, alpha2: f64) -> f64 {
     let a = (x_dot_n * x_dot_n).recip() - 1.0;
     0.5 * (1.0 + alpha2 * a).sqrt() - 0.5
@@ -282,3 +778,35 @@ fn g_smith_joint(l_dot_n: f64, v_dot_n: f64, alpha2: f64) -> f64 {
 fn f_schlick_f64(v_dot_h: f64, f0: f64) -> f64 {
     f0 + (1.0 - f0) * (1.0 - v_dot_h).powi(5)
 }
+
+// 導体のフレネル反射率（S 波と P 波の平均）
+// https://seblagarde.wordpress.com/2013/04/29/memo-on-fresnel-equations/
+fn f_conductor_f64(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * a * cos_i;
+    let r_s = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let r_p = r_s * (t3 - t4) / (t3 + t4);
+
+    0.5 * (r_s + r_p)
+}
+
+fn f_conductor(cos_i: f64, eta: &Color, k: &Color) -> Color {
+    Color::new(
+        f_conductor_f64(cos_i, eta.x, k.x),
+        f_conductor_f64(cos_i, eta.y, k.y),
+        f_conductor_f64(cos_i, eta.z, k.z),
+    )
+}
+
+fn schlick_weight(cos: f64) -> f64 {
+    (1.0 - saturate(cos)).powi(5)
+}
@@ -34,3 +34,43 @@ pub fn det(a: &Vector3, b: &Vector3, c: &Vector3) -> f64 {
 pub fn mix(x: &Vector3, y: &Vector3, a: f64) -> Vector3 {
     *x * (1.0 - a) + *y * a
 }
+
+// coefficients[0] + coefficients[1] * x + coefficients[2] * x^2 + ... = 0 の [min, max] にある実数解を昇順に求める
+// 導関数の解で単調な区間に分割し、符号が変わる区間を二分法で解く（重解は求めない）
+pub fn solve_polynomial(coefficients: &[f64], min: f64, max: f64) -> Vec<f64> {
+    let degree = coefficients.len() - 1;
+    if degree == 0 {
+        return vec![];
+    }
+    if degree == 1 {
+        let x = -coefficients[0] / coefficients[1];
+        return if x >= min && x <= max { vec![x] } else { vec![] };
+    }
+
+    let evaluate = |x: f64| coefficients.iter().rev().fold(0.0, |sum, c| sum * x + c);
+    let derivative: Vec<f64> = (1..degree + 1).map(|i| coefficients[i] * i as f64).collect();
+
+    let mut points = vec![min];
+    points.extend(solve_polynomial(&derivative, min, max));
+    points.push(max);
+
+    let mut roots = vec![];
+    for w in points.windows(2) {
+        let (mut low, mut high) = (w[0], w[1]);
+        let f_low = evaluate(low);
+        if f_low == 0.0 {
+            if roots.last() != Some(&low) { roots.push(low); }
+            continue;
+        }
+        if f_low.signum() == evaluate(high).signum() {
+            continue;
+        }
+        for _ in 0..100 {
+            let mid = 0.5 * (low + high);
+            if mid <= low || mid >= high { break; }
+            if evaluate(mid).signum() == f_low.signum() { low = mid; } else { high = mid; }
+        }
+        roots.push(0.5 * (low + high));
+    }
+    roots
+}
@@ -1,12 +1,16 @@
 use std::ops::{Mul, Index, IndexMut};
 use vector::Vector3;
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq)]
 pub struct Matrix44 {
     elements: [[f64; 4]; 4],
 }
 
 impl Matrix44 {
+    pub fn new(elements: [[f64; 4]; 4]) -> Matrix44 {
+        Matrix44 { elements }
+    }
+
     #[cfg_attr(rustfmt, rustfmt_skip)]
     pub fn identity() -> Matrix44 {
         Matrix44 {
@@ -143,6 +147,32 @@ impl Matrix44 {
 
         return s;
     }
+
+    pub fn transpose(&self) -> Matrix44 {
+        let mut result = Matrix44::identity();
+        for i in 0..4 {
+            for j in 0..4 {
+                result[i][j] = self[j][i];
+            }
+        }
+        result
+    }
+
+    // 平行移動を除いた 3x3 部分の行列式
+    pub fn determinant(&self) -> f64 {
+        self[0][0] * (self[1][1] * self[2][2] - self[1][2] * self[2][1])
+            - self[0][1] * (self[1][0] * self[2][2] - self[1][2] * self[2][0])
+            + self[0][2] * (self[1][0] * self[2][1] - self[1][1] * self[2][0])
+    }
+
+    // 平行移動を無視して方向ベクトルを変換する
+    pub fn transform_direction(&self, v: &Vector3) -> Vector3 {
+        Vector3 {
+            x: v.x * self[0][0] + v.y * self[0][1] + v.z * self[0][2],
+            y: v.x * self[1][0] + v.y * self[1][1] + v.z * self[1][2],
+            z: v.x * self[2][0] + v.y * self[2][1] + v.z * self[2][2],
+        }
+    }
 }
 
 impl Index<usize> for Matrix44 {
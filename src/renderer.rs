@@ -121,7 +121,7 @@ impl Renderer for DebugRenderer {
             match self.mode {
                 DebugRenderMode::Shading => {
                     let shadow_ray = Ray {
-                        origin: intersection.position + intersection.normal * config::OFFSET,
+                        origin: intersection.position + intersection.geometric_normal * config::OFFSET,
                         direction: light_direction,
                     };
                     let (shadow_hit, _) = scene.intersect(&shadow_ray);
@@ -172,16 +172,16 @@ impl Renderer for PathTracingRenderer {
         let mut reflectance = Color::one();
 
         for _ in 1..config::PATHTRACING_BOUNCE_LIMIT {
-            let random = rng.gen::<(f64, f64)>();
+            let random = rng.gen::<(f64, f64, f64)>();
             let (hit, mut intersection) = scene.intersect(&ray);
-            let mut current_reflectance = 1.0;
+            let mut current_reflectance = Color::one();
 
             if hit {
                 let view = &-ray.direction;
-                if let Some(result) = intersection.material.sample(random, &intersection.position, view, &intersection.normal) {
+                if let Some(result) = intersection.material.sample(random, &intersection.position, view, &intersection.normal, &intersection.geometric_normal) {
                     if intersection.material.nee_available() {
                         accumulation += reflectance * PathTracingRenderer::next_event_estimation(
-                            random, &result.ray.origin, view, &intersection.normal,
+                            (random.0, random.1), &result.ray.origin, view, &intersection.normal,
                             scene, &emissions, &intersection.material);
                     }
 
@@ -194,7 +194,7 @@ impl Renderer for PathTracingRenderer {
             }
 
             accumulation += reflectance * intersection.material.emission;
-            reflectance *= intersection.material.albedo * current_reflectance;
+            reflectance *= current_reflectance;
 
             if !hit || reflectance == Vector3::zero() { break; }
         }
@@ -292,6 +292,6 @@ impl PathTracingRenderer {
             }
         }
 
-        accumulation * material.albedo
+        accumulation
     }
 }
@@ -1,18 +1,36 @@
+use std::sync::Arc;
+
 use config;
 use vector::{Vector3, Vector2};
 use material::{Material, PointMaterial, SurfaceType};
 use camera::Ray;
 use texture::ImageTexture;
-use math::{equals_eps, modulo};
+use math::{equals_eps, modulo, solve_polynomial};
 use color::Color;
 use bvh::{BvhNode, Aabb, intersect_polygon};
+use matrix::Matrix44;
+use description::{ElementDescription, ElementType, TransformDescription, DisplacementDescription, from_vector3, unspanned};
+use gltf_loader::is_gltf_path;
+use subdivision::Subdivision;
+use displacement::Displacement;
 
 #[derive(Debug)]
 pub struct Intersection {
     pub position: Vector3,
     pub distance: f64,
     pub normal: Vector3,
+
+    // ポリゴンの面の法線。normal は頂点法線を補間したものなので、レイの原点をずらすときはこちらを使う
+    pub geometric_normal: Vector3,
+
     pub uv: Vector2,
+
+    // UV の u が増える方向（異方性反射の向きに使う）。求められない要素では zero にする
+    pub tangent: Vector3,
+
+    // 頂点カラー。albedo に乗算する
+    pub vertex_color: Color,
+
     pub material: PointMaterial,
 }
 
@@ -22,18 +40,36 @@ pub struct Surface {
     pub pdf: f64,
 }
 
+impl Surface {
+    // オブジェクト座標でサンプリングした点をワールド座標に変換する
+    // 面積は |det M| * |M^-T n| 倍になるので、面積あたりの pdf をその分だけ小さくする
+    pub fn transform(&self, matrix: &Matrix44, normal_matrix: &Matrix44) -> Surface {
+        let normal = normal_matrix.transform_direction(&self.normal);
+        let area_scale = matrix.determinant().abs() * normal.length();
+        Surface {
+            position: *matrix * self.position,
+            normal: normal.normalize(),
+            pdf: self.pdf / area_scale,
+        }
+    }
+}
+
 impl Intersection {
     pub fn empty() -> Intersection {
         Intersection {
             position: Vector3::zero(),
             distance: config::INF,
             normal: Vector3::zero(),
+            geometric_normal: Vector3::zero(),
             uv: Vector2::zero(),
+            tangent: Vector3::zero(),
+            vertex_color: Color::one(),
             material: PointMaterial {
                 surface: SurfaceType::Diffuse,
                 albedo: Color::one(),
                 emission: Color::zero(),
                 roughness: 0.2,
+                tangent: Vector3::zero(),
             },
         }
     }
@@ -44,8 +80,56 @@ pub trait Intersectable: Sync {
     fn material(&self) -> &Material;
     fn aabb(&self) -> Aabb;
 
+    // 無限に広がる要素（Plane）は false を返す。aabb() を持たないので BVH の外で交差判定する
+    fn bounded(&self) -> bool;
+
     fn nee_available(&self) -> bool;
     fn sample_on_surface(&self, random: (f64, f64)) -> Surface;
+
+    // シーンファイルに書き出すための記述。書き出せない要素は None を返す
+    fn description(&self) -> Option<ElementDescription>;
+}
+
+// 形状の表面とレイの交点
+#[derive(Copy, Clone, Debug)]
+pub struct Boundary {
+    pub distance: f64,
+    pub normal: Vector3,
+    pub geometric_normal: Vector3,
+    pub uv: Vector2,
+}
+
+impl Boundary {
+    // 区間が直線の端まで続く場合に使う
+    pub fn infinite(distance: f64) -> Boundary {
+        Boundary {
+            distance,
+            normal: Vector3::zero(),
+            geometric_normal: Vector3::zero(),
+            uv: Vector2::zero(),
+        }
+    }
+
+    pub fn flip(&self) -> Boundary {
+        Boundary {
+            normal: -self.normal,
+            geometric_normal: -self.geometric_normal,
+            ..*self
+        }
+    }
+}
+
+// レイが形状の内側にある区間。法線は常に形状の外側を向く
+#[derive(Copy, Clone, Debug)]
+pub struct Interval {
+    pub enter: Boundary,
+    pub exit: Boundary,
+}
+
+// 内側と外側が定まる閉じた形状。Csg の子要素に使う
+pub trait Solid: Intersectable {
+    // レイを直線とみなして（負の距離も含めて）、内側にある区間を距離の昇順に返す
+    fn intervals(&self, ray: &Ray) -> Vec<Interval>;
 }
 
 pub struct Sphere {
@@ -65,12 +149,10 @@ impl Intersectable for Sphere {
             intersection.position = ray.origin + ray.direction * t;
             intersection.distance = t;
             intersection.normal = (intersection.position - self.center).normalize();
-
-            intersection.uv.y = 1.0 - intersection.normal.y.acos() / config::PI;
-            intersection.uv.x = 0.5
-                - intersection.normal.z.signum()
-                * (intersection.normal.x / intersection.normal.xz().length()).acos()
-                / config::PI2;
+            intersection.geometric_normal = intersection.normal;
+            intersection.tangent = Vector3::new(intersection.normal.z, 0.0, -intersection.normal.x);// sphere_uv の u が増える方向
+            intersection.vertex_color = Color::one();
+            intersection.uv = sphere_uv(&intersection.normal);
             true
         } else {
             false
@@ -86,6 +168,8 @@ impl Intersectable for Sphere {
         }
     }
 
+    fn bounded(&self) -> bool { true }
+
     fn nee_available(&self) -> bool { true }
 
     // http://apollon.issp.u-tokyo.ac.jp/~watanabe/pdf/prob.pdf
@@ -99,9 +183,40 @@ impl Intersectable for Sphere {
         let pdf = (4.0 * config::PI * self.radius * self.radius).recip();
         Surface { position, normal, pdf }
     }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Sphere, &self.material);
+        description.center = Some(from_vector3(&self.center));
+        description.radius = Some(self.radius);
+        Some(description)
+    }
+}
+
+impl Solid for Sphere {
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let a: Vector3 = ray.origin - self.center;
+        let b = a.dot(&ray.direction);
+        let c = a.dot(&a) - self.radius * self.radius;
+        let d = b * b - c;
+        if d <= 0.0 {
+            return vec![];
+        }
+
+        let boundary = |t: f64| {
+            let normal = (ray.origin + ray.direction * t - self.center).normalize();
+            Boundary { distance: t, normal, geometric_normal: normal, uv: sphere_uv(&normal) }
+        };
+        vec![Interval { enter: boundary(-b - d.sqrt()), exit: boundary(-b + d.sqrt()) }]
+    }
+}
+
+fn sphere_uv(normal: &Vector3) -> Vector2 {
+    Vector2::new(
+        0.5 - normal.z.signum() * (normal.x / normal.xz().length()).acos() / config::PI2,
+        1.0 - normal.y.acos() / config::PI,
+    )
 }
 
-#[allow(dead_code)]
 pub struct Plane {
     pub center: Vector3,
     pub normal: Vector3,
@@ -116,10 +231,15 @@ impl Intersectable for Plane {
         if t > 0.0 && t < intersection.distance {
             intersection.position = ray.origin + ray.direction * t;
             intersection.normal = self.normal;
+            intersection.geometric_normal = self.normal;
+            intersection.vertex_color = Color::one();
             intersection.distance = t;
 
-            // normalがY軸なことを前提にUVを計算
-            intersection.uv = Vector2::new(modulo(intersection.position.x, 1.0), modulo(intersection.position.z, 1.0));
+            // 平面上の接ベクトルを軸にしてUVを計算（normal が Y 軸の場合は X, Z 軸になる）
+            let (tangent, bitangent) = tangent_frame(&self.normal);
+            let p = intersection.position - self.center;
+            intersection.uv = Vector2::new(modulo(p.dot(&tangent), 1.0), modulo(p.dot(&bitangent), 1.0));
+            intersection.tangent = tangent;
             true
         } else {
             false
@@ -136,11 +256,541 @@ impl Intersectable for Plane {
         }
     }
 
+    fn bounded(&self) -> bool { false }
+
+    fn nee_available(&self) -> bool { false }
+
+    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
+        unimplemented!()
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Plane, &self.material);
+        description.center = Some(from_vector3(&self.center));
+        description.normal = Some(from_vector3(&self.normal));
+        Some(description)
+    }
+}
+
+// 法線に垂直な (tangent, bitangent)
+// normal が Y 軸の場合は X, Z 軸になる
+fn tangent_frame(normal: &Vector3) -> (Vector3, Vector3) {
+    let up = if normal.z.abs() < 0.999 { Vector3::new(0.0, 0.0, 1.0) } else { Vector3::new(1.0, 0.0, 0.0) };
+    let tangent = normal.cross(&up).normalize();
+    let bitangent = tangent.cross(normal);
+    (tangent, bitangent)
+}
+
+// 法線が normal で半径 radius の円の、各軸方向の広がり（radius * sin(法線と軸のなす角)）
+fn disk_extent(normal: &Vector3, radius: f64) -> Vector3 {
+    Vector3::new(
+        (1.0 - normal.x * normal.x).max(0.0).sqrt(),
+        (1.0 - normal.y * normal.y).max(0.0).sqrt(),
+        (1.0 - normal.z * normal.z).max(0.0).sqrt(),
+    ) * radius
+}
+
+// 平行四辺形。corner から edge_u, edge_v の2辺で張る
+// 面光源として next event estimation に対応する
+pub struct Rectangle {
+    pub corner: Vector3,
+    pub edge_u: Vector3,
+    pub edge_v: Vector3,
+    pub material: Material,
+}
+
+impl Intersectable for Rectangle {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        let n = self.edge_u.cross(&self.edge_v);
+        let v = ray.direction.dot(&n);
+        if v == 0.0 {
+            return false;
+        }
+        let t = (self.corner - ray.origin).dot(&n) / v;
+        if t <= 0.0 || t >= intersection.distance {
+            return false;
+        }
+
+        // 交点を corner + u * edge_u + v * edge_v と表したときの (u, v)
+        let position = ray.origin + ray.direction * t;
+        let q = position - self.corner;
+        let w = n / n.dot(&n);
+        let uv = Vector2::new(w.dot(&q.cross(&self.edge_v)), w.dot(&self.edge_u.cross(&q)));
+        if uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0 {
+            return false;
+        }
+
+        intersection.position = position;
+        intersection.distance = t;
+        intersection.normal = n.normalize();
+        intersection.geometric_normal = intersection.normal;
+        intersection.tangent = self.edge_u;
+        intersection.vertex_color = Color::one();
+        intersection.uv = uv;
+        true
+    }
+
+    fn material(&self) -> &Material { &self.material }
+
+    fn aabb(&self) -> Aabb {
+        let mut aabb = Aabb::from_triangle(&self.corner, &(self.corner + self.edge_u), &(self.corner + self.edge_v));
+        let p = self.corner + self.edge_u + self.edge_v;
+        aabb.merge(&Aabb { min: p, max: p });
+        aabb
+    }
+
+    fn bounded(&self) -> bool { true }
+
+    fn nee_available(&self) -> bool { true }
+
+    fn sample_on_surface(&self, random: (f64, f64)) -> Surface {
+        let n = self.edge_u.cross(&self.edge_v);
+        let position = self.corner + self.edge_u * random.0 + self.edge_v * random.1;
+        let pdf = n.length().recip();
+        Surface { position, normal: n.normalize(), pdf }
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Rectangle, &self.material);
+        description.corner = Some(from_vector3(&self.corner));
+        description.edge_u = Some(from_vector3(&self.edge_u));
+        description.edge_v = Some(from_vector3(&self.edge_v));
+        Some(description)
+    }
+}
+
+// 円盤。面光源として next event estimation に対応する
+pub struct Disk {
+    pub center: Vector3,
+    pub normal: Vector3,
+    pub radius: f64,
+    pub material: Material,
+}
+
+impl Intersectable for Disk {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        let v = ray.direction.dot(&self.normal);
+        if v == 0.0 {
+            return false;
+        }
+        let t = (self.center - ray.origin).dot(&self.normal) / v;
+        if t <= 0.0 || t >= intersection.distance {
+            return false;
+        }
+
+        let position = ray.origin + ray.direction * t;
+        let p = position - self.center;
+        if p.norm() > self.radius * self.radius {
+            return false;
+        }
+
+        intersection.position = position;
+        intersection.distance = t;
+        intersection.normal = self.normal;
+        intersection.geometric_normal = self.normal;
+        intersection.vertex_color = Color::one();
+
+        // 外接する正方形にテクスチャを貼る
+        let (tangent, bitangent) = tangent_frame(&self.normal);
+        let scale = 0.5 / self.radius;
+        intersection.uv = Vector2::new(0.5 + p.dot(&tangent) * scale, 0.5 + p.dot(&bitangent) * scale);
+        intersection.tangent = tangent;
+        true
+    }
+
+    fn material(&self) -> &Material { &self.material }
+
+    fn aabb(&self) -> Aabb {
+        let extent = disk_extent(&self.normal, self.radius);
+        Aabb {
+            min: self.center - extent,
+            max: self.center + extent,
+        }
+    }
+
+    fn bounded(&self) -> bool { true }
+
+    fn nee_available(&self) -> bool { true }
+
+    fn sample_on_surface(&self, random: (f64, f64)) -> Surface {
+        let (tangent, bitangent) = tangent_frame(&self.normal);
+        let r = self.radius * random.0.sqrt();
+        let theta = config::PI2 * random.1;
+        let position = self.center + tangent * (r * theta.cos()) + bitangent * (r * theta.sin());
+        let pdf = (config::PI * self.radius * self.radius).recip();
+        Surface { position, normal: self.normal, pdf }
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Disk, &self.material);
+        description.center = Some(from_vector3(&self.center));
+        description.normal = Some(from_vector3(&self.normal));
+        description.radius = Some(self.radius);
+        Some(description)
+    }
+}
+
+// origin を原点、axis 方向を Y 軸とする正規直交座標系
+// Cylinder, Cone, Capsule, Torus はこの座標系で交差判定する
+struct LocalFrame {
+    origin: Vector3,
+    x: Vector3,
+    y: Vector3,
+    z: Vector3,
+}
+
+impl LocalFrame {
+    fn new(origin: Vector3, axis: &Vector3) -> LocalFrame {
+        let y = axis.normalize();
+        let (x, z) = tangent_frame(&y);
+        LocalFrame { origin, x, y, z }
+    }
+
+    fn to_local(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: self.local_direction(&(ray.origin - self.origin)),
+            direction: self.local_direction(&ray.direction),
+        }
+    }
+
+    fn local_direction(&self, v: &Vector3) -> Vector3 {
+        Vector3::new(v.dot(&self.x), v.dot(&self.y), v.dot(&self.z))
+    }
+
+    fn world_direction(&self, v: &Vector3) -> Vector3 {
+        self.x * v.x + self.y * v.y + self.z * v.z
+    }
+
+    // ローカル座標での交点の候補 (距離, 法線, UV) のうち、最も近いものを intersection に書き込む
+    fn set_nearest(&self, ray: &Ray, candidates: &[(f64, Vector3, Vector2)], intersection: &mut Intersection) -> bool {
+        let mut nearest: Option<&(f64, Vector3, Vector2)> = None;
+        for candidate in candidates {
+            if candidate.0 > 0.0 && candidate.0 < intersection.distance && nearest.map_or(true, |n| candidate.0 < n.0) {
+                nearest = Some(candidate);
+            }
+        }
+
+        if let Some(&(t, normal, uv)) = nearest {
+            intersection.position = ray.origin + ray.direction * t;
+            intersection.distance = t;
+            intersection.normal = self.world_direction(&normal).normalize();
+            intersection.geometric_normal = intersection.normal;
+            intersection.tangent = Vector3::zero();
+            intersection.vertex_color = Color::one();
+            intersection.uv = uv;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Y 軸まわりの角度を 0-1 にした U 座標
+fn azimuth_u(p: &Vector3) -> f64 {
+    0.5 + p.z.atan2(p.x) / config::PI2
+}
+
+// 半径 radius の Y 軸まわりの無限円柱との交点の距離
+fn intersect_infinite_cylinder(ray: &Ray, radius: f64) -> Vec<f64> {
+    let a = ray.direction.x * ray.direction.x + ray.direction.z * ray.direction.z;
+    let b = ray.origin.x * ray.direction.x + ray.origin.z * ray.direction.z;
+    let c = ray.origin.x * ray.origin.x + ray.origin.z * ray.origin.z - radius * radius;
+    let d = b * b - a * c;
+    if a == 0.0 || d < 0.0 {
+        return vec![];
+    }
+    let d = d.sqrt();
+    vec![(-b - d) / a, (-b + d) / a]
+}
+
+// Y = y の平面上の半径 radius の円盤との交点の候補
+fn intersect_cap(ray: &Ray, y: f64, radius: f64, normal_y: f64) -> Option<(f64, Vector3, Vector2)> {
+    if ray.direction.y == 0.0 {
+        return None;
+    }
+    let t = (y - ray.origin.y) / ray.direction.y;
+    let p = ray.origin + ray.direction * t;
+    if p.x * p.x + p.z * p.z > radius * radius {
+        return None;
+    }
+    let uv = Vector2::new(0.5 + 0.5 * p.x / radius, 0.5 + 0.5 * p.z / radius);
+    Some((t, Vector3::new(0.0, normal_y, 0.0), uv))
+}
+
+// bottom から top までの円柱（両端の蓋を含む）
+pub struct Cylinder {
+    pub bottom: Vector3,
+    pub top: Vector3,
+    pub radius: f64,
+    pub material: Material,
+}
+
+impl Intersectable for Cylinder {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        let axis = self.top - self.bottom;
+        let height = axis.length();
+        let frame = LocalFrame::new(self.bottom, &axis);
+        let local = frame.to_local(ray);
+
+        let mut candidates = vec![];
+        for t in intersect_infinite_cylinder(&local, self.radius) {
+            let p = local.origin + local.direction * t;
+            if p.y >= 0.0 && p.y <= height {
+                candidates.push((t, Vector3::new(p.x, 0.0, p.z), Vector2::new(azimuth_u(&p), p.y / height)));
+            }
+        }
+        candidates.extend(intersect_cap(&local, 0.0, self.radius, -1.0));
+        candidates.extend(intersect_cap(&local, height, self.radius, 1.0));
+        frame.set_nearest(ray, &candidates, intersection)
+    }
+
+    fn material(&self) -> &Material { &self.material }
+
+    fn aabb(&self) -> Aabb {
+        let extent = disk_extent(&(self.top - self.bottom).normalize(), self.radius);
+        let mut aabb = Aabb { min: self.bottom - extent, max: self.bottom + extent };
+        aabb.merge(&Aabb { min: self.top - extent, max: self.top + extent });
+        aabb
+    }
+
+    fn bounded(&self) -> bool { true }
+
+    fn nee_available(&self) -> bool { false }
+
+    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
+        unimplemented!()
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Cylinder, &self.material);
+        description.bottom = Some(from_vector3(&self.bottom));
+        description.top = Some(from_vector3(&self.top));
+        description.radius = Some(self.radius);
+        Some(description)
+    }
+}
+
+// 底面の中心が bottom で半径 radius、頂点が top の円錐（底面の蓋を含む）
+pub struct Cone {
+    pub bottom: Vector3,
+    pub top: Vector3,
+    pub radius: f64,
+    pub material: Material,
+}
+
+impl Intersectable for Cone {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        let axis = self.top - self.bottom;
+        let height = axis.length();
+        let frame = LocalFrame::new(self.bottom, &axis);
+        let local = frame.to_local(ray);
+        let o = local.origin;
+        let d = local.direction;
+
+        // x^2 + z^2 = (k (height - y))^2
+        let k2 = (self.radius / height) * (self.radius / height);
+        let h = height - o.y;
+        let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+        let b = o.x * d.x + o.z * d.z + k2 * h * d.y;
+        let c = o.x * o.x + o.z * o.z - k2 * h * h;
+        let ts = if a.abs() < config::EPS * config::EPS {
+            if b == 0.0 { vec![] } else { vec![-0.5 * c / b] }
+        } else {
+            let discriminant = b * b - a * c;
+            if discriminant < 0.0 { vec![] } else { vec![(-b - discriminant.sqrt()) / a, (-b + discriminant.sqrt()) / a] }
+        };
+
+        let mut candidates = vec![];
+        for t in ts {
+            let p = o + d * t;
+            if p.y >= 0.0 && p.y <= height {
+                let normal = Vector3::new(p.x, k2 * (height - p.y), p.z);
+                candidates.push((t, normal, Vector2::new(azimuth_u(&p), p.y / height)));
+            }
+        }
+        candidates.extend(intersect_cap(&local, 0.0, self.radius, -1.0));
+        frame.set_nearest(ray, &candidates, intersection)
+    }
+
+    fn material(&self) -> &Material { &self.material }
+
+    fn aabb(&self) -> Aabb {
+        let extent = disk_extent(&(self.top - self.bottom).normalize(), self.radius);
+        let mut aabb = Aabb { min: self.bottom - extent, max: self.bottom + extent };
+        aabb.merge(&Aabb { min: self.top, max: self.top });
+        aabb
+    }
+
+    fn bounded(&self) -> bool { true }
+
+    fn nee_available(&self) -> bool { false }
+
+    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
+        unimplemented!()
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Cone, &self.material);
+        description.bottom = Some(from_vector3(&self.bottom));
+        description.top = Some(from_vector3(&self.top));
+        description.radius = Some(self.radius);
+        Some(description)
+    }
+}
+
+// bottom から top までの線分からの距離が radius の点の集合（円柱の両端が半球）
+pub struct Capsule {
+    pub bottom: Vector3,
+    pub top: Vector3,
+    pub radius: f64,
+    pub material: Material,
+}
+
+impl Intersectable for Capsule {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        let axis = self.top - self.bottom;
+        let height = axis.length();
+        let frame = LocalFrame::new(self.bottom, &axis);
+        let local = frame.to_local(ray);
+
+        // V 座標は下の半球の底から上の半球の頂上までを 0-1 にする
+        let v_scale = (height + 2.0 * self.radius).recip();
+        let mut candidates = vec![];
+        for t in intersect_infinite_cylinder(&local, self.radius) {
+            let p = local.origin + local.direction * t;
+            if p.y >= 0.0 && p.y <= height {
+                candidates.push((t, Vector3::new(p.x, 0.0, p.z), Vector2::new(azimuth_u(&p), (p.y + self.radius) * v_scale)));
+            }
+        }
+
+        // 両端の半球
+        for &(center_y, sign) in &[(0.0, -1.0), (height, 1.0)] {
+            let center = Vector3::new(0.0, center_y, 0.0);
+            let oc = local.origin - center;
+            let b = oc.dot(&local.direction);
+            let c = oc.dot(&oc) - self.radius * self.radius;
+            let discriminant = b * b - c;
+            if discriminant < 0.0 {
+                continue;
+            }
+            for &t in &[-b - discriminant.sqrt(), -b + discriminant.sqrt()] {
+                let p = local.origin + local.direction * t;
+                if (p.y - center_y) * sign >= 0.0 {
+                    candidates.push((t, p - center, Vector2::new(azimuth_u(&p), (p.y + self.radius) * v_scale)));
+                }
+            }
+        }
+        frame.set_nearest(ray, &candidates, intersection)
+    }
+
+    fn material(&self) -> &Material { &self.material }
+
+    fn aabb(&self) -> Aabb {
+        let extent = Vector3::from_one(self.radius);
+        let mut aabb = Aabb { min: self.bottom - extent, max: self.bottom + extent };
+        aabb.merge(&Aabb { min: self.top - extent, max: self.top + extent });
+        aabb
+    }
+
+    fn bounded(&self) -> bool { true }
+
+    fn nee_available(&self) -> bool { false }
+
+    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
+        unimplemented!()
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Capsule, &self.material);
+        description.bottom = Some(from_vector3(&self.bottom));
+        description.top = Some(from_vector3(&self.top));
+        description.radius = Some(self.radius);
+        Some(description)
+    }
+}
+
+// 中心が center で normal まわりに回転したトーラス
+// radius は中心から管の中心までの半径、minor_radius は管の半径
+pub struct Torus {
+    pub center: Vector3,
+    pub normal: Vector3,
+    pub radius: f64,
+    pub minor_radius: f64,
+    pub material: Material,
+}
+
+impl Intersectable for Torus {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        let frame = LocalFrame::new(self.center, &self.normal);
+        let local = frame.to_local(ray);
+        let r2 = self.radius * self.radius;
+        let minor_r2 = self.minor_radius * self.minor_radius;
+
+        // 外接球と交差する区間だけを調べる
+        let bounding = self.radius + self.minor_radius;
+        let b = local.origin.dot(&local.direction);
+        let c = local.origin.dot(&local.origin) - bounding * bounding;
+        let discriminant = b * b - c;
+        if discriminant < 0.0 {
+            return false;
+        }
+        let t_min = (-b - discriminant.sqrt()).max(0.0);
+        let t_max = (-b + discriminant.sqrt()).min(intersection.distance);
+        if t_min >= t_max {
+            return false;
+        }
+
+        // 桁落ちを防ぐために、外接球との交点を原点にして4次方程式を解く
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2)
+        let o = local.origin + local.direction * t_min;
+        let d = local.direction;
+        let n = o.dot(&d);
+        let g = o.dot(&o) + r2 - minor_r2;
+        let coefficients = [
+            g * g - 4.0 * r2 * (o.x * o.x + o.z * o.z),
+            4.0 * n * g - 8.0 * r2 * (o.x * d.x + o.z * d.z),
+            4.0 * n * n + 2.0 * g - 4.0 * r2 * (d.x * d.x + d.z * d.z),
+            4.0 * n,
+            1.0,
+        ];
+
+        let mut candidates = vec![];
+        if let Some(s) = solve_polynomial(&coefficients, 0.0, t_max - t_min).first() {
+            let t = t_min + s;
+            let p = local.origin + local.direction * t;
+            let normal = p * (p.dot(&p) + r2 - minor_r2) - Vector3::new(p.x, 0.0, p.z) * (2.0 * r2);
+            let v = 0.5 + p.y.atan2(p.xz().length() - self.radius) / config::PI2;
+            candidates.push((t, normal, Vector2::new(azimuth_u(&p), v)));
+        }
+        frame.set_nearest(ray, &candidates, intersection)
+    }
+
+    fn material(&self) -> &Material { &self.material }
+
+    fn aabb(&self) -> Aabb {
+        let extent = disk_extent(&self.normal.normalize(), self.radius) + Vector3::from_one(self.minor_radius);
+        Aabb {
+            min: self.center - extent,
+            max: self.center + extent,
+        }
+    }
+
+    fn bounded(&self) -> bool { true }
+
     fn nee_available(&self) -> bool { false }
 
     fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
         unimplemented!()
     }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Torus, &self.material);
+        description.center = Some(from_vector3(&self.center));
+        description.normal = Some(from_vector3(&self.normal));
+        description.radius = Some(self.radius);
+        description.minor_radius = Some(self.minor_radius);
+        Some(description)
+    }
 }
 
 pub struct Cuboid {
@@ -148,34 +798,57 @@ pub struct Cuboid {
     pub material: Material,
 }
 
+impl Cuboid {
+    // 表面上の点の法線と UV を求める
+    // 高速化のためにY軸から先に判定する
+    fn face(&self, position: &Vector3) -> Option<(Vector3, Vector2)> {
+        let uvw = (*position - self.aabb.min) / (self.aabb.max - self.aabb.min);
+        if equals_eps(position.y, self.aabb.max.y) {
+            Some((Vector3::new(0.0, 1.0, 0.0), uvw.xiz()))
+        } else if equals_eps(position.y, self.aabb.min.y) {
+            Some((Vector3::new(0.0, -1.0, 0.0), uvw.xiz()))
+        } else if equals_eps(position.x, self.aabb.min.x) {
+            Some((Vector3::new(-1.0, 0.0, 0.0), uvw.zy()))
+        } else if equals_eps(position.x, self.aabb.max.x) {
+            Some((Vector3::new(1.0, 0.0, 0.0), uvw.zy()))
+        } else if equals_eps(position.z, self.aabb.min.z) {
+            Some((Vector3::new(0.0, 0.0, -1.0), uvw.xy()))
+        } else if equals_eps(position.z, self.aabb.max.z) {
+            Some((Vector3::new(0.0, 0.0, 1.0), uvw.xy()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Solid for Cuboid {
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let (t_min, t_max) = self.aabb.ray_interval(ray);
+        if !(t_min < t_max) {
+            return vec![];
+        }
+
+        let boundary = |t: f64| {
+            let (normal, uv) = self.face(&(ray.origin + ray.direction * t)).unwrap_or((Vector3::zero(), Vector2::zero()));
+            Boundary { distance: t, normal, geometric_normal: normal, uv }
+        };
+        vec![Interval { enter: boundary(t_min), exit: boundary(t_max) }]
+    }
+}
+
 impl Intersectable for Cuboid {
     fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
         let (hit, distance) = self.aabb.intersect_ray(ray);
         if hit && distance < intersection.distance {
             intersection.position = ray.origin + ray.direction * distance;
             intersection.distance = distance;
-            let uvw = (intersection.position - self.aabb.min) / (self.aabb.max - self.aabb.min);
-            // 交点座標から法線を求める
-            // 高速化のためにY軸から先に判定する
-            if equals_eps(intersection.position.y, self.aabb.max.y) {
-                intersection.normal = Vector3::new(0.0, 1.0, 0.0);
-                intersection.uv = uvw.xiz();
-            } else if equals_eps(intersection.position.y, self.aabb.min.y) {
-                intersection.normal = Vector3::new(0.0, -1.0, 0.0);
-                intersection.uv = uvw.xiz();
-            } else if equals_eps(intersection.position.x, self.aabb.min.x) {
-                intersection.normal = Vector3::new(-1.0, 0.0, 0.0);
-                intersection.uv = uvw.zy();
-            } else if equals_eps(intersection.position.x, self.aabb.max.x) {
-                intersection.normal = Vector3::new(1.0, 0.0, 0.0);
-                intersection.uv = uvw.zy();
-            } else if equals_eps(intersection.position.z, self.aabb.min.z) {
-                intersection.normal = Vector3::new(0.0, 0.0, -1.0);
-                intersection.uv = uvw.xy();
-            } else if equals_eps(intersection.position.z, self.aabb.max.z) {
-                intersection.normal = Vector3::new(0.0, 0.0, 1.0);
-                intersection.uv = uvw.xy();
+            if let Some((normal, uv)) = self.face(&intersection.position) {
+                intersection.normal = normal;
+                intersection.uv = uv;
             }
+            intersection.geometric_normal = intersection.normal;
+            intersection.tangent = Vector3::zero();
+            intersection.vertex_color = Color::one();
             true
         } else {
             false
@@ -186,23 +859,383 @@ impl Intersectable for Cuboid {
 
     fn aabb(&self) -> Aabb { self.aabb.clone() }
 
+    fn bounded(&self) -> bool { true }
+
     fn nee_available(&self) -> bool { false }
 
     fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
         unimplemented!()
     }
+
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = ElementDescription::new(ElementType::Cuboid, &self.material);
+        description.min = Some(from_vector3(&self.aabb.min));
+        description.max = Some(from_vector3(&self.aabb.max));
+        Some(description)
+    }
+}
+
+// 任意の要素を行列で変換する（回転・拡大縮小した球や直方体など）
+// レイをオブジェクト座標に変換して交差判定し、法線は逆転置行列でワールド座標に戻す
+pub struct Transformed<T: Intersectable> {
+    pub object: T,
+    pub matrix: Matrix44,
+    inverse: Matrix44,
+    normal_matrix: Matrix44,
+}
+
+impl<T: Intersectable> Transformed<T> {
+    pub fn new(object: T, matrix: Matrix44) -> Transformed<T> {
+        let inverse = matrix.inverse();
+        Transformed {
+            object,
+            matrix,
+            inverse,
+            normal_matrix: inverse.transpose(),
+        }
+    }
+}
+
+impl<T: Intersectable> Intersectable for Transformed<T> {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        // Sphere などは方向ベクトルが正規化されていることを前提にしているので、
+        // 正規化したうえで距離をオブジェクト座標の長さに換算する
+        let direction = self.inverse.transform_direction(&ray.direction);
+        let scale = direction.length();
+        let local_ray = Ray {
+            origin: self.inverse * ray.origin,
+            direction: direction / scale,
+        };
+        let distance = intersection.distance;
+        intersection.distance = distance * scale;
+        if self.object.intersect(&local_ray, intersection) {
+            intersection.distance /= scale;
+            intersection.position = ray.origin + ray.direction * intersection.distance;
+            intersection.normal = self.normal_matrix.transform_direction(&intersection.normal).normalize();
+            intersection.geometric_normal = self.normal_matrix.transform_direction(&intersection.geometric_normal).normalize();
+            intersection.tangent = self.matrix.transform_direction(&intersection.tangent);
+            true
+        } else {
+            intersection.distance = distance;
+            false
+        }
+    }
+
+    fn material(&self) -> &Material { self.object.material() }
+
+    fn aabb(&self) -> Aabb { self.object.aabb().transform(&self.matrix) }
+
+    fn bounded(&self) -> bool { self.object.bounded() }
+
+    fn nee_available(&self) -> bool { self.object.nee_available() }
+
+    fn sample_on_surface(&self, random: (f64, f64)) -> Surface {
+        self.object.sample_on_surface(random).transform(&self.matrix, &self.normal_matrix)
+    }
+
+    // transform は左から順に乗算されるので、入れ子の場合は外側の行列を先頭に追加する
+    fn description(&self) -> Option<ElementDescription> {
+        let mut description = self.object.description()?;
+        let mut transform = vec![TransformDescription::from_matrix(&self.matrix)];
+        transform.extend(description.transform.take().into_iter().flat_map(|t| t));
+        description.transform = Some(transform);
+        Some(description)
+    }
+}
+
+impl<T: Solid> Solid for Transformed<T> {
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let direction = self.inverse.transform_direction(&ray.direction);
+        let scale = direction.length();
+        let local_ray = Ray {
+            origin: self.inverse * ray.origin,
+            direction: direction / scale,
+        };
+        let transform = |b: Boundary| Boundary {
+            distance: b.distance / scale,
+            normal: self.normal_matrix.transform_direction(&b.normal).normalize(),
+            geometric_normal: self.normal_matrix.transform_direction(&b.geometric_normal).normalize(),
+            uv: b.uv,
+        };
+        self.object.intervals(&local_ray).into_iter()
+            .map(|interval| Interval { enter: transform(interval.enter), exit: transform(interval.exit) })
+            .collect()
+    }
 }
 
 pub struct Face {
     pub v0: usize,
     pub v1: usize,
     pub v2: usize,
+
+    // Mesh.normals, Mesh.uvs のインデックス（OBJ に vn, vt が無い面は None）
+    pub normal_indexes: Option<[usize; 3]>,
+    pub uv_indexes: Option<[usize; 3]>,
+
+    // 多角形を扇状に三角形分割した2枚目以降の面なら true（v0, v1 と直前の面の v0, v2 が同じ）
+    // Catmull-Clark 分割で元の多角形を復元するために使う
+    pub fan: bool,
+}
+
+impl Face {
+    pub fn new(v0: usize, v1: usize, v2: usize) -> Face {
+        Face {
+            v0,
+            v1,
+            v2,
+            normal_indexes: None,
+            uv_indexes: None,
+            fan: false,
+        }
+    }
+}
+
+// メッシュの読み込み元
+// シーンファイルに書き出すときに、頂点を展開せずにファイルパスと変換行列で記述するために使う
+#[derive(Clone, Debug)]
+pub struct MeshSource {
+    pub path: String,
+    pub matrix: Matrix44,
+
+    // OBJ の o, g で分割したグループの名前（ファイル全体を1つのメッシュとして読み込んだ場合は None）
+    pub group: Option<String>,
+
+    // OBJ ファイルのすべてのグループを1つのメッシュとして読み込んだかどうか（ObjLoader::load）
+    // 書き出さないと、読み込み直したときにグループごとに分割されてしまう
+    pub merge_groups: bool,
+
+    // OBJ の usemtl で分割したマテリアルの名前
+    pub usemtl: Option<String>,
+
+    // MTL ファイルのマテリアルを使ったかどうか
+    pub import_mtl: bool,
+
+    // 読み込んだ後に適用した細分割と変位（細分割が先）
+    pub subdivision: Option<Subdivision>,
+    pub displacement: Option<Displacement>,
 }
 
 pub struct Mesh {
     pub vertexes: Vec<Vector3>,
+    pub normals: Vec<Vector3>,
+    pub uvs: Vec<Vector2>,
+
+    // 頂点カラー（vertexes と同じ順番。無い場合は空）
+    pub colors: Vec<Color>,
+
     pub faces: Vec<Face>,
     pub material: Material,
+    pub source: Option<MeshSource>,
+
+    // 頂点法線が無い面を、BvhMesh::from_mesh で計算した頂点法線で滑らかにするかどうか（false なら面の法線のまま）
+    pub smooth: bool,
+
+    // UV（OBJ の vt）が無い面に、BvhMesh::from_mesh で投影した UV を使うかどうか（false なら交点の重心座標のまま）
+    pub project_uvs: bool,
+}
+
+impl Mesh {
+    // 頂点法線が無い面について、頂点を共有する面の法線を角度で重み付けして平均する
+    // 面同士の角度が config::SMOOTHING_ANGLE より大きい辺は平滑化しない
+    // 同じ頂点で同じ面の組み合わせから求めた法線は1つにまとめる（折り目が無ければ頂点ごとに1つになる）
+    pub fn compute_normals(&mut self) {
+        let cos_threshold = config::SMOOTHING_ANGLE.to_radians().cos();
+
+        // 面の法線と、各頂点での内角
+        let mut face_normals = vec![];
+        let mut corner_angles = vec![];
+        for face in &self.faces {
+            let (v0, v1, v2) = (self.vertexes[face.v0], self.vertexes[face.v1], self.vertexes[face.v2]);
+            let cross = (v1 - v0).cross(&(v2 - v0));
+            face_normals.push(if cross == Vector3::zero() { None } else { Some(cross.normalize()) });
+            corner_angles.push([angle_between(v1 - v0, v2 - v0), angle_between(v2 - v1, v0 - v1), angle_between(v0 - v2, v1 - v2)]);
+        }
+
+        // 頂点ごとに、その頂点を共有する (面, 面の中での頂点番号)
+        let mut vertex_faces = vec![vec![]; self.vertexes.len()];
+        for (i, face) in self.faces.iter().enumerate() {
+            vertex_faces[face.v0].push((i, 0));
+            vertex_faces[face.v1].push((i, 1));
+            vertex_faces[face.v2].push((i, 2));
+        }
+
+        // 頂点ごとに、求めた法線と Mesh.normals でのインデックス
+        let mut vertex_normals: Vec<Vec<(Vector3, usize)>> = vec![vec![]; self.vertexes.len()];
+
+        for i in 0..self.faces.len() {
+            if self.faces[i].normal_indexes.is_some() {
+                continue;
+            }
+            let face_normal = match face_normals[i] {
+                Some(n) => n,
+                None => continue,// 縮退した面は面の法線のままにする
+            };
+
+            let mut indexes = [0; 3];
+            for (k, vertex) in [self.faces[i].v0, self.faces[i].v1, self.faces[i].v2].iter().enumerate() {
+                let mut normal = Vector3::zero();
+                for &(j, corner) in &vertex_faces[*vertex] {
+                    if let Some(n) = face_normals[j] {
+                        if n.dot(&face_normal) >= cos_threshold {
+                            normal += n * corner_angles[j][corner];
+                        }
+                    }
+                }
+                let normal = normal.normalize();
+                indexes[k] = match vertex_normals[*vertex].iter().find(|&&(n, _)| n == normal) {
+                    Some(&(_, index)) => index,
+                    None => {
+                        self.normals.push(normal);
+                        vertex_normals[*vertex].push((normal, self.normals.len() - 1));
+                        self.normals.len() - 1
+                    }
+                };
+            }
+            self.faces[i].normal_indexes = Some(indexes);
+        }
+    }
+
+    // 面の面積の累積和（最後の要素が表面積）
+    pub fn area_cdf(&self) -> Vec<f64> {
+        let mut sum = 0.0;
+        self.faces.iter()
+            .map(|face| {
+                let edge1 = self.vertexes[face.v1] - self.vertexes[face.v0];
+                let edge2 = self.vertexes[face.v2] - self.vertexes[face.v0];
+                sum += 0.5 * edge1.cross(&edge2).length();
+                sum
+            })
+            .collect()
+    }
+
+    // 面積に比例して面を選び、面上で一様に点をサンプリングする（pdf は表面積の逆数）
+    pub fn sample_on_surface_with_cdf(&self, area_cdf: &Vec<f64>, random: (f64, f64)) -> Surface {
+        let area = area_cdf[area_cdf.len() - 1];
+        let r = random.0 * area;
+
+        // r < area_cdf[i] を満たす最小の i を二分探索する（面積 0 の面は選ばれない）
+        let mut low = 0;
+        let mut high = area_cdf.len() - 1;
+        while low < high {
+            let mid = (low + high) / 2;
+            if area_cdf[mid] <= r { low = mid + 1; } else { high = mid; }
+        }
+
+        // 面を選んだ残りの乱数を面上のサンプリングに再利用する
+        let prev = if low == 0 { 0.0 } else { area_cdf[low - 1] };
+        let u = ((r - prev) / (area_cdf[low] - prev)).min(1.0);
+
+        let face = &self.faces[low];
+        let v0 = self.vertexes[face.v0];
+        let v1 = self.vertexes[face.v1];
+        let v2 = self.vertexes[face.v2];
+        let su = u.sqrt();
+        let b0 = 1.0 - su;
+        let b1 = random.1 * su;
+        let position = v0 * b0 + v1 * b1 + v2 * (1.0 - b0 - b1);
+        let normal = (v1 - v0).cross(&(v2 - v0)).normalize();
+        Surface { position, normal, pdf: area.recip() }
+    }
+
+    // UV が無い面について、メッシュの AABB に対して Cuboid と同じ向きで投影した UV を求める
+    // 投影する軸は面の法線の成分が最も大きい軸にする。同じ頂点を同じ軸で投影した UV は1つにまとめる
+    pub fn compute_uvs(&mut self) {
+        if self.faces.iter().all(|face| face.uv_indexes.is_some()) {
+            return;
+        }
+
+        let mut min = Vector3::from_one(config::INF);
+        let mut max = Vector3::from_one(-config::INF);
+        for v in &self.vertexes {
+            min = Vector3::new(min.x.min(v.x), min.y.min(v.y), min.z.min(v.z));
+            max = Vector3::new(max.x.max(v.x), max.y.max(v.y), max.z.max(v.z));
+        }
+        // 厚みが 0 の軸で 0 除算しないようにする
+        let size = Vector3::new((max.x - min.x).max(config::EPS), (max.y - min.y).max(config::EPS), (max.z - min.z).max(config::EPS));
+
+        // 頂点ごとに、Y, X, Z 軸で投影した UV の Mesh.uvs でのインデックス
+        let mut vertex_uvs = vec![[None; 3]; self.vertexes.len()];
+        for i in 0..self.faces.len() {
+            if self.faces[i].uv_indexes.is_some() {
+                continue;
+            }
+
+            let corners = [self.faces[i].v0, self.faces[i].v1, self.faces[i].v2];
+            let vertexes = [self.vertexes[corners[0]], self.vertexes[corners[1]], self.vertexes[corners[2]]];
+            let n = (vertexes[1] - vertexes[0]).cross(&(vertexes[2] - vertexes[0]));
+            let (ax, ay, az) = (n.x.abs(), n.y.abs(), n.z.abs());
+            let axis = if ay >= ax && ay >= az { 0 } else if ax >= az { 1 } else { 2 };
+
+            let mut indexes = [0; 3];
+            for k in 0..3 {
+                if let Some(index) = vertex_uvs[corners[k]][axis] {
+                    indexes[k] = index;
+                    continue;
+                }
+                let uvw = (vertexes[k] - min) / size;
+                self.uvs.push(match axis {
+                    0 => uvw.xiz(),
+                    1 => uvw.zy(),
+                    _ => uvw.xy(),
+                });
+                indexes[k] = self.uvs.len() - 1;
+                vertex_uvs[corners[k]][axis] = Some(indexes[k]);
+            }
+            self.faces[i].uv_indexes = Some(indexes);
+        }
+    }
+
+    // intersect_polygon が設定した重心座標から、頂点法線と UV と頂点カラーを補間する
+    pub fn interpolate_vertex_attributes(&self, face: &Face, intersection: &mut Intersection) {
+        let (u, v) = (intersection.uv.x, intersection.uv.y);
+        intersection.vertex_color = if self.colors.is_empty() {
+            Color::one()
+        } else {
+            self.colors[face.v0] * (1.0 - u - v) + self.colors[face.v1] * u + self.colors[face.v2] * v
+        };
+        if let Some(indexes) = face.normal_indexes {
+            let normal = self.normals[indexes[0]] * (1.0 - u - v) + self.normals[indexes[1]] * u + self.normals[indexes[2]] * v;
+            intersection.normal = normal.normalize();
+        }
+        intersection.tangent = Vector3::zero();
+        if let Some(indexes) = face.uv_indexes {
+            intersection.uv = self.uvs[indexes[0]] * (1.0 - u - v) + self.uvs[indexes[1]] * u + self.uvs[indexes[2]] * v;
+
+            // 面の辺を UV の差で表して、u 方向の微分 dp/du を求める
+            let (uv0, uv1, uv2) = (self.uvs[indexes[0]], self.uvs[indexes[1]], self.uvs[indexes[2]]);
+            let (du1, dv1) = (uv1.x - uv0.x, uv1.y - uv0.y);
+            let (du2, dv2) = (uv2.x - uv0.x, uv2.y - uv0.y);
+            let det = du1 * dv2 - du2 * dv1;
+            if det.abs() > config::EPS * config::EPS {
+                let edge1 = self.vertexes[face.v1] - self.vertexes[face.v0];
+                let edge2 = self.vertexes[face.v2] - self.vertexes[face.v0];
+                intersection.tangent = (edge1 * dv2 - edge2 * dv1) / det;
+            }
+        }
+    }
+
+    fn bvh_mesh_description(&self) -> ElementDescription {
+        let mut description = ElementDescription::new(ElementType::BvhMesh, &self.material);
+        if let Some(ref source) = self.source {
+            description.path = Some(unspanned(&source.path));
+            description.group = source.group.clone();
+            description.usemtl = source.usemtl.clone();
+            description.import_mtl = if source.import_mtl { Some(true) } else { None };
+            description.merge_groups = if source.merge_groups { Some(true) } else { None };
+            description.subdivision = source.subdivision.clone();
+            description.displacement = source.displacement.as_ref().map(DisplacementDescription::from_displacement);
+            description.transform = Some(vec![TransformDescription::from_matrix(&source.matrix)]);
+            if is_gltf_path(&source.path) {
+                description.material = None;
+            }
+        } else {
+            description.vertexes = Some(self.vertexes.iter().map(from_vector3).collect());
+            description.faces = Some(self.faces.iter().map(|f| [f.v0, f.v1, f.v2]).collect());
+        }
+        description.smooth = if self.smooth { Some(true) } else { None };
+        description.project_uvs = if self.project_uvs { Some(true) } else { None };
+        description
+    }
 }
 
 impl Intersectable for Mesh {
@@ -210,6 +1243,7 @@ impl Intersectable for Mesh {
         let mut any_hit = false;
         for face in &self.faces {
             if intersect_polygon(&self.vertexes[face.v0], &self.vertexes[face.v1], &self.vertexes[face.v2], ray, intersection) {
+                self.interpolate_vertex_attributes(face, intersection);
                 any_hit = true;
             }
         }
@@ -226,16 +1260,28 @@ impl Intersectable for Mesh {
         }
     }
 
+    fn bounded(&self) -> bool { true }
+
     fn nee_available(&self) -> bool { false }
 
     fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
         unimplemented!()
     }
+
+    fn description(&self) -> Option<ElementDescription> { None }
+}
+
+fn angle_between(a: Vector3, b: Vector3) -> f64 {
+    let cos = a.dot(&b) / (a.length() * b.length());
+    cos.max(-1.0).min(1.0).acos()
 }
 
 pub struct BvhMesh {
     pub mesh: Mesh,
     pub bvh: BvhNode,
+
+    // next event estimation で使う面積の累積和
+    pub area_cdf: Vec<f64>,
 }
 
 impl Intersectable for BvhMesh {
@@ -247,43 +1293,170 @@ impl Intersectable for BvhMesh {
 
     fn aabb(&self) -> Aabb { self.bvh.aabb.clone() }
 
-    fn nee_available(&self) -> bool { false }
+    fn bounded(&self) -> bool { true }
 
-    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
-        unimplemented!()
+    fn nee_available(&self) -> bool {
+        self.area_cdf.last().map_or(false, |area| *area > 0.0)
+    }
+
+    fn sample_on_surface(&self, random: (f64, f64)) -> Surface {
+        self.mesh.sample_on_surface_with_cdf(&self.area_cdf, random)
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        Some(self.mesh.bvh_mesh_description())
+    }
+}
+
+// 閉じたメッシュとして、交点の数の偶奇で内側と外側を判定する
+// 面の向きは揃っていなくてもよい
+impl Solid for BvhMesh {
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let mut hits = vec![];
+        self.bvh.intersect_all_for_mesh(&self.mesh, ray, &mut hits);
+        hits.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
+
+        // 隣り合う三角形の辺を通ると同じ交点が2回見つかるので、1つにまとめる
+        let mut boundaries: Vec<Boundary> = vec![];
+        for hit in hits {
+            let boundary = Boundary { distance: hit.distance, normal: hit.normal, geometric_normal: hit.geometric_normal, uv: hit.uv };
+            let duplicated = boundaries.last().map_or(false, |last| {
+                (last.distance - boundary.distance).abs() < config::EPS * config::EPS
+                    && last.geometric_normal.dot(&ray.direction).signum() == boundary.geometric_normal.dot(&ray.direction).signum()
+            });
+            if !duplicated {
+                boundaries.push(boundary);
+            }
+        }
+
+        // 前方の交点が奇数個なら、レイの原点はメッシュの内側にある
+        let mut intervals = vec![];
+        let mut enter = if boundaries.len() % 2 == 1 { Some(Boundary::infinite(-config::INF)) } else { None };
+        for boundary in boundaries {
+            // 入る点の法線はレイと逆向き、出る点の法線はレイと同じ向きにする
+            let facing = boundary.geometric_normal.dot(&ray.direction);
+            match enter.take() {
+                None => enter = Some(if facing > 0.0 { boundary.flip() } else { boundary }),
+                Some(e) => intervals.push(Interval { enter: e, exit: if facing < 0.0 { boundary.flip() } else { boundary } }),
+            }
+        }
+        intervals
     }
 }
 
 impl BvhMesh {
-    pub fn from_mesh(mesh: Mesh) -> BvhMesh {
+    pub fn from_mesh(mut mesh: Mesh) -> BvhMesh {
+        if mesh.smooth {
+            mesh.compute_normals();
+        }
+        if mesh.project_uvs {
+            mesh.compute_uvs();
+        }
         let bvh = BvhNode::build_from_mesh(&mesh);
         //println!("bvh: {:?}", bvh);
+        let area_cdf = mesh.area_cdf();
         BvhMesh {
             bvh: bvh,
             mesh: mesh,
+            area_cdf,
+        }
+    }
+}
+
+// 共有した BvhMesh を、オブジェクト座標からワールド座標への行列で配置する
+// レイをオブジェクト座標に変換して交差判定するので、同じメッシュをいくつ配置しても BVH は1つで済む
+pub struct Instance {
+    pub mesh: Arc<BvhMesh>,
+    pub matrix: Matrix44,
+    inverse: Matrix44,
+    normal_matrix: Matrix44,
+    aabb: Aabb,
+}
+
+impl Instance {
+    pub fn new(mesh: Arc<BvhMesh>, matrix: Matrix44) -> Instance {
+        let inverse = matrix.inverse();
+
+        // 頂点を変換して AABB を求める（AABB の8つの頂点を変換するよりも小さくなる）
+        let mut aabb = Aabb {
+            min: Vector3::from_one(config::INF),
+            max: Vector3::from_one(-config::INF),
+        };
+        for v in &mesh.mesh.vertexes {
+            let p = matrix * *v;
+            aabb.merge(&Aabb { min: p, max: p });
+        }
+
+        Instance {
+            mesh,
+            matrix,
+            inverse,
+            normal_matrix: inverse.transpose(),
+            aabb,
         }
     }
 }
 
+impl Intersectable for Instance {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        // 方向ベクトルを正規化しないことで、オブジェクト座標とワールド座標で距離 t が共通になる
+        let local_ray = Ray {
+            origin: self.inverse * ray.origin,
+            direction: self.inverse.transform_direction(&ray.direction),
+        };
+        if self.mesh.intersect(&local_ray, intersection) {
+            intersection.position = ray.origin + ray.direction * intersection.distance;
+            intersection.normal = self.normal_matrix.transform_direction(&intersection.normal).normalize();
+            intersection.geometric_normal = self.normal_matrix.transform_direction(&intersection.geometric_normal).normalize();
+            intersection.tangent = self.matrix.transform_direction(&intersection.tangent);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn material(&self) -> &Material { self.mesh.material() }
+
+    fn aabb(&self) -> Aabb { self.aabb.clone() }
+
+    fn bounded(&self) -> bool { true }
+
+    fn nee_available(&self) -> bool { self.mesh.nee_available() }
+
+    fn sample_on_surface(&self, random: (f64, f64)) -> Surface {
+        self.mesh.sample_on_surface(random).transform(&self.matrix, &self.normal_matrix)
+    }
+
+    // メッシュの頂点に行列を適用していない場合だけ書き出せる
+    fn description(&self) -> Option<ElementDescription> {
+        match self.mesh.mesh.source {
+            Some(ref source) if source.matrix != Matrix44::identity() => return None,
+            _ => {}
+        }
+        let mut description = self.mesh.mesh.bvh_mesh_description();
+        description.kind = ElementType::Instance;
+        description.transform = Some(vec![TransformDescription::from_matrix(&self.matrix)]);
+        Some(description)
+    }
+}
+
 pub struct Skybox {
-    pub px_texture: ImageTexture,
-    pub nx_texture: ImageTexture,
-    pub py_texture: ImageTexture,
-    pub ny_texture: ImageTexture,
-    pub pz_texture: ImageTexture,
-    pub nz_texture: ImageTexture,
+    // None の場合は、全方向が intensity の一様な色になる
+    pub faces: Option<SkyboxFaces>,
     pub intensity: Vector3,
 }
 
 impl Skybox {
     pub fn new(px_path: &str, nx_path: &str, py_path: &str, ny_path: &str, pz_path: &str, nz_path: &str, intensity: &Vector3) -> Skybox {
         Skybox {
-            px_texture: ImageTexture::new(px_path),
-            nx_texture: ImageTexture::new(nx_path),
-            py_texture: ImageTexture::new(py_path),
-            ny_texture: ImageTexture::new(ny_path),
-            pz_texture: ImageTexture::new(pz_path),
-            nz_texture: ImageTexture::new(nz_path),
+            faces: Some(SkyboxFaces {
+                px_texture: ImageTexture::new(px_path),
+                nx_texture: ImageTexture::new(nx_path),
+                py_texture: ImageTexture::new(py_path),
+                ny_texture: ImageTexture::new(ny_path),
+                pz_texture: ImageTexture::new(pz_path),
+                nz_texture: ImageTexture::new(nz_path),
+            }),
             intensity: *intensity,
         }
     }
@@ -292,28 +1465,54 @@ impl Skybox {
         Skybox::new(px_path, nx_path, py_path, ny_path, pz_path, nz_path, &Vector3::one())
     }
 
+    pub fn constant(color: &Vector3) -> Skybox {
+        Skybox {
+            faces: None,
+            intensity: *color,
+        }
+    }
+
     pub fn sample(&self, direction: &Vector3) -> Vector3 {
+        match self.faces {
+            Some(ref faces) => self.intensity * faces.sample(direction),
+            None => self.intensity,
+        }
+    }
+}
+
+// キューブマップの6面
+pub struct SkyboxFaces {
+    pub px_texture: ImageTexture,
+    pub nx_texture: ImageTexture,
+    pub py_texture: ImageTexture,
+    pub ny_texture: ImageTexture,
+    pub pz_texture: ImageTexture,
+    pub nz_texture: ImageTexture,
+}
+
+impl SkyboxFaces {
+    fn sample(&self, direction: &Vector3) -> Vector3 {
         let abs_x = direction.x.abs();
         let abs_y = direction.y.abs();
         let abs_z = direction.z.abs();
 
         if abs_x > abs_y && abs_x > abs_z {
             if direction.x.is_sign_positive() {
-                self.intensity * self.px_texture.sample_bilinear_0center(-direction.z / direction.x, direction.y / direction.x)
+                self.px_texture.sample_bilinear_0center(-direction.z / direction.x, direction.y / direction.x)
             } else {
-                self.intensity * self.nx_texture.sample_bilinear_0center(-direction.z / direction.x, -direction.y / direction.x)
+                self.nx_texture.sample_bilinear_0center(-direction.z / direction.x, -direction.y / direction.x)
             }
         } else if abs_y > abs_x && abs_y > abs_z {
             if direction.y.is_sign_positive() {
-                self.intensity * self.py_texture.sample_bilinear_0center(direction.x / direction.y, -direction.z / direction.y)
+                self.py_texture.sample_bilinear_0center(direction.x / direction.y, -direction.z / direction.y)
             } else {
-                self.intensity * self.ny_texture.sample_bilinear_0center(-direction.x / direction.y, -direction.z / direction.y)
+                self.ny_texture.sample_bilinear_0center(-direction.x / direction.y, -direction.z / direction.y)
             }
         } else {
             if direction.z.is_sign_positive() {
-                self.intensity * self.pz_texture.sample_bilinear_0center(direction.x / direction.z, direction.y / direction.z)
+                self.pz_texture.sample_bilinear_0center(direction.x / direction.z, direction.y / direction.z)
             } else {
-                self.intensity * self.nz_texture.sample_bilinear_0center(direction.x / direction.z, -direction.y / direction.z)
+                self.nz_texture.sample_bilinear_0center(direction.x / direction.z, -direction.y / direction.z)
             }
         }
     }
@@ -342,10 +1541,11 @@ impl SceneTrait for Scene {
 
         if let Some(element) = nearest {
             let material = element.material();
-            intersection.material.surface = material.surface.clone();
-            intersection.material.albedo = material.albedo.sample(intersection.uv);
+            intersection.material.surface = material.surface_at(intersection.uv);
+            intersection.material.albedo = material.albedo.sample(intersection.uv) * intersection.vertex_color;
             intersection.material.emission = material.emission.sample(intersection.uv);
             intersection.material.roughness = material.roughness.sample(intersection.uv).x;
+            intersection.material.tangent = intersection.tangent;
             (true, intersection)
         } else {
             intersection.material.emission = self.skybox.sample(&ray.direction);
@@ -365,7 +1565,7 @@ impl Scene {
 
     pub fn add_with_check_collisions(&mut self, element: Box<Intersectable>) -> bool {
         let aabb = element.aabb();
-        let no_collisions = self.elements.iter().all(|ref e| !e.aabb().intersect_aabb(&aabb));
+        let no_collisions = self.elements.iter().all(|ref e| !e.bounded() || !e.aabb().intersect_aabb(&aabb));
         if no_collisions {
             self.elements.push(element);
             true
@@ -379,20 +1579,29 @@ impl Scene {
 pub struct BvhScene {
     pub scene: Scene,
     pub bvh: BvhNode,
+
+    // BVH に含めない無限に広がる要素のインデックス
+    pub unbounded_indexes: Vec<usize>,
 }
 
 impl SceneTrait for BvhScene {
     fn intersect(&self, ray: &Ray) -> (bool, Intersection) {
         let mut intersection = Intersection::empty();
-        let nearest_index = self.bvh.intersect_for_scene(&self.scene, ray, &mut intersection);
+        let mut nearest_index = self.bvh.intersect_for_scene(&self.scene, ray, &mut intersection);
+        for index in &self.unbounded_indexes {
+            if self.scene.elements[*index].intersect(ray, &mut intersection) {
+                nearest_index = Some(*index);
+            }
+        }
 
         if let Some(index) = nearest_index {
             let element = &self.scene.elements[index];
             let material = element.material();
-            intersection.material.surface = material.surface.clone();
-            intersection.material.albedo = material.albedo.sample(intersection.uv);
+            intersection.material.surface = material.surface_at(intersection.uv);
+            intersection.material.albedo = material.albedo.sample(intersection.uv) * intersection.vertex_color;
             intersection.material.emission = material.emission.sample(intersection.uv);
             intersection.material.roughness = material.roughness.sample(intersection.uv).x;
+            intersection.material.tangent = intersection.tangent;
             (true, intersection)
         } else {
             intersection.material.emission = self.scene.skybox.sample(&ray.direction);
@@ -408,9 +1617,121 @@ impl SceneTrait for BvhScene {
 impl BvhScene {
     pub fn from_scene(scene: Scene) -> BvhScene {
         let bvh = BvhNode::build_from_scene(&scene);
+        let unbounded_indexes = (0..scene.elements.len()).filter(|i| !scene.elements[*i].bounded()).collect();
         BvhScene {
             scene: scene,
             bvh: bvh,
+            unbounded_indexes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use texture::Texture;
+
+    fn mesh(vertexes: Vec<Vector3>, faces: Vec<Face>) -> Mesh {
+        Mesh {
+            vertexes,
+            normals: vec![],
+            uvs: vec![],
+            colors: vec![],
+            faces,
+            material: Material {
+                surface: SurfaceType::Diffuse,
+                albedo: Texture::white(),
+                emission: Texture::black(),
+                roughness: Texture::black(),
+            },
+            source: None,
+            smooth: true,
+            project_uvs: false,
+        }
+    }
+
+    #[test]
+    fn smooth_normals_are_shared_per_vertex() {
+        // 少しだけ折れた四角形なので、すべての頂点で両方の面の法線を平均する
+        let mut quad = mesh(
+            vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(1.0, 0.1, 1.0), Vector3::new(0.0, 0.0, 1.0)],
+            vec![Face::new(0, 2, 1), Face::new(0, 3, 2)],
+        );
+        quad.compute_normals();
+        assert_eq!(quad.normals.len(), 4);
+        assert_eq!(quad.faces[0].normal_indexes.unwrap()[0], quad.faces[1].normal_indexes.unwrap()[0]);
+        assert_eq!(quad.faces[0].normal_indexes.unwrap()[1], quad.faces[1].normal_indexes.unwrap()[2]);
+    }
+
+    #[test]
+    fn creases_keep_separate_normals() {
+        // 直角に折れた辺の両端では、面ごとに別の法線になる
+        let mut fold = mesh(
+            vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 1.0, 0.0)],
+            vec![Face::new(0, 2, 1), Face::new(0, 1, 3)],
+        );
+        fold.compute_normals();
+        assert_eq!(fold.normals.len(), 6);
+        assert_eq!(fold.normals[fold.faces[0].normal_indexes.unwrap()[0]], Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(fold.normals[fold.faces[1].normal_indexes.unwrap()[0]], Vector3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn projected_uvs_are_shared_per_vertex_and_axis() {
+        // 床の四角形は Y 軸で投影した4つの UV を共有し、壁の面は Z 軸で投影した UV を別に持つ
+        let mut box_corner = mesh(
+            vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 1.0), Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 1.0, 0.0)],
+            vec![Face::new(0, 2, 1), Face::new(0, 3, 2), Face::new(0, 1, 4)],
+        );
+        box_corner.compute_uvs();
+        assert_eq!(box_corner.uvs.len(), 7);
+        assert_eq!(box_corner.faces[0].uv_indexes.unwrap()[0], box_corner.faces[1].uv_indexes.unwrap()[0]);
+        let uv = box_corner.uvs[box_corner.faces[2].uv_indexes.unwrap()[2]];
+        assert_eq!((uv.x, uv.y), (0.0, 1.0));
+    }
+
+    #[test]
+    fn instance_samples_scaled_surface() {
+        // 面積1の床を (2, 3, 4) 倍すると面積は 2 * 4 = 8 になり、高さ方向の拡大は面積に効かない
+        let floor = Arc::new(BvhMesh::from_mesh(mesh(
+            vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 1.0), Vector3::new(0.0, 0.0, 1.0)],
+            vec![Face::new(0, 2, 1), Face::new(0, 3, 2)],
+        )));
+        let instance = Instance::new(floor, Matrix44::translate(0.0, 5.0, 0.0) * Matrix44::scale(2.0, 3.0, 4.0));
+        assert!(instance.nee_available());
+        for &random in &[(0.1, 0.2), (0.5, 0.5), (0.9, 0.7)] {
+            let surface = instance.sample_on_surface(random);
+            assert!(equals_eps(surface.pdf, 1.0 / 8.0));
+            assert!(equals_eps(surface.position.y, 5.0));
+            assert!(surface.position.x >= 0.0 && surface.position.x <= 2.0);
+            assert!(surface.position.z >= 0.0 && surface.position.z <= 4.0);
+            assert!(equals_eps(surface.normal.y.abs(), 1.0));
+        }
+    }
+
+    #[test]
+    fn transformed_sphere_samples_ellipsoid_surface() {
+        // 単位球を X 方向に2倍した楕円体。面積の拡大率は |det M| * |M^-T n| = 2 * |(n.x / 2, n.y, n.z)| なので、点ごとに pdf が変わる
+        let sphere = Sphere {
+            center: Vector3::zero(),
+            radius: 1.0,
+            material: Material {
+                surface: SurfaceType::Diffuse,
+                albedo: Texture::white(),
+                emission: Texture::white(),
+                roughness: Texture::black(),
+            },
+        };
+        let local_pdf = sphere.sample_on_surface((0.0, 0.0)).pdf;
+        let ellipsoid = Transformed::new(sphere, Matrix44::translate(0.0, 1.0, 0.0) * Matrix44::scale(2.0, 1.0, 1.0));
+        assert!(ellipsoid.nee_available());
+        for &random in &[(0.1, 0.2), (0.5, 0.5), (0.9, 0.7)] {
+            let surface = ellipsoid.sample_on_surface(random);
+            let p = surface.position - Vector3::new(0.0, 1.0, 0.0);
+            let n = Vector3::new(p.x / 2.0, p.y, p.z).normalize();
+            let area_scale = 2.0 * Vector3::new(n.x / 2.0, n.y, n.z).length();
+            assert!(equals_eps(surface.pdf, local_pdf / area_scale));
+            assert!(surface.normal.approximately(&Vector3::new(n.x / 2.0, n.y, n.z).normalize()));
         }
     }
 }
@@ -0,0 +1,609 @@
+extern crate toml;
+extern crate serde_path_to_error;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+use toml::Spanned;
+
+use vector::Vector3;
+use matrix::Matrix44;
+use scene::{Scene, Sphere, Plane, Rectangle, Disk, Cylinder, Cone, Capsule, Torus, Cuboid, Mesh, Face, BvhMesh, Instance, Transformed, Skybox, Intersectable, Solid};
+use bvh::Aabb;
+use sdf::Sdf;
+use csg::Csg;
+use heightfield::Heightfield;
+use subdivision::{Subdivision, subdivide, MAX_SUBDIVISION_LEVEL};
+use displacement::{Displacement, displace};
+use camera::Camera;
+use material::{Material, SurfaceType, Principled, PrincipledMaps};
+use texture::{Texture, ImageTexture};
+use loader::{ObjLoader, PlyLoader, StlLoader, MeshLoadError};
+use gltf_loader::{GltfLoader, is_gltf_path};
+use description::{SceneDescription, CameraDescription, SkyboxDescription, ElementDescription, ElementType,
+                  MaterialDescription, TextureDescription, DisplacementDescription, to_vector3, to_direction};
+
+#[derive(Debug)]
+pub enum SceneLoadError {
+    Io { path: String, error: io::Error },
+    Invalid { path: String, line: usize, key: String, message: String },
+    Mesh(MeshLoadError),
+}
+
+impl fmt::Display for SceneLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneLoadError::Io { ref path, ref error } => write!(f, "{}: {}", path, error),
+            SceneLoadError::Invalid { ref path, line, ref key, ref message } => {
+                if key.is_empty() {
+                    write!(f, "{}:{}: {}", path, line, message)
+                } else {
+                    write!(f, "{}:{}: {} for key `{}`", path, line, message, key)
+                }
+            }
+            SceneLoadError::Mesh(ref error) => write!(f, "{}", error),
+        }
+    }
+}
+
+pub struct SceneLoader {
+    path: String,
+    source: String,
+
+    // Instance で共有するメッシュ（transform 以外が同じ要素は同じメッシュを参照する）
+    instanced_meshes: RefCell<Vec<(InstanceKey, Vec<Arc<BvhMesh>>)>>,
+}
+
+#[derive(PartialEq)]
+struct InstanceKey {
+    path: Option<String>,
+    group: Option<String>,
+    merge_groups: Option<bool>,
+    usemtl: Option<String>,
+    import_mtl: Option<bool>,
+    smooth: Option<bool>,
+    project_uvs: Option<bool>,
+    vertexes: Option<Vec<[f64; 3]>>,
+    faces: Option<Vec<[usize; 3]>>,
+    subdivision: Option<Subdivision>,
+    displacement: Option<DisplacementDescription>,
+    material: Option<MaterialDescription>,
+}
+
+impl InstanceKey {
+    fn new(element: &ElementDescription) -> InstanceKey {
+        InstanceKey {
+            path: element.path.as_ref().map(|p| p.get_ref().clone()),
+            group: element.group.clone(),
+            merge_groups: element.merge_groups,
+            usemtl: element.usemtl.clone(),
+            import_mtl: element.import_mtl,
+            smooth: element.smooth,
+            project_uvs: element.project_uvs,
+            vertexes: element.vertexes.clone(),
+            faces: element.faces.clone(),
+            subdivision: element.subdivision.clone(),
+            displacement: element.displacement.clone(),
+            material: element.material.clone(),
+        }
+    }
+}
+
+impl SceneLoader {
+    pub fn load(path: &str) -> Result<(Camera, Scene), SceneLoadError> {
+        if is_gltf_path(path) {
+            return SceneLoader::load_gltf(path);
+        }
+
+        let mut source = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut source))
+            .map_err(|error| SceneLoadError::Io { path: path.to_string(), error })?;
+
+        let loader = SceneLoader { path: path.to_string(), source, instanced_meshes: RefCell::new(vec![]) };
+        let description: SceneDescription = loader.parse()?;
+        let camera = loader.build_camera(&description.camera);
+        let skybox = loader.build_skybox(&description.skybox)?;
+
+        let mut scene = Scene {
+            elements: vec![],
+            skybox,
+        };
+        for (i, element) in description.elements.iter().enumerate() {
+            let line = loader.line_at(element.span().start);
+            for object in loader.build_element(element.get_ref(), &format!("elements[{}]", i), line)? {
+                scene.add(object);
+            }
+        }
+
+        Ok((camera, scene))
+    }
+
+    // glTF ファイルをシーンとして読み込む
+    // カメラは glTF ファイルのものを使い、スカイボックスは glTF では表現できないので白一色にする
+    // 別のスカイボックスを使う場合は、シーンファイルの BvhMesh から glTF ファイルを読み込む
+    fn load_gltf(path: &str) -> Result<(Camera, Scene), SceneLoadError> {
+        let camera = GltfLoader::load_camera(path).map_err(SceneLoadError::Mesh)?;
+        let mut scene = Scene {
+            elements: vec![],
+            skybox: Skybox::constant(&Vector3::one()),
+        };
+        for mesh in GltfLoader::load(path, Matrix44::identity()).map_err(SceneLoadError::Mesh)? {
+            scene.add(Box::new(BvhMesh::from_mesh(mesh)));
+        }
+        Ok((camera, scene))
+    }
+
+    fn parse(&self) -> Result<SceneDescription, SceneLoadError> {
+        serde_path_to_error::deserialize(toml::Deserializer::new(&self.source)).map_err(|error| {
+            let line = error.inner().span().map_or(1, |span| self.line_at(span.start));
+            // Spanned の内部表現がパスに現れるので取り除く
+            let key = error.path().to_string().replace(".$__serde_spanned_private_value", "");
+            let key = if key == "." { String::new() } else { key };
+            self.invalid(line, &key, error.inner().message())
+        })
+    }
+
+    fn build_camera(&self, camera: &CameraDescription) -> Camera {
+        Camera::new(
+            to_vector3(&camera.eye),
+            to_vector3(&camera.target),
+            to_vector3(&camera.y_up),
+            camera.fov,
+            camera.lens_shape.clone(),
+            camera.aperture,
+            camera.focus_distance,
+        )
+    }
+
+    fn build_skybox(&self, skybox: &SkyboxDescription) -> Result<Skybox, SceneLoadError> {
+        match (&skybox.px, &skybox.nx, &skybox.py, &skybox.ny, &skybox.pz, &skybox.nz) {
+            (Some(px), Some(nx), Some(py), Some(ny), Some(pz), Some(nz)) => Ok(Skybox::new(
+                self.check_file(px, "skybox.px")?,
+                self.check_file(nx, "skybox.nx")?,
+                self.check_file(py, "skybox.py")?,
+                self.check_file(ny, "skybox.ny")?,
+                self.check_file(pz, "skybox.pz")?,
+                self.check_file(nz, "skybox.nz")?,
+                &to_vector3(&skybox.intensity),
+            )),
+            (None, None, None, None, None, None) => Ok(Skybox::constant(&to_vector3(&skybox.intensity))),
+            faces => {
+                let spans = [faces.0, faces.1, faces.2, faces.3, faces.4, faces.5];
+                let line = spans.iter().filter_map(|face| face.as_ref()).next().map_or(1, |face| self.line_at(face.span().start));
+                Err(self.invalid(line, "skybox", "specify all six faces (px, nx, py, ny, pz, nz) or none of them"))
+            }
+        }
+    }
+
+    // OBJ ファイルのグループは別々の要素として読み込むので、1つの要素から複数の Intersectable ができる
+    fn build_element(&self, element: &ElementDescription, key: &str, line: usize) -> Result<Vec<Box<Intersectable>>, SceneLoadError> {
+        let kind = element.kind;
+        self.check_keys(element, key, line)?;
+        let material = self.build_element_material(element, key)?;
+        match kind {
+            ElementType::Sphere => {
+                let sphere = Sphere {
+                    center: to_vector3(self.require(&element.center, key, "center", line)?),
+                    radius: *self.require(&element.radius, key, "radius", line)?,
+                    material: self.require_material(material, key, line)?,
+                };
+                Ok(vec![self.transform_primitive(element, sphere)])
+            }
+            ElementType::Plane => {
+                Ok(vec![Box::new(Plane {
+                    center: to_vector3(self.require(&element.center, key, "center", line)?),
+                    normal: to_direction(self.require(&element.normal, key, "normal", line)?),
+                    material: self.require_material(material, key, line)?,
+                })])
+            }
+            ElementType::Rectangle => {
+                Ok(vec![Box::new(Rectangle {
+                    corner: to_vector3(self.require(&element.corner, key, "corner", line)?),
+                    edge_u: to_vector3(self.require(&element.edge_u, key, "edge_u", line)?),
+                    edge_v: to_vector3(self.require(&element.edge_v, key, "edge_v", line)?),
+                    material: self.require_material(material, key, line)?,
+                })])
+            }
+            ElementType::Disk => {
+                Ok(vec![Box::new(Disk {
+                    center: to_vector3(self.require(&element.center, key, "center", line)?),
+                    normal: to_direction(self.require(&element.normal, key, "normal", line)?),
+                    radius: *self.require(&element.radius, key, "radius", line)?,
+                    material: self.require_material(material, key, line)?,
+                })])
+            }
+            ElementType::Cylinder => {
+                Ok(vec![Box::new(Cylinder {
+                    bottom: to_vector3(self.require(&element.bottom, key, "bottom", line)?),
+                    top: to_vector3(self.require(&element.top, key, "top", line)?),
+                    radius: *self.require(&element.radius, key, "radius", line)?,
+                    material: self.require_material(material, key, line)?,
+                })])
+            }
+            ElementType::Cone => {
+                Ok(vec![Box::new(Cone {
+                    bottom: to_vector3(self.require(&element.bottom, key, "bottom", line)?),
+                    top: to_vector3(self.require(&element.top, key, "top", line)?),
+                    radius: *self.require(&element.radius, key, "radius", line)?,
+                    material: self.require_material(material, key, line)?,
+                })])
+            }
+            ElementType::Capsule => {
+                Ok(vec![Box::new(Capsule {
+                    bottom: to_vector3(self.require(&element.bottom, key, "bottom", line)?),
+                    top: to_vector3(self.require(&element.top, key, "top", line)?),
+                    radius: *self.require(&element.radius, key, "radius", line)?,
+                    material: self.require_material(material, key, line)?,
+                })])
+            }
+            ElementType::Torus => {
+                Ok(vec![Box::new(Torus {
+                    center: to_vector3(self.require(&element.center, key, "center", line)?),
+                    normal: to_direction(self.require(&element.normal, key, "normal", line)?),
+                    radius: *self.require(&element.radius, key, "radius", line)?,
+                    minor_radius: *self.require(&element.minor_radius, key, "minor_radius", line)?,
+                    material: self.require_material(material, key, line)?,
+                })])
+            }
+            ElementType::Cuboid => {
+                let cuboid = Cuboid {
+                    aabb: Aabb {
+                        min: to_vector3(self.require(&element.min, key, "min", line)?),
+                        max: to_vector3(self.require(&element.max, key, "max", line)?),
+                    },
+                    material: self.require_material(material, key, line)?,
+                };
+                Ok(vec![self.transform_primitive(element, cuboid)])
+            }
+            ElementType::Sdf => {
+                Ok(vec![Box::new(Sdf {
+                    distance_function: self.require(&element.sdf, key, "sdf", line)?.to_distance_function(),
+                    aabb: Aabb {
+                        min: to_vector3(self.require(&element.min, key, "min", line)?),
+                        max: to_vector3(self.require(&element.max, key, "max", line)?),
+                    },
+                    material: self.require_material(material, key, line)?,
+                })])
+            }
+            ElementType::Heightfield => {
+                let texture = self.open_image(self.require(&element.path, key, "path", line)?, &format!("{}.path", key))?;
+                let extent = Aabb {
+                    min: to_vector3(self.require(&element.min, key, "min", line)?),
+                    max: to_vector3(self.require(&element.max, key, "max", line)?),
+                };
+                Ok(vec![Box::new(Heightfield::new(texture, extent, self.require_material(material, key, line)?))])
+            }
+            ElementType::Csg => {
+                let material = self.require_material(material, key, line)?;
+                Ok(vec![Box::new(self.build_csg(element, key, line, material)?)])
+            }
+            ElementType::BvhMesh => {
+                let matrix = self.build_matrix(element);
+                let meshes = self.load_meshes(element, key, line, matrix, material)?;
+                Ok(meshes.into_iter().map(|mesh| Box::new(BvhMesh::from_mesh(mesh)) as Box<Intersectable>).collect())
+            }
+            ElementType::Instance => {
+                // メッシュは行列を適用せずに読み込み、transform は Instance の行列にする
+                let instance_key = InstanceKey::new(element);
+                let cached = self.instanced_meshes.borrow().iter().find(|c| c.0 == instance_key).map(|c| c.1.clone());
+                let meshes = match cached {
+                    Some(meshes) => meshes,
+                    None => {
+                        let meshes: Vec<_> = self.load_meshes(element, key, line, Matrix44::identity(), material)?
+                            .into_iter()
+                            .map(|mesh| Arc::new(BvhMesh::from_mesh(mesh)))
+                            .collect();
+                        self.instanced_meshes.borrow_mut().push((instance_key, meshes.clone()));
+                        meshes
+                    }
+                };
+                let matrix = self.build_matrix(element);
+                Ok(meshes.into_iter().map(|mesh| Box::new(Instance::new(mesh, matrix)) as Box<Intersectable>).collect())
+            }
+        }
+    }
+
+    // 要素の種類で使わないキーが指定されていたらエラーにする
+    fn check_keys(&self, element: &ElementDescription, key: &str, line: usize) -> Result<(), SceneLoadError> {
+        for present in element.present_keys() {
+            if !element.kind.keys().contains(&present) {
+                return Err(self.invalid(line, &format!("{}.{}", key, present), &format!("unknown field for {:?}", element.kind)));
+            }
+        }
+        Ok(())
+    }
+
+    fn build_element_material(&self, element: &ElementDescription, key: &str) -> Result<Option<Material>, SceneLoadError> {
+        match element.material {
+            Some(ref material) => Ok(Some(self.build_material(material, &format!("{}.material", key))?)),
+            None => Ok(None),
+        }
+    }
+
+    // 子要素は入れ子のテーブルなので、エラーの行番号は Csg の要素の行にする
+    fn build_csg(&self, element: &ElementDescription, key: &str, line: usize, material: Material) -> Result<Csg, SceneLoadError> {
+        let a_key = format!("{}.a", key);
+        let b_key = format!("{}.b", key);
+        Ok(Csg {
+            operation: *self.require(&element.operation, key, "operation", line)?,
+            a: self.build_solid(self.require(&element.a, key, "a", line)?, &a_key, line, &material)?,
+            b: self.build_solid(self.require(&element.b, key, "b", line)?, &b_key, line, &material)?,
+            material,
+        })
+    }
+
+    // Csg の子要素。material を省略した場合は親のマテリアルを使う（描画には親のマテリアルだけを使う）
+    fn build_solid(&self, element: &ElementDescription, key: &str, line: usize, parent_material: &Material) -> Result<Box<Solid>, SceneLoadError> {
+        let kind = element.kind;
+        self.check_keys(element, key, line)?;
+        let own_material = self.build_element_material(element, key)?;
+        let material = own_material.clone().unwrap_or_else(|| parent_material.clone());
+        match kind {
+            ElementType::Sphere => {
+                let sphere = Sphere {
+                    center: to_vector3(self.require(&element.center, key, "center", line)?),
+                    radius: *self.require(&element.radius, key, "radius", line)?,
+                    material,
+                };
+                Ok(self.transform_solid(element, sphere))
+            }
+            ElementType::Cuboid => {
+                let cuboid = Cuboid {
+                    aabb: Aabb {
+                        min: to_vector3(self.require(&element.min, key, "min", line)?),
+                        max: to_vector3(self.require(&element.max, key, "max", line)?),
+                    },
+                    material,
+                };
+                Ok(self.transform_solid(element, cuboid))
+            }
+            ElementType::BvhMesh => {
+                // glTF ファイルはファイル内のマテリアルを使うので、親のマテリアルを渡さない
+                let gltf = element.path.as_ref().map_or(false, |path| is_gltf_path(path.get_ref()));
+                let material = if gltf { own_material } else { Some(material) };
+                let mut meshes = self.load_meshes(element, key, line, self.build_matrix(element), material)?;
+                if meshes.len() != 1 {
+                    return Err(self.invalid(line, key, "Csg requires a single mesh (specify group, usemtl or merge_groups)"));
+                }
+                Ok(Box::new(BvhMesh::from_mesh(meshes.pop().unwrap())))
+            }
+            ElementType::Csg => Ok(Box::new(self.build_csg(element, key, line, material)?)),
+            _ => Err(self.invalid(line, &format!("{}.type", key), &format!("{:?} cannot be used in Csg", kind))),
+        }
+    }
+
+    fn transform_solid<T: Solid + 'static>(&self, element: &ElementDescription, object: T) -> Box<Solid> {
+        if element.transform.is_some() {
+            Box::new(Transformed::new(object, self.build_matrix(element)))
+        } else {
+            Box::new(object)
+        }
+    }
+
+    // transform がある場合は Transformed で包む
+    fn transform_primitive<T: Intersectable + 'static>(&self, element: &ElementDescription, object: T) -> Box<Intersectable> {
+        if element.transform.is_some() {
+            Box::new(Transformed::new(object, self.build_matrix(element)))
+        } else {
+            Box::new(object)
+        }
+    }
+
+    // 行列の積を左から順に計算する（identity を掛けると -0.0 が 0.0 になるので初期値にしない）
+    fn build_matrix(&self, element: &ElementDescription) -> Matrix44 {
+        let mut matrices = element.transform.iter().flat_map(|t| t.iter()).map(|t| t.to_matrix());
+        let first = matrices.next().unwrap_or_else(Matrix44::identity);
+        matrices.fold(first, |m, t| m * t)
+    }
+
+    // BvhMesh, Instance の path または vertexes と faces からメッシュを読み込む
+    fn load_meshes(&self, element: &ElementDescription, key: &str, line: usize, matrix: Matrix44, material: Option<Material>) -> Result<Vec<Mesh>, SceneLoadError> {
+        if let Some(ref path) = element.path {
+            if element.vertexes.is_some() || element.faces.is_some() {
+                return Err(self.invalid(line, &format!("{}.path", key), "path cannot be used with vertexes and faces"));
+            }
+            let path_key = format!("{}.path", key);
+            let file = self.check_file(path, &path_key)?;
+            let extension = Path::new(file).extension().map_or(String::new(), |e| e.to_string_lossy().to_lowercase());
+            let meshes = match extension.as_str() {
+                "ply" | "stl" => {
+                    for (name, present) in &[("group", element.group.is_some()), ("merge_groups", element.merge_groups.is_some()), ("usemtl", element.usemtl.is_some()), ("import_mtl", element.import_mtl.is_some())] {
+                        if *present {
+                            return Err(self.invalid(line, &format!("{}.{}", key, name), &format!("{} can only be used with OBJ files", name)));
+                        }
+                    }
+                    if extension == "ply" {
+                        PlyLoader::load(file, matrix, self.require_material(material, key, line)?).map(|mesh| vec![mesh])
+                    } else {
+                        StlLoader::load(file, matrix, self.require_material(material, key, line)?).map(|mesh| vec![mesh])
+                    }
+                }
+                "gltf" | "glb" => {
+                    for (name, present) in &[("merge_groups", element.merge_groups.is_some()), ("usemtl", element.usemtl.is_some()), ("import_mtl", element.import_mtl.is_some()), ("material", material.is_some())] {
+                        if *present {
+                            return Err(self.invalid(line, &format!("{}.{}", key, name), &format!("{} cannot be used with glTF files", name)));
+                        }
+                    }
+                    // group を指定しない場合は、すべてのプリミティブを別々のメッシュにする
+                    match element.group {
+                        Some(ref group) => GltfLoader::load_part(file, group, matrix).map(|mesh| vec![mesh]),
+                        None => GltfLoader::load(file, matrix),
+                    }
+                }
+                _ => {
+                    // group, usemtl を指定しない場合は、グループごとに別のメッシュにする
+                    // merge_groups を指定した場合は、ファイル全体を1つのメッシュにする
+                    let material = self.require_material(material, key, line)?;
+                    let import_mtl = element.import_mtl.unwrap_or(false);
+                    let group = element.group.as_ref().map(|s| s.as_str());
+                    let usemtl = element.usemtl.as_ref().map(|s| s.as_str());
+                    if element.merge_groups.unwrap_or(false) {
+                        for (name, present) in &[("group", group.is_some()), ("usemtl", usemtl.is_some()), ("import_mtl", import_mtl)] {
+                            if *present {
+                                return Err(self.invalid(line, &format!("{}.{}", key, name), &format!("{} cannot be used with merge_groups", name)));
+                            }
+                        }
+                        ObjLoader::load(file, matrix, material).map(|mesh| vec![mesh])
+                    } else if group.is_some() || usemtl.is_some() {
+                        ObjLoader::load_part(file, group, usemtl, import_mtl, matrix, material).map(|mesh| vec![mesh])
+                    } else {
+                        ObjLoader::load_groups(file, matrix, material, import_mtl)
+                    }
+                }
+            };
+            let meshes = meshes.map_err(|e| self.invalid(self.line_at(path.span().start), &path_key, &format!("failed to load mesh ({})", e)))?;
+            self.refine_meshes(element, key, line, meshes)
+        } else {
+            for (name, present) in &[("group", element.group.is_some()), ("merge_groups", element.merge_groups.is_some()), ("usemtl", element.usemtl.is_some()), ("import_mtl", element.import_mtl.is_some())] {
+                if *present {
+                    return Err(self.invalid(line, &format!("{}.{}", key, name), &format!("{} can only be used with path", name)));
+                }
+            }
+            let mesh = self.build_mesh(element, key, line, matrix, self.require_material(material, key, line)?)?;
+            self.refine_meshes(element, key, line, vec![mesh])
+        }
+    }
+
+    // 読み込んだメッシュを細分割してから変位させる
+    // smooth を指定すると頂点法線を、project_uvs を指定すると UV を BvhMesh::from_mesh で計算する
+    fn refine_meshes(&self, element: &ElementDescription, key: &str, line: usize, mut meshes: Vec<Mesh>) -> Result<Vec<Mesh>, SceneLoadError> {
+        if let Some(ref subdivision) = element.subdivision {
+            // 1段ごとに面の数が4倍になるので、大きすぎる level はメモリを使い切る前にエラーにする
+            if subdivision.level > MAX_SUBDIVISION_LEVEL {
+                return Err(self.invalid(line, &format!("{}.subdivision.level", key), &format!("must be at most {}", MAX_SUBDIVISION_LEVEL)));
+            }
+            meshes = meshes.into_iter().map(|mesh| subdivide(mesh, subdivision)).collect();
+        }
+        if let Some(ref displacement) = element.displacement {
+            if let Some(edge_length) = displacement.edge_length {
+                if !(edge_length.is_finite() && edge_length > 0.0) {
+                    return Err(self.invalid(line, &format!("{}.displacement.edge_length", key), "must be a positive number"));
+                }
+            }
+            let displacement = Displacement {
+                texture: self.build_texture(&displacement.texture, &format!("{}.displacement.texture", key))?,
+                scale: displacement.scale,
+                edge_length: displacement.edge_length,
+            };
+            meshes = meshes.into_iter().map(|mesh| displace(mesh, &displacement)).collect();
+        }
+        for mesh in &mut meshes {
+            mesh.smooth = element.smooth.unwrap_or(false);
+            mesh.project_uvs = element.project_uvs.unwrap_or(false);
+        }
+        Ok(meshes)
+    }
+
+    // path の代わりに頂点と面を直接記述したメッシュ
+    fn build_mesh(&self, element: &ElementDescription, key: &str, line: usize, matrix: Matrix44, material: Material) -> Result<Mesh, SceneLoadError> {
+        let vertexes: Vec<_> = self.require(&element.vertexes, key, "vertexes", line)?.iter()
+            .map(|v| matrix * to_vector3(v))
+            .collect();
+
+        let mut faces = vec![];
+        for face in self.require(&element.faces, key, "faces", line)? {
+            if face.iter().any(|i| *i >= vertexes.len()) {
+                return Err(self.invalid(line, &format!("{}.faces", key), &format!("vertex index out of range: {:?}", face)));
+            }
+            faces.push(Face::new(face[0], face[1], face[2]));
+        }
+
+        Ok(Mesh {
+            vertexes,
+            normals: vec![],
+            uvs: vec![],
+            colors: vec![],
+            faces,
+            material,
+            source: None,
+            smooth: false,
+            project_uvs: false,
+        })
+    }
+
+    fn build_material(&self, material: &MaterialDescription, key: &str) -> Result<Material, SceneLoadError> {
+        let surface = match material.surface {
+            SurfaceType::Principled(ref principled) => SurfaceType::Principled(Principled {
+                maps: self.build_principled_maps(material, key)?,
+                ..principled.clone()
+            }),
+            ref surface => surface.clone(),
+        };
+        Ok(Material {
+            surface,
+            albedo: self.build_texture(&material.albedo, &format!("{}.albedo", key))?,
+            emission: self.build_texture(&material.emission, &format!("{}.emission", key))?,
+            roughness: self.build_texture(&material.roughness, &format!("{}.roughness", key))?,
+        })
+    }
+
+    // Principled のテクスチャが1つも無ければ None にして、交差判定のたびにサンプリングしないようにする
+    fn build_principled_maps(&self, material: &MaterialDescription, key: &str) -> Result<Option<PrincipledMaps>, SceneLoadError> {
+        let textures = [&material.metallic, &material.specular, &material.sheen, &material.clearcoat, &material.transmission];
+        if textures.iter().all(|texture| texture.is_none()) {
+            return Ok(None);
+        }
+
+        let build = |texture: &Option<TextureDescription>, name: &str| match *texture {
+            Some(ref texture) => self.build_texture(texture, &format!("{}.{}", key, name)),
+            None => Ok(Texture::white()),
+        };
+        Ok(Some(PrincipledMaps {
+            metallic: build(&material.metallic, "metallic")?,
+            specular: build(&material.specular, "specular")?,
+            sheen: build(&material.sheen, "sheen")?,
+            clearcoat: build(&material.clearcoat, "clearcoat")?,
+            transmission: build(&material.transmission, "transmission")?,
+        }))
+    }
+
+    fn build_texture(&self, texture: &TextureDescription, key: &str) -> Result<Texture, SceneLoadError> {
+        let color = to_vector3(&texture.color);
+        match texture.path {
+            Some(ref path) => Ok(Texture::new(self.check_file(path, &format!("{}.path", key))?, color)),
+            None => Ok(Texture::from_color(color)),
+        }
+    }
+
+    fn require<'a, T>(&self, value: &'a Option<T>, key: &str, name: &str, line: usize) -> Result<&'a T, SceneLoadError> {
+        value.as_ref().ok_or_else(|| self.invalid(line, &format!("{}.{}", key, name), "missing field"))
+    }
+
+    // glTF 以外の要素では material は必須
+    fn require_material(&self, material: Option<Material>, key: &str, line: usize) -> Result<Material, SceneLoadError> {
+        material.ok_or_else(|| self.invalid(line, &format!("{}.material", key), "missing field"))
+    }
+
+    // 参照先のファイルが無い場合は、ローダー内部で panic する前にエラーとして報告する
+    fn check_file<'a>(&self, file: &'a Spanned<String>, key: &str) -> Result<&'a str, SceneLoadError> {
+        if Path::new(file.get_ref()).is_file() {
+            Ok(file.get_ref())
+        } else {
+            Err(self.invalid(self.line_at(file.span().start), key, &format!("file not found: \"{}\"", file.get_ref())))
+        }
+    }
+
+    fn open_image(&self, file: &Spanned<String>, key: &str) -> Result<ImageTexture, SceneLoadError> {
+        ImageTexture::open(self.check_file(file, key)?).map_err(|error| {
+            self.invalid(self.line_at(file.span().start), key, &format!("cannot decode image \"{}\": {}", file.get_ref(), error))
+        })
+    }
+
+    fn invalid(&self, line: usize, key: &str, message: &str) -> SceneLoadError {
+        SceneLoadError::Invalid {
+            path: self.path.clone(),
+            line,
+            key: key.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    // 1 origin
+    fn line_at(&self, offset: usize) -> usize {
+        self.source[..offset].matches('\n').count() + 1
+    }
+}
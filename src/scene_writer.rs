@@ -0,0 +1,177 @@
+extern crate toml;
+
+use std::fs::File;
+use std::io::{self, Write};
+
+use camera::Camera;
+use scene::Scene;
+use description::{SceneDescription, CameraDescription, SkyboxDescription};
+
+pub struct SceneWriter;
+
+impl SceneWriter {
+    // SceneLoader で読み込み直すと同じ Scene が得られるように書き出す
+    // 浮動小数点数は Rust の最短表現で書き出されるので、読み込み時に誤差は生じない
+    pub fn write(path: &str, camera: &Camera, scene: &Scene) -> io::Result<()> {
+        let mut elements = vec![];
+        for (i, element) in scene.elements.iter().enumerate() {
+            match element.description() {
+                Some(description) => elements.push(toml::Spanned::new(0..0, description)),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("elements[{}] cannot be written to a scene file", i))),
+            }
+        }
+
+        let description = SceneDescription {
+            camera: CameraDescription::from_camera(camera),
+            skybox: SkyboxDescription::from_skybox(&scene.skybox),
+            elements,
+        };
+        let source = toml::to_string(&description)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut f = File::create(path)?;
+        f.write_all(source.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use scene_loader::SceneLoader;
+
+    const SCENE: &str = r#"
+[camera]
+eye = [0.0, 2.0, 9.0]
+target = [0.0, 1.0, 0.0]
+fov = 10.0
+lens_shape = "Square"
+aperture = 0.1
+focus_distance = 8.8
+
+[skybox]
+intensity = [0.5, 0.6, 0.7]
+
+[[elements]]
+type = "Sphere"
+center = [0.0, 0.6, 0.0]
+radius = 0.6
+[elements.material]
+surface = { type = "Layered", refractive_index = 1.5, coat_roughness = 0.1, coat = { type = "GGX", f0 = 1.0 }, base = { type = "Diffuse" } }
+albedo = { color = [0.8, 0.2, 0.1] }
+roughness = { color = [0.3, 0.3, 0.3] }
+
+[[elements]]
+type = "Cuboid"
+min = [-0.5, 0.0, -0.5]
+max = [0.5, 1.0, 0.5]
+transform = [{ rotate_y = 30.0 }, { translate = [2.0, 0.0, 0.0] }]
+[elements.material]
+surface = { type = "Conductor", ior = "Gold" }
+albedo = { color = [1.0, 1.0, 1.0] }
+
+[[elements]]
+type = "Rectangle"
+corner = [-1.0, 3.0, -1.0]
+edge_u = [2.0, 0.0, 0.0]
+edge_v = [0.0, 0.0, 2.0]
+[elements.material]
+surface = { type = "Diffuse" }
+albedo = { color = [0.0, 0.0, 0.0] }
+emission = { color = [10.0, 10.0, 10.0] }
+
+[[elements]]
+type = "Torus"
+center = [-2.0, 0.5, 0.0]
+normal = [0.0, 1.0, 1.0]
+radius = 0.45
+minor_radius = 0.15
+[elements.material]
+surface = { type = "AnisotropicGGX", f0 = 0.9, roughness_x = 0.1, roughness_y = 0.5, tangent = [1.0, 0.0, 0.0] }
+albedo = { color = [0.9, 0.9, 0.9] }
+
+[[elements]]
+type = "Csg"
+operation = "Difference"
+a = { type = "Cuboid", min = [-2.2, 0.0, -0.5], max = [-1.2, 1.0, 0.5] }
+b = { type = "Sphere", center = [-1.7, 0.8, 0.2], radius = 0.6 }
+[elements.material]
+surface = { type = "Principled", metallic = 0.25, transmission = 0.5, clearcoat = 1.0 }
+albedo = { color = [0.8, 0.4, 0.3] }
+metallic = { path = "textures/2d/checkered_diagonal_10_0.1_0.6_512.png" }
+
+[[elements]]
+type = "Sdf"
+min = [0.9, 0.0, -0.6]
+max = [2.4, 1.2, 0.6]
+sdf = { type = "SmoothUnion", k = 0.25, a = { type = "Sphere", center = [1.4, 0.6, 0.0], radius = 0.4 }, b = { type = "Box", center = [1.95, 0.4, 0.0], half_size = [0.3, 0.4, 0.3] } }
+[elements.material]
+surface = { type = "GGXRefraction", refractive_index = 1.5 }
+albedo = { color = [0.9, 0.9, 1.0] }
+
+[[elements]]
+type = "BvhMesh"
+vertexes = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+faces = [[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]]
+subdivision = { scheme = "Loop", level = 1 }
+smooth = true
+project_uvs = true
+[elements.material]
+surface = { type = "Diffuse" }
+albedo = { color = [0.5, 0.5, 0.5] }
+
+[[elements]]
+type = "Plane"
+center = [0.0, 0.0, 0.0]
+normal = [0.0, 1.0, 0.0]
+[elements.material]
+surface = { type = "Diffuse" }
+albedo = { path = "textures/2d/checkered_diagonal_12_0.1_0.9_512.png" }
+"#;
+
+    // path を読み込んで書き出し、書き出したファイルをもう一度読み込んで書き出すと同じ内容になる
+    // 書き出したファイルを読み込み直して書き出すと、最初に書き出したものと同じになる
+    fn assert_round_trip(name: &str, camera: &Camera, scene: &Scene) {
+        let exported = env::temp_dir().join(format!("hanamaru-round-trip-{}-1.toml", name));
+        let reexported = env::temp_dir().join(format!("hanamaru-round-trip-{}-2.toml", name));
+
+        SceneWriter::write(exported.to_str().unwrap(), camera, scene).unwrap();
+        let (camera, reloaded) = SceneLoader::load(exported.to_str().unwrap()).unwrap();
+        assert_eq!(reloaded.elements.len(), scene.elements.len(), "{}", name);
+        SceneWriter::write(reexported.to_str().unwrap(), &camera, &reloaded).unwrap();
+
+        let (exported_source, reexported_source) = (fs::read_to_string(&exported).unwrap(), fs::read_to_string(&reexported).unwrap());
+        fs::remove_file(&exported).unwrap();
+        fs::remove_file(&reexported).unwrap();
+        assert!(exported_source == reexported_source, "{} changed after reloading", name);
+    }
+
+    fn assert_scene_file_round_trips(name: &str, path: &str) {
+        let (camera, scene) = SceneLoader::load(path).unwrap();
+        assert_round_trip(name, &camera, &scene);
+    }
+
+    #[test]
+    fn sample_scene_round_trips() {
+        assert_scene_file_round_trips("simple", "scenes/simple.toml");
+    }
+
+    #[test]
+    fn every_kind_of_element_round_trips() {
+        let path = env::temp_dir().join("hanamaru-round-trip-elements.toml");
+        fs::write(&path, SCENE).unwrap();
+        assert_scene_file_round_trips("elements", path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    // コードで組み立てたシーンも、書き出したものを読み込み直して同じファイルになる
+    // rtcamp6_v4 のモデルはリポジトリに含まれていないので除く
+    #[test]
+    fn presets_round_trip() {
+        for preset in ::PRESETS.iter().filter(|preset| preset.name != "rtcamp6_v4") {
+            let (camera, scene) = (preset.init)();
+            assert_round_trip(preset.name, &camera, &scene);
+        }
+    }
+}
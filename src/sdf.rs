@@ -0,0 +1,238 @@
+use config;
+use vector::{Vector3, Vector2};
+use material::Material;
+use camera::Ray;
+use color::Color;
+use math::{clamp, modulo};
+use bvh::Aabb;
+use scene::{Intersectable, Intersection, Surface};
+use description::{ElementDescription, ElementType, SdfDescription, from_vector3};
+
+// 符号付き距離関数。形状の内側では負の値を返す
+// 真の距離より大きな値を返すとレイが形状を通り抜けるので、距離の推定値は小さめに見積もる
+pub trait DistanceFunction: Sync {
+    fn distance(&self, p: &Vector3) -> f64;
+
+    // シーンファイルに書き出すための記述。書き出せない距離関数は None を返す
+    fn description(&self) -> Option<SdfDescription>;
+}
+
+// 距離関数を sphere tracing で描画する要素
+// 距離関数には形の大きさの情報が無いので、BVH のために形状を囲む aabb を指定する
+pub struct Sdf {
+    pub distance_function: Box<DistanceFunction>,
+    pub aabb: Aabb,
+    pub material: Material,
+}
+
+impl Sdf {
+    // 中心差分で距離関数の勾配を求める
+    fn normal(&self, p: &Vector3) -> Vector3 {
+        let h = config::SPHERE_TRACING_EPS;
+        let f = &self.distance_function;
+        Vector3::new(
+            f.distance(&Vector3::new(p.x + h, p.y, p.z)) - f.distance(&Vector3::new(p.x - h, p.y, p.z)),
+            f.distance(&Vector3::new(p.x, p.y + h, p.z)) - f.distance(&Vector3::new(p.x, p.y - h, p.z)),
+            f.distance(&Vector3::new(p.x, p.y, p.z + h)) - f.distance(&Vector3::new(p.x, p.y, p.z - h)),
+        ).normalize()
+    }
+}
+
+impl Intersectable for Sdf {
+    fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
+        let (t_min, t_max) = self.aabb.ray_interval(ray);
+        let t_end = t_max.min(intersection.distance);
+        let mut t = t_min.max(0.0);
+
+        // 屈折したレイは形状の内側から出発するので、距離の絶対値だけ進める
+        for _ in 0..config::SPHERE_TRACING_MAX_STEPS {
+            if t > t_end {
+                return false;
+            }
+
+            let position = ray.origin + ray.direction * t;
+            let distance = self.distance_function.distance(&position).abs();
+            if distance < config::SPHERE_TRACING_EPS {
+                intersection.position = position;
+                intersection.distance = t;
+                intersection.normal = self.normal(&position);
+                intersection.geometric_normal = intersection.normal;
+                intersection.tangent = Vector3::zero();
+                intersection.vertex_color = Color::one();
+                intersection.uv = Vector2::zero();
+                return true;
+            }
+            t += distance;
+        }
+        false
+    }
+
+    fn material(&self) -> &Material { &self.material }
+
+    fn aabb(&self) -> Aabb { self.aabb.clone() }
+
+    fn bounded(&self) -> bool { true }
+
+    fn nee_available(&self) -> bool { false }
+
+    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
+        unimplemented!()
+    }
+
+    fn description(&self) -> Option<ElementDescription> {
+        self.distance_function.description().map(|sdf| {
+            let mut description = ElementDescription::new(ElementType::Sdf, &self.material);
+            description.min = Some(from_vector3(&self.aabb.min));
+            description.max = Some(from_vector3(&self.aabb.max));
+            description.sdf = Some(sdf);
+            description
+        })
+    }
+}
+
+fn box_distance(p: &Vector3, half_size: &Vector3) -> f64 {
+    let q = Vector3::new(p.x.abs() - half_size.x, p.y.abs() - half_size.y, p.z.abs() - half_size.z);
+    let outside = Vector3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).length();
+    let inside = q.x.max(q.y).max(q.z).min(0.0);
+    outside + inside
+}
+
+pub struct SphereDistance {
+    pub center: Vector3,
+    pub radius: f64,
+}
+
+impl DistanceFunction for SphereDistance {
+    fn distance(&self, p: &Vector3) -> f64 {
+        (*p - self.center).length() - self.radius
+    }
+
+    fn description(&self) -> Option<SdfDescription> {
+        Some(SdfDescription::Sphere {
+            center: from_vector3(&self.center),
+            radius: self.radius,
+        })
+    }
+}
+
+pub struct BoxDistance {
+    pub center: Vector3,
+    pub half_size: Vector3,
+}
+
+impl DistanceFunction for BoxDistance {
+    fn distance(&self, p: &Vector3) -> f64 {
+        box_distance(&(*p - self.center), &self.half_size)
+    }
+
+    fn description(&self) -> Option<SdfDescription> {
+        Some(SdfDescription::Box {
+            center: from_vector3(&self.center),
+            half_size: from_vector3(&self.half_size),
+        })
+    }
+}
+
+// 中心が center で、半径がおよそ 1.2 * scale の Mandelbulb
+// power = 8 が一般的な形。Y 軸を対称軸にする
+// http://blog.hvidtfeldts.net/index.php/2011/09/distance-estimated-3d-fractals-v-the-mandelbulb-different-de-approximations/
+pub struct Mandelbulb {
+    pub center: Vector3,
+    pub scale: f64,
+    pub power: f64,
+    pub iterations: u32,
+}
+
+impl DistanceFunction for Mandelbulb {
+    fn distance(&self, p: &Vector3) -> f64 {
+        let local = (*p - self.center) / self.scale;
+        let c = Vector3::new(local.x, local.z, local.y);
+        let mut z = c;
+        let mut dr = 1.0;
+        let mut r = z.length();
+        for _ in 0..self.iterations {
+            if r > 2.0 {
+                break;
+            }
+
+            let theta = (z.z / r).acos() * self.power;
+            let phi = z.y.atan2(z.x) * self.power;
+            dr = r.powf(self.power - 1.0) * self.power * dr + 1.0;
+            z = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()) * r.powf(self.power) + c;
+            r = z.length();
+        }
+        0.5 * r.ln() * r / dr * self.scale
+    }
+
+    fn description(&self) -> Option<SdfDescription> {
+        Some(SdfDescription::Mandelbulb {
+            center: from_vector3(&self.center),
+            scale: self.scale,
+            power: self.power,
+            iterations: self.iterations,
+        })
+    }
+}
+
+// 中心が center で、一辺の半分が size の Menger sponge
+// http://iquilezles.org/www/articles/menger/menger.htm
+pub struct MengerSponge {
+    pub center: Vector3,
+    pub size: f64,
+    pub iterations: u32,
+}
+
+impl DistanceFunction for MengerSponge {
+    fn distance(&self, p: &Vector3) -> f64 {
+        let local = (*p - self.center) / self.size;
+        let mut d = box_distance(&local, &Vector3::one());
+        let mut s = 1.0;
+        for _ in 0..self.iterations {
+            let a = Vector3::new(modulo(local.x * s, 2.0), modulo(local.y * s, 2.0), modulo(local.z * s, 2.0)) - 1.0;
+            s *= 3.0;
+            let r = Vector3::new(
+                (1.0 - 3.0 * a.x.abs()).abs(),
+                (1.0 - 3.0 * a.y.abs()).abs(),
+                (1.0 - 3.0 * a.z.abs()).abs(),
+            );
+            let da = r.x.max(r.y);
+            let db = r.y.max(r.z);
+            let dc = r.z.max(r.x);
+            let c = (da.min(db).min(dc) - 1.0) / s;
+            d = d.max(c);
+        }
+        d * self.size
+    }
+
+    fn description(&self) -> Option<SdfDescription> {
+        Some(SdfDescription::MengerSponge {
+            center: from_vector3(&self.center),
+            size: self.size,
+            iterations: self.iterations,
+        })
+    }
+}
+
+// 2つの形状の和集合の境界を、幅 k で滑らかにつなぐ
+// http://iquilezles.org/www/articles/smin/smin.htm
+pub struct SmoothUnion {
+    pub a: Box<DistanceFunction>,
+    pub b: Box<DistanceFunction>,
+    pub k: f64,
+}
+
+impl DistanceFunction for SmoothUnion {
+    fn distance(&self, p: &Vector3) -> f64 {
+        let a = self.a.distance(p);
+        let b = self.b.distance(p);
+        let h = clamp(0.5 + 0.5 * (b - a) / self.k, 0.0, 1.0);
+        b + (a - b) * h - self.k * h * (1.0 - h)
+    }
+
+    fn description(&self) -> Option<SdfDescription> {
+        match (self.a.description(), self.b.description()) {
+            (Some(a), Some(b)) => Some(SdfDescription::SmoothUnion { a: Box::new(a), b: Box::new(b), k: self.k }),
+            _ => None,
+        }
+    }
+}
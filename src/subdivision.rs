@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+
+use vector::{Vector3, Vector2};
+use material::Material;
+use scene::{Mesh, Face};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SubdivisionScheme {
+    // 三角形を4つに分割する
+    Loop,
+    // 多角形を頂点の数の四角形に分割する（OBJ, PLY の多角形は分割前の形で扱う）
+    CatmullClark,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Subdivision {
+    pub scheme: SubdivisionScheme,
+    pub level: u32,
+
+    // 隣り合う面の法線のなす角（度）がこれより大きい辺を折り目として残す
+    // 省略した場合は境界の辺だけが折り目になる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crease_angle: Option<f64>,
+}
+
+// シーンファイルで指定できる level の上限（面の数は 4^level 倍になる）
+pub const MAX_SUBDIVISION_LEVEL: u32 = 6;
+
+// 細分割したメッシュを返す
+// 頂点法線は捨てるので、BvhMesh::from_mesh で計算し直す。UV と頂点カラーは線形に補間する
+pub fn subdivide(mesh: Mesh, subdivision: &Subdivision) -> Mesh {
+    let mut control = ControlMesh::from_mesh(&mesh, subdivision.scheme);
+    control.creases = control.angle_creases(subdivision.crease_angle);
+    for _ in 0..subdivision.level {
+        control = match subdivision.scheme {
+            SubdivisionScheme::Loop => control.loop_step(),
+            SubdivisionScheme::CatmullClark => control.catmull_clark_step(),
+        };
+    }
+
+    let mut result = control.to_mesh(mesh.material);
+    result.source = mesh.source;
+    if let Some(ref mut source) = result.source {
+        source.subdivision = Some(subdivision.clone());
+    }
+    result
+}
+
+// 新しい頂点を元の頂点の重み付き和で表す（位置と頂点カラーに同じ重みを使う）
+type Stencil = Vec<(usize, f64)>;
+
+fn scaled(stencil: &Stencil, scale: f64) -> Stencil {
+    stencil.iter().map(|&(i, w)| (i, w * scale)).collect()
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+struct Polygon {
+    vertexes: Vec<usize>,
+
+    // 角ごとの UV（すべての面に UV がある場合だけ）
+    uvs: Option<Vec<Vector2>>,
+}
+
+struct ControlMesh {
+    vertexes: Vec<Vector3>,
+    colors: Vec<Vector3>,
+    polygons: Vec<Polygon>,
+
+    // 角度で決めた折り目の辺。細分割した辺にも引き継ぐ（境界の辺は毎回判定する）
+    creases: HashSet<(usize, usize)>,
+}
+
+// 辺と、辺を共有する面
+struct Topology {
+    edges: Vec<(usize, usize)>,
+    edge_indexes: HashMap<(usize, usize), usize>,
+    edge_polygons: Vec<Vec<usize>>,
+    vertex_neighbors: Vec<Vec<usize>>,
+    vertex_polygons: Vec<Vec<usize>>,
+}
+
+impl ControlMesh {
+    fn from_mesh(mesh: &Mesh, scheme: SubdivisionScheme) -> ControlMesh {
+        let mut polygons: Vec<Polygon> = vec![];
+        for face in &mesh.faces {
+            let uvs = face.uv_indexes.map(|indexes| indexes.iter().map(|&i| mesh.uvs[i]).collect::<Vec<_>>());
+            if face.fan && scheme == SubdivisionScheme::CatmullClark && !polygons.is_empty() {
+                let polygon = polygons.last_mut().unwrap();
+                polygon.vertexes.push(face.v2);
+                polygon.uvs = match (polygon.uvs.take(), uvs) {
+                    (Some(mut polygon_uvs), Some(uvs)) => {
+                        polygon_uvs.push(uvs[2]);
+                        Some(polygon_uvs)
+                    }
+                    _ => None,
+                };
+            } else {
+                polygons.push(Polygon { vertexes: vec![face.v0, face.v1, face.v2], uvs });
+            }
+        }
+
+        // 一部の面にしか UV が無い場合は UV を使わない
+        if polygons.iter().any(|p| p.uvs.is_none()) {
+            for polygon in &mut polygons {
+                polygon.uvs = None;
+            }
+        }
+
+        ControlMesh {
+            vertexes: mesh.vertexes.clone(),
+            colors: mesh.colors.clone(),
+            polygons,
+            creases: HashSet::new(),
+        }
+    }
+
+    fn topology(&self) -> Topology {
+        let mut topology = Topology {
+            edges: vec![],
+            edge_indexes: HashMap::new(),
+            edge_polygons: vec![],
+            vertex_neighbors: vec![vec![]; self.vertexes.len()],
+            vertex_polygons: vec![vec![]; self.vertexes.len()],
+        };
+        for (p, polygon) in self.polygons.iter().enumerate() {
+            let n = polygon.vertexes.len();
+            for i in 0..n {
+                let (a, b) = (polygon.vertexes[i], polygon.vertexes[(i + 1) % n]);
+                let key = edge_key(a, b);
+                let index = match topology.edge_indexes.get(&key) {
+                    Some(&index) => index,
+                    None => {
+                        topology.edges.push(key);
+                        topology.edge_polygons.push(vec![]);
+                        topology.vertex_neighbors[a].push(b);
+                        topology.vertex_neighbors[b].push(a);
+                        topology.edges.len() - 1
+                    }
+                };
+                topology.edge_indexes.insert(key, index);
+                topology.edge_polygons[index].push(p);
+                topology.vertex_polygons[a].push(p);
+            }
+        }
+        topology
+    }
+
+    fn is_crease(&self, topology: &Topology, edge: usize) -> bool {
+        topology.edge_polygons[edge].len() != 2 || self.creases.contains(&topology.edges[edge])
+    }
+
+    // 平面でない多角形にも使えるように、辺ごとの外積の和で面の法線を求める
+    fn polygon_normal(&self, polygon: &Polygon) -> Vector3 {
+        let n = polygon.vertexes.len();
+        let mut normal = Vector3::zero();
+        for i in 0..n {
+            let a = self.vertexes[polygon.vertexes[i]];
+            let b = self.vertexes[polygon.vertexes[(i + 1) % n]];
+            normal += a.cross(&b);
+        }
+        normal.normalize()
+    }
+
+    fn angle_creases(&self, crease_angle: Option<f64>) -> HashSet<(usize, usize)> {
+        let mut creases = HashSet::new();
+        if let Some(angle) = crease_angle {
+            let cos_threshold = angle.to_radians().cos();
+            let topology = self.topology();
+            for (edge, polygons) in topology.edge_polygons.iter().enumerate() {
+                if polygons.len() == 2 {
+                    let n0 = self.polygon_normal(&self.polygons[polygons[0]]);
+                    let n1 = self.polygon_normal(&self.polygons[polygons[1]]);
+                    if n0.dot(&n1) < cos_threshold {
+                        creases.insert(topology.edges[edge]);
+                    }
+                }
+            }
+        }
+        creases
+    }
+
+    // 折り目の辺が2本の頂点は折り目に沿って動かし、3本以上の頂点（角）は動かさない
+    // それ以外は None を返して、各方式の規則で動かす
+    fn crease_vertex_stencil(&self, topology: &Topology, v: usize) -> Option<Stencil> {
+        let crease_neighbors: Vec<usize> = topology.vertex_neighbors[v].iter()
+            .cloned()
+            .filter(|&u| self.is_crease(topology, topology.edge_indexes[&edge_key(v, u)]))
+            .collect();
+        match crease_neighbors.len() {
+            2 => Some(vec![(v, 0.75), (crease_neighbors[0], 0.125), (crease_neighbors[1], 0.125)]),
+            n if n > 2 => Some(vec![(v, 1.0)]),
+            _ => None,
+        }
+    }
+
+    // 折り目の辺は、分割した2本の辺も折り目にする
+    fn split_creases(&self, topology: &Topology, edge_offset: usize) -> HashSet<(usize, usize)> {
+        let mut creases = HashSet::new();
+        for &(a, b) in &self.creases {
+            if let Some(&edge) = topology.edge_indexes.get(&(a, b)) {
+                creases.insert(edge_key(a, edge_offset + edge));
+                creases.insert(edge_key(edge_offset + edge, b));
+            }
+        }
+        creases
+    }
+
+    fn apply(&self, stencils: &[Stencil], polygons: Vec<Polygon>, creases: HashSet<(usize, usize)>) -> ControlMesh {
+        let evaluate = |values: &Vec<Vector3>| -> Vec<Vector3> {
+            stencils.iter()
+                .map(|stencil| stencil.iter().fold(Vector3::zero(), |sum, &(i, w)| sum + values[i] * w))
+                .collect()
+        };
+        ControlMesh {
+            vertexes: evaluate(&self.vertexes),
+            colors: if self.colors.is_empty() { vec![] } else { evaluate(&self.colors) },
+            polygons,
+            creases,
+        }
+    }
+
+    // 新しい頂点は [元の頂点, 辺の中点] の順に並べる
+    // https://graphics.stanford.edu/~mdfisher/subdivision.html
+    fn loop_step(&self) -> ControlMesh {
+        let topology = self.topology();
+        let vertex_count = self.vertexes.len();
+        let mut stencils = vec![];
+
+        for v in 0..vertex_count {
+            let stencil = self.crease_vertex_stencil(&topology, v).unwrap_or_else(|| {
+                let neighbors = &topology.vertex_neighbors[v];
+                let n = neighbors.len() as f64;
+                if neighbors.is_empty() {
+                    return vec![(v, 1.0)];
+                }
+                let beta = if neighbors.len() == 3 { 3.0 / 16.0 } else { 3.0 / (8.0 * n) };
+                let mut stencil = vec![(v, 1.0 - n * beta)];
+                stencil.extend(neighbors.iter().map(|&u| (u, beta)));
+                stencil
+            });
+            stencils.push(stencil);
+        }
+
+        for (edge, &(a, b)) in topology.edges.iter().enumerate() {
+            if self.is_crease(&topology, edge) {
+                stencils.push(vec![(a, 0.5), (b, 0.5)]);
+            } else {
+                let mut stencil = vec![(a, 0.375), (b, 0.375)];
+                for &p in &topology.edge_polygons[edge] {
+                    let opposite = self.polygons[p].vertexes.iter().cloned().find(|&c| c != a && c != b).unwrap();
+                    stencil.push((opposite, 0.125));
+                }
+                stencils.push(stencil);
+            }
+        }
+
+        let mut polygons = vec![];
+        for polygon in &self.polygons {
+            let v = &polygon.vertexes;
+            let e = |i: usize, j: usize| vertex_count + topology.edge_indexes[&edge_key(v[i], v[j])];
+            let (e01, e12, e20) = (e(0, 1), e(1, 2), e(2, 0));
+            let uvs = polygon.uvs.as_ref().map(|uv| {
+                let (m01, m12, m20) = ((uv[0] + uv[1]) * 0.5, (uv[1] + uv[2]) * 0.5, (uv[2] + uv[0]) * 0.5);
+                vec![vec![uv[0], m01, m20], vec![uv[1], m12, m01], vec![uv[2], m20, m12], vec![m01, m12, m20]]
+            });
+            let corners = [[v[0], e01, e20], [v[1], e12, e01], [v[2], e20, e12], [e01, e12, e20]];
+            for (k, corner) in corners.iter().enumerate() {
+                polygons.push(Polygon {
+                    vertexes: corner.to_vec(),
+                    uvs: uvs.as_ref().map(|uvs| uvs[k].clone()),
+                });
+            }
+        }
+
+        let creases = self.split_creases(&topology, vertex_count);
+        self.apply(&stencils, polygons, creases)
+    }
+
+    // 新しい頂点は [元の頂点, 辺の中点, 面の中心] の順に並べる
+    // https://en.wikipedia.org/wiki/Catmull%E2%80%93Clark_subdivision_surface
+    fn catmull_clark_step(&self) -> ControlMesh {
+        let topology = self.topology();
+        let vertex_count = self.vertexes.len();
+        let face_offset = vertex_count + topology.edges.len();
+
+        let face_stencils: Vec<Stencil> = self.polygons.iter()
+            .map(|polygon| {
+                let w = (polygon.vertexes.len() as f64).recip();
+                polygon.vertexes.iter().map(|&v| (v, w)).collect()
+            })
+            .collect();
+
+        let mut stencils = vec![];
+        for v in 0..vertex_count {
+            let stencil = self.crease_vertex_stencil(&topology, v).unwrap_or_else(|| {
+                // (Q + 2R + (n - 3)P) / n
+                // Q は隣接する面の中心の平均、R は接続する辺の中点の平均
+                let polygons = &topology.vertex_polygons[v];
+                let neighbors = &topology.vertex_neighbors[v];
+                if polygons.is_empty() || neighbors.is_empty() {
+                    return vec![(v, 1.0)];
+                }
+                let n = neighbors.len() as f64;
+                let mut stencil = vec![(v, (n - 3.0) / n)];
+                for &p in polygons {
+                    stencil.extend(scaled(&face_stencils[p], (n * polygons.len() as f64).recip()));
+                }
+                for &u in neighbors {
+                    stencil.push((v, 1.0 / (n * n)));
+                    stencil.push((u, 1.0 / (n * n)));
+                }
+                stencil
+            });
+            stencils.push(stencil);
+        }
+
+        for (edge, &(a, b)) in topology.edges.iter().enumerate() {
+            if self.is_crease(&topology, edge) {
+                stencils.push(vec![(a, 0.5), (b, 0.5)]);
+            } else {
+                let mut stencil = vec![(a, 0.25), (b, 0.25)];
+                for &p in &topology.edge_polygons[edge] {
+                    stencil.extend(scaled(&face_stencils[p], 0.25));
+                }
+                stencils.push(stencil);
+            }
+        }
+
+        stencils.extend(face_stencils.into_iter());
+
+        let mut polygons = vec![];
+        for (p, polygon) in self.polygons.iter().enumerate() {
+            let v = &polygon.vertexes;
+            let n = v.len();
+            let e = |i: usize, j: usize| vertex_count + topology.edge_indexes[&edge_key(v[i], v[j])];
+            let center_uv = polygon.uvs.as_ref().map(|uv| uv.iter().fold(Vector2::zero(), |sum, &x| sum + x) * (n as f64).recip());
+            for i in 0..n {
+                let (prev, next) = ((i + n - 1) % n, (i + 1) % n);
+                polygons.push(Polygon {
+                    vertexes: vec![v[i], e(i, next), face_offset + p, e(prev, i)],
+                    uvs: polygon.uvs.as_ref().map(|uv| vec![
+                        uv[i],
+                        (uv[i] + uv[next]) * 0.5,
+                        center_uv.unwrap(),
+                        (uv[prev] + uv[i]) * 0.5,
+                    ]),
+                });
+            }
+        }
+
+        let creases = self.split_creases(&topology, vertex_count);
+        self.apply(&stencils, polygons, creases)
+    }
+
+    // 多角形は扇状に三角形分割する。UV は角ごとに別々に持つ
+    fn to_mesh(&self, material: Material) -> Mesh {
+        let mut uvs = vec![];
+        let mut faces = vec![];
+        for polygon in &self.polygons {
+            let uv_offset = uvs.len();
+            if let Some(ref polygon_uvs) = polygon.uvs {
+                uvs.extend(polygon_uvs.iter().cloned());
+            }
+            let v = &polygon.vertexes;
+            for k in 1..(v.len() - 1) {
+                let mut face = Face::new(v[0], v[k], v[k + 1]);
+                if polygon.uvs.is_some() {
+                    face.uv_indexes = Some([uv_offset, uv_offset + k, uv_offset + k + 1]);
+                }
+                face.fan = k > 1;
+                faces.push(face);
+            }
+        }
+
+        Mesh {
+            vertexes: self.vertexes.clone(),
+            normals: vec![],
+            uvs,
+            colors: self.colors.clone(),
+            faces,
+            material,
+            source: None,
+            smooth: false,
+            project_uvs: false,
+        }
+    }
+}
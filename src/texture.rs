@@ -3,6 +3,7 @@ extern crate image;
 use image::{DynamicImage, GenericImage};
 use std::path::Path;
 use std::fmt;
+use std::sync::Arc;
 
 use vector::{Vector3, Vector2};
 use color::{Color, rgba_to_color, gamma_to_linear};
@@ -10,12 +11,27 @@ use math::clamp_u32;
 
 pub struct ImageTexture {
     pub image: DynamicImage,
+    pub path: String,
 }
 
 impl ImageTexture {
     pub fn new(path: &str) -> ImageTexture {
+        ImageTexture::open(path).unwrap()
+    }
+
+    // 読み込めない画像をエラーとして返す
+    pub fn open(path: &str) -> image::ImageResult<ImageTexture> {
+        Ok(ImageTexture {
+            image: image::open(&Path::new(path))?,
+            path: path.to_string(),
+        })
+    }
+
+    // ファイル以外から読み込んだ画像（path は表示用）
+    pub fn from_image(image: DynamicImage, path: &str) -> ImageTexture {
         ImageTexture {
-            image: image::open(&Path::new(path)).unwrap(),
+            image,
+            path: path.to_string(),
         }
     }
 
@@ -69,23 +85,32 @@ impl fmt::Debug for ImageTexture {
     }
 }
 
-#[derive(Debug)]
+// 同じ画像を複数のマテリアルで共有できるように Arc で保持する
+#[derive(Clone, Debug)]
 pub struct Texture {
-    pub image_texture: Option<ImageTexture>,
+    pub image_texture: Option<Arc<ImageTexture>>,
     pub color: Color,
 }
 
+// 同じ色で、同じパスの画像を使うテクスチャを等しいとみなす
+impl PartialEq for Texture {
+    fn eq(&self, other: &Texture) -> bool {
+        let path = |texture: &Texture| texture.image_texture.as_ref().map(|image| image.path.clone());
+        self.color == other.color && path(self) == path(other)
+    }
+}
+
 impl Texture {
     pub fn new(path: &str, color: Color) -> Texture {
         Texture {
-            image_texture: Some(ImageTexture::new(path)),
+            image_texture: Some(Arc::new(ImageTexture::new(path))),
             color: color,
         }
     }
 
     pub fn from_path(path: &str) -> Texture {
         Texture {
-            image_texture: Some(ImageTexture::new(path)),
+            image_texture: Some(Arc::new(ImageTexture::new(path))),
             color: Vector3::one(),
         }
     }